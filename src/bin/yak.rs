@@ -0,0 +1,528 @@
+#[macro_use] extern crate log;
+extern crate env_logger;
+extern crate getopts;
+extern crate rustc_serialize;
+extern crate yak_client;
+
+use std::collections::BTreeMap;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, Read, Write};
+use std::process;
+use std::str;
+
+use getopts::Options;
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::Json;
+use yak_client::{Client, Delivery, NodeStatus, Role, SessionStatus, SpaceStatus, SubscriptionOptions, YakError};
+
+// Exit statuses.
+const EXIT_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Format {
+  Raw,
+  Hex,
+  Json,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Input {
+  Values(Vec<String>),
+  /// Each file's whole content is one record; "-" reads stdin a line at a
+  /// time instead.
+  Files(Vec<String>),
+  Lines,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+  Produce { key: Vec<u8>, input: Input },
+  Read { key: Vec<u8> },
+  Tail { from: u64, catch_up: bool },
+  Spaces,
+  Status,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Invocation {
+  url: String,
+  command: Command,
+  format: Format,
+}
+
+#[derive(Debug)]
+enum ArgsError {
+  /// Asked for help, which isn't an error as such.
+  Help(String),
+  Usage(String),
+}
+
+#[derive(Debug)]
+enum Failure {
+  Client(YakError),
+  Io(io::Error),
+}
+
+impl fmt::Display for Failure {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      &Failure::Client(ref e) => write!(f, "{}", e),
+      &Failure::Io(ref e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl From<YakError> for Failure {
+  fn from(err: YakError) -> Failure {
+    Failure::Client(err)
+  }
+}
+
+impl From<io::Error> for Failure {
+  fn from(err: io::Error) -> Failure {
+    Failure::Io(err)
+  }
+}
+
+pub fn main() {
+  env_logger::init().unwrap_or(());
+  let invocation = match parse_args(std::env::args()) {
+    Ok(invocation) => invocation,
+    Err(ArgsError::Help(usage)) => {
+      println!("{}", usage);
+      return
+    },
+    Err(ArgsError::Usage(usage)) => {
+      let _ = writeln!(io::stderr(), "{}", usage);
+      process::exit(EXIT_USAGE)
+    },
+  };
+
+  let stdout = io::stdout();
+  match run(&invocation, &mut stdout.lock()) {
+    Ok(()) => (),
+    // Whoever we were piping to has had enough.
+    Err(Failure::Io(ref e)) if e.kind() == io::ErrorKind::BrokenPipe => (),
+    Err(e) => {
+      let _ = writeln!(io::stderr(), "{}: {}", invocation.url, e);
+      process::exit(EXIT_FAILED)
+    },
+  }
+}
+
+fn options() -> Options {
+  let mut opts = Options::new();
+  opts.optopt("k", "key", "key to produce with; empty by default", "KEY");
+  opts.optmulti("f", "file", "produce the content of FILE as one record, or each line of stdin for -", "FILE");
+  opts.optopt("", "from", "offset to tail from; 0 by default", "OFFSET");
+  opts.optflag("", "catch-up", "stop tailing once everything already written has been printed");
+  opts.optopt("o", "output", "how to print values: raw (the default), hex or json, which prints a JSON object a line", "FORMAT");
+  opts.optflag("h", "help", "print this help");
+  opts
+}
+
+fn usage(program: &str, opts: &Options) -> String {
+  let brief = format!("Usage: {} [options] COMMAND yak://HOST:PORT/SPACE [ARGS]
+
+Commands:
+    produce [VALUE...]  append each VALUE, or each --file, or each line of stdin
+    read KEY            print the values written under KEY
+    tail                print records as they are written to the space; from
+                        a work queue, take each message and ack it once printed
+    spaces              list the node's spaces, with their offsets and sizes
+    status              show what the node is up to", program);
+  opts.usage(&brief)
+}
+
+fn parse_args<I: IntoIterator<Item=String>>(args: I) -> Result<Invocation, ArgsError> {
+  let mut args = args.into_iter();
+  let program = args.next().unwrap_or_else(|| "yak".to_string());
+  let args : Vec<String> = args.collect();
+  let opts = options();
+  let usage_error = |msg: String| ArgsError::Usage(format!("{}\n\n{}", msg, usage(&program, &opts)));
+  let matches = match opts.parse(&args) {
+    Ok(matches) => matches,
+    Err(e) => return Err(usage_error(e.to_string())),
+  };
+  if matches.opt_present("h") {
+    return Err(ArgsError::Help(usage(&program, &opts)))
+  }
+
+  let format = match matches.opt_str("o").as_ref().map(|s| &s[..]) {
+    None | Some("raw") => Format::Raw,
+    Some("hex") => Format::Hex,
+    Some("json") => Format::Json,
+    Some(other) => return Err(usage_error(format!("Unknown output format: {}", other))),
+  };
+  let (command, url, rest) = match matches.free.split_first() {
+    Some((command, rest)) if !rest.is_empty() => (command, rest[0].clone(), &rest[1..]),
+    Some(_) => return Err(usage_error("No URL given".to_string())),
+    None => return Err(usage_error("No command given".to_string())),
+  };
+  let no_more = |rest: &[String]| if rest.is_empty() {
+    Ok(())
+  } else {
+    Err(usage_error(format!("Unexpected arguments: {:?}", rest)))
+  };
+
+  let command = match &command[..] {
+    "produce" => {
+      let key = matches.opt_str("k").unwrap_or_default().into_bytes();
+      let files = matches.opt_strs("f");
+      let input = match (rest.is_empty(), files.is_empty()) {
+        (false, false) => return Err(usage_error("Give either values or files to produce, not both".to_string())),
+        (false, true) => Input::Values(rest.to_vec()),
+        (true, false) => Input::Files(files),
+        (true, true) => Input::Lines,
+      };
+      Command::Produce { key: key, input: input }
+    },
+    "read" => match rest.split_first() {
+      Some((key, more)) => {
+        try!(no_more(more));
+        Command::Read { key: key.clone().into_bytes() }
+      },
+      None => return Err(usage_error("No key given to read".to_string())),
+    },
+    "tail" => {
+      try!(no_more(rest));
+      let from = match matches.opt_str("from") {
+        Some(from) => try!(from.parse().map_err(|_| usage_error(format!("Not an offset: {}", from)))),
+        None => 0,
+      };
+      Command::Tail { from: from, catch_up: matches.opt_present("catch-up") }
+    },
+    "spaces" => {
+      try!(no_more(rest));
+      Command::Spaces
+    },
+    "status" => {
+      try!(no_more(rest));
+      Command::Status
+    },
+    other => return Err(usage_error(format!("Unknown command: {}", other))),
+  };
+  Ok(Invocation { url: url, command: command, format: format })
+}
+
+fn run<W: Write>(invocation: &Invocation, out: &mut W) -> Result<(), Failure> {
+  let mut client = try!(Client::connect(&invocation.url));
+  let format = invocation.format;
+  match invocation.command {
+    Command::Produce { ref key, ref input } => produce(&mut client, key, input),
+    Command::Read { ref key } => {
+      for value in try!(client.read(key)) {
+        try!(print_value(out, format, key, &value.content));
+      }
+      Ok(())
+    },
+    Command::Tail { from, catch_up } => {
+      let options = SubscriptionOptions { start_offset: from, catch_up: catch_up, ..SubscriptionOptions::default() };
+      let mut subscription = try!(client.subscribe_with(options));
+      while let Some(delivery) = try!(subscription.fetch_next()) {
+        try!(print_delivery(out, format, &delivery));
+        // So that whoever's reading sees each record as it arrives.
+        try!(out.flush());
+        // Otherwise we'd sit on a work queue's leases until they lapsed, and
+        // its messages would be handed out again, to us or anyone else.
+        // Catching up takes no leases.
+        if !catch_up {
+          try!(subscription.ack());
+        }
+      }
+      Ok(())
+    },
+    Command::Spaces => {
+      for space in try!(client.status()).spaces {
+        try!(print_space(out, format, &space));
+      }
+      Ok(())
+    },
+    Command::Status => print_status(out, format, &try!(client.status())),
+  }
+}
+
+// Each run produces as a new producer, so that a write retried after the
+// connection drops isn't appended twice. Hashers are randomly keyed, which
+// makes them a source of producer ids that are unlikely to be reused.
+fn produce(client: &mut Client, key: &[u8], input: &Input) -> Result<(), Failure> {
+  client.set_producer(RandomState::new().build_hasher().finish(), 0);
+  match input {
+    &Input::Values(ref values) => {
+      for value in values {
+        try!(client.write(key, value.as_bytes()));
+      }
+    },
+    &Input::Files(ref paths) => {
+      for path in paths {
+        if path == "-" {
+          try!(produce_lines(client, key, io::stdin().lock()));
+        } else {
+          let mut content = Vec::new();
+          try!(File::open(path).and_then(|mut f| f.read_to_end(&mut content)));
+          try!(client.write(key, &content));
+        }
+      }
+    },
+    &Input::Lines => try!(produce_lines(client, key, io::stdin().lock())),
+  }
+  Ok(())
+}
+
+fn produce_lines<R: BufRead>(client: &mut Client, key: &[u8], input: R) -> Result<(), Failure> {
+  for line in input.split(b'\n') {
+    let line = try!(line);
+    debug!("Producing {} bytes", line.len());
+    try!(client.write(key, &line));
+  }
+  Ok(())
+}
+
+// JSON has no way to hold arbitrary bytes, so those that aren't UTF-8 are
+// given in hex, under the field's name with "_hex" on the end.
+fn bytes_field(fields: &mut BTreeMap<String, Json>, name: &str, bytes: &[u8]) {
+  match str::from_utf8(bytes) {
+    Ok(text) => fields.insert(name.to_string(), Json::String(text.to_string())),
+    Err(_) => fields.insert(format!("{}_hex", name), Json::String(bytes.to_hex())),
+  };
+}
+
+fn print_value<W: Write>(out: &mut W, format: Format, key: &[u8], value: &[u8]) -> io::Result<()> {
+  match format {
+    Format::Raw => {
+      try!(out.write_all(value));
+      out.write_all(b"\n")
+    },
+    Format::Hex => writeln!(out, "{}", value.to_hex()),
+    Format::Json => {
+      let mut fields = BTreeMap::new();
+      bytes_field(&mut fields, "key", key);
+      bytes_field(&mut fields, "value", value);
+      writeln!(out, "{}", Json::Object(fields))
+    },
+  }
+}
+
+fn print_delivery<W: Write>(out: &mut W, format: Format, delivery: &Delivery) -> io::Result<()> {
+  match format {
+    Format::Raw | Format::Hex => print_value(out, format, &delivery.key, &delivery.content),
+    Format::Json => {
+      let mut fields = BTreeMap::new();
+      fields.insert("space".to_string(), Json::String(delivery.space.clone()));
+      fields.insert("offset".to_string(), Json::U64(delivery.offset));
+      bytes_field(&mut fields, "key", &delivery.key);
+      bytes_field(&mut fields, "value", &delivery.content);
+      writeln!(out, "{}", Json::Object(fields))
+    },
+  }
+}
+
+fn space_json(space: &SpaceStatus) -> Json {
+  let mut fields = BTreeMap::new();
+  fields.insert("name".to_string(), Json::String(space.name.clone()));
+  fields.insert("first".to_string(), Json::U64(space.first));
+  fields.insert("last".to_string(), Json::U64(space.last));
+  fields.insert("bytes".to_string(), Json::U64(space.bytes));
+  Json::Object(fields)
+}
+
+fn session_json(session: &SessionStatus) -> Json {
+  let subscriptions = session.subscriptions.iter().map(|subscription| {
+    let mut fields = BTreeMap::new();
+    fields.insert("id".to_string(), Json::U64(subscription.id));
+    fields.insert("space".to_string(), Json::String(subscription.space.clone()));
+    Json::Object(fields)
+  }).collect();
+  let mut fields = BTreeMap::new();
+  fields.insert("peer".to_string(), Json::String(session.peer.clone()));
+  fields.insert("subscriptions".to_string(), Json::Array(subscriptions));
+  Json::Object(fields)
+}
+
+fn print_space<W: Write>(out: &mut W, format: Format, space: &SpaceStatus) -> io::Result<()> {
+  match format {
+    Format::Raw | Format::Hex => writeln!(out, "{}\t{}\t{}\t{}", space.name, space.first, space.last, space.bytes),
+    Format::Json => writeln!(out, "{}", space_json(space)),
+  }
+}
+
+fn role_name(role: Role) -> &'static str {
+  match role {
+    Role::Head => "head",
+    Role::Middle => "middle",
+    Role::Tail => "tail",
+  }
+}
+
+fn print_status<W: Write>(out: &mut W, format: Format, status: &NodeStatus) -> Result<(), Failure> {
+  match format {
+    Format::Raw | Format::Hex => {
+      try!(writeln!(out, "address\t{}", status.address));
+      try!(writeln!(out, "role\t{}", role_name(status.role)));
+      if let Some(ref downstream) = status.downstream {
+        let health = match downstream.healthy {
+          Some(true) => "healthy",
+          Some(false) => "failed",
+          None => "unknown",
+        };
+        try!(writeln!(out, "downstream\t{}\t{}", downstream.address, health));
+      }
+      for session in status.sessions.iter() {
+        try!(writeln!(out, "session\t{}\t{}", session.peer, session.subscriptions.len()));
+        for subscription in session.subscriptions.iter() {
+          try!(writeln!(out, "subscription\t{}\t{}", subscription.id, subscription.space));
+        }
+      }
+      try!(writeln!(out, "spaces\t{}", status.spaces.len()));
+    },
+    Format::Json => {
+      let mut fields = BTreeMap::new();
+      fields.insert("address".to_string(), Json::String(status.address.clone()));
+      fields.insert("role".to_string(), Json::String(role_name(status.role).to_string()));
+      fields.insert("downstream".to_string(), match status.downstream {
+        Some(ref downstream) => {
+          let mut d = BTreeMap::new();
+          d.insert("address".to_string(), Json::String(downstream.address.clone()));
+          d.insert("healthy".to_string(), downstream.healthy.map(Json::Boolean).unwrap_or(Json::Null));
+          Json::Object(d)
+        },
+        None => Json::Null,
+      });
+      fields.insert("sessions".to_string(), Json::Array(status.sessions.iter().map(session_json).collect()));
+      fields.insert("spaces".to_string(), Json::Array(status.spaces.iter().map(space_json).collect()));
+      try!(writeln!(out, "{}", Json::Object(fields)));
+    },
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+extern crate yak_mq;
+
+#[cfg(test)]
+mod test {
+  use super::{parse_args, print_delivery, print_value, run, ArgsError, Command, Format, Input, Invocation};
+  use std::io::{self, Write};
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+  use std::time::{Duration, Instant};
+  use yak_client::{Client, Delivery, QueueConfig, YakError};
+  use yak_mq::testing::Chain;
+
+  const URL : &'static str = "yak://127.0.0.1:7700/space";
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|s| s.to_string()).collect()
+  }
+
+  fn printed<F: FnOnce(&mut Vec<u8>)>(print: F) -> String {
+    let mut out = Vec::new();
+    print(&mut out);
+    String::from_utf8(out).unwrap()
+  }
+
+  fn run_printing(command_line: &[&str]) -> String {
+    printed(|out| run(&parse_args(args(command_line)).unwrap(), out).unwrap())
+  }
+
+  // Collects what's printed on another thread.
+  #[derive(Clone)]
+  struct Shared(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn produces_values_from_arguments() {
+    assert_eq!(parse_args(args(&["yak", "produce", URL, "a", "b", "--key", "k"])).unwrap(), Invocation {
+      url: URL.to_string(),
+      command: Command::Produce { key: b"k".to_vec(), input: Input::Values(args(&["a", "b"])) },
+      format: Format::Raw,
+    });
+  }
+
+  #[test]
+  fn produces_lines_from_stdin_by_default() {
+    let invocation = parse_args(args(&["yak", "produce", URL])).unwrap();
+    assert_eq!(invocation.command, Command::Produce { key: Vec::new(), input: Input::Lines });
+  }
+
+  #[test]
+  fn tails_from_an_offset() {
+    let invocation = parse_args(args(&["yak", "-o", "json", "tail", URL, "--from", "42"])).unwrap();
+    assert_eq!((invocation.command, invocation.format), (Command::Tail { from: 42, catch_up: false }, Format::Json));
+  }
+
+  #[test]
+  fn rejects_bad_command_lines() {
+    let bad_lines : Vec<&[&str]> = vec![&["yak"], &["yak", "read", URL], &["yak", "status"], &["yak", "frobnicate", URL],
+        &["yak", "-o", "xml", "status", URL], &["yak", "tail", URL, "--from", "soon"], &["yak", "spaces", URL, "extra"]];
+    for bad in bad_lines {
+      match parse_args(args(bad)) {
+        Err(ArgsError::Usage(_)) => (),
+        other => panic!("{:?} gave {:?}", bad, other),
+      }
+    }
+  }
+
+  #[test]
+  fn prints_values_in_each_format() {
+    assert_eq!(printed(|out| print_value(out, Format::Raw, b"k", b"value").unwrap()), "value\n");
+    assert_eq!(printed(|out| print_value(out, Format::Hex, b"k", b"\x00\xff").unwrap()), "00ff\n");
+    assert_eq!(printed(|out| print_value(out, Format::Json, b"k", b"\xff").unwrap()), "{\"key\":\"k\",\"value_hex\":\"ff\"}\n");
+  }
+
+  #[test]
+  fn tails_what_it_produced() {
+    let chain = Chain::start(2).unwrap();
+    run_printing(&["yak", "produce", &chain.head_url("produced"), "a", "b"]);
+    assert_eq!(run_printing(&["yak", "tail", "--catch-up", &chain.tail_url("produced")]), "a\nb\n");
+  }
+
+  #[test]
+  fn tails_a_work_queue_acking_what_it_prints() {
+    let chain = Chain::start(2).unwrap();
+    let mut head = Client::connect(&chain.head_url("queued")).unwrap();
+    head.configure_queue(QueueConfig { visibility_timeout_ms: 100, max_deliveries: 3, dead_letter_space: None }).unwrap();
+    head.write(b"key", b"a").unwrap();
+    head.write(b"key", b"b").unwrap();
+
+    let seen = Shared(Arc::new(Mutex::new(Vec::new())));
+    let invocation = parse_args(args(&["yak", "tail", &chain.tail_url("queued")])).unwrap();
+    let mut out = seen.clone();
+    // Tails until the chain goes away at the end of the test.
+    thread::spawn(move || run(&invocation, &mut out));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while seen.0.lock().unwrap().len() < 4 && Instant::now() < deadline {
+      thread::sleep(Duration::from_millis(10));
+    }
+    // Long enough for any lease it kept to lapse.
+    thread::sleep(Duration::from_millis(300));
+
+    let tail = Client::connect(&chain.tail_url("queued")).unwrap();
+    let mut subscription = tail.subscribe().unwrap();
+    match subscription.fetch_next_within(200) {
+      Err(YakError::Timeout) => (),
+      other => panic!("Expected nothing left to hand out, got {:?}", other),
+    }
+    assert_eq!(String::from_utf8(seen.0.lock().unwrap().clone()).unwrap(), "a\nb\n");
+  }
+
+  #[test]
+  fn prints_deliveries_as_json_lines() {
+    let delivery = Delivery { space: "s".to_string(), offset: 3, key: b"k".to_vec(), content: b"v".to_vec() };
+    assert_eq!(printed(|out| print_delivery(out, Format::Json, &delivery).unwrap()),
+      "{\"key\":\"k\",\"offset\":3,\"space\":\"s\",\"value\":\"v\"}\n");
+  }
+}
@@ -0,0 +1,435 @@
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+
+use getopts::{Options, Matches};
+use rustc_serialize::Decodable;
+use toml;
+
+use yak_client::HEARTBEAT_INTERVAL_MS;
+
+const DEFAULT_LOG_CONFIG : &'static str = "log.toml";
+// Requests that go down the chain hold a session worker until the reply
+// comes back, so we want a few more of these than there are cores.
+const DEFAULT_SESSION_WORKERS : i64 = 16;
+const DEFAULT_SUBSCRIPTION_WORKERS : i64 = 8;
+// Enough for each of the workers to hold a connection at once.
+const DEFAULT_POOL_SIZE : i64 = 32;
+const DEFAULT_CLIENT_WRITE_TIMEOUT_MS : i64 = 10 * HEARTBEAT_INTERVAL_MS as i64;
+const DEFAULT_STORE_CONNECTION_TIMEOUT_MS : i64 = 30000;
+const DEFAULT_SHUTDOWN_TIMEOUT_MS : i64 = 30000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backend {
+  Sqlite,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoreConfig {
+  pub backend: Backend,
+  pub path: PathBuf,
+  pub pool_size: u32,
+  /// How long to wait for a database connection from the pool.
+  pub connection_timeout_ms: u32,
+  /// How many records to keep in each space; older ones are dropped as new
+  /// ones are written. `None` keeps everything.
+  pub max_records: Option<u64>,
+}
+
+impl StoreConfig {
+  pub fn at(path: &Path) -> StoreConfig {
+    StoreConfig {
+      backend: Backend::Sqlite,
+      path: path.to_path_buf(),
+      pool_size: DEFAULT_POOL_SIZE as u32,
+      connection_timeout_ms: DEFAULT_STORE_CONNECTION_TIMEOUT_MS as u32,
+      max_records: None,
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+  pub listen: String,
+  /// The address we give clients that ask for the chain's layout, if not
+  /// the one we're listening on.
+  pub advertise: Option<String>,
+  /// The next node along the chain, unless we're the tail.
+  pub next: Option<String>,
+  pub store: StoreConfig,
+  pub session_workers: usize,
+  pub subscription_workers: usize,
+  /// How long a client may go without taking anything we're trying to send
+  /// it before we give up on it.
+  pub client_write_timeout_ms: u64,
+  /// How long to wait for in-flight requests to finish when shutting down.
+  pub shutdown_timeout_ms: u64,
+  /// Where to serve metrics over HTTP, if anywhere.
+  pub metrics_listen: Option<String>,
+  pub log_config: PathBuf,
+}
+
+impl Config {
+  /// The default settings for a node of its own, with no chain.
+  pub fn new(store_dir: &Path, listen: &str) -> Config {
+    Config {
+      listen: listen.to_string(),
+      advertise: None,
+      next: None,
+      store: StoreConfig::at(store_dir),
+      session_workers: DEFAULT_SESSION_WORKERS as usize,
+      subscription_workers: DEFAULT_SUBSCRIPTION_WORKERS as usize,
+      client_write_timeout_ms: DEFAULT_CLIENT_WRITE_TIMEOUT_MS as u64,
+      shutdown_timeout_ms: DEFAULT_SHUTDOWN_TIMEOUT_MS as u64,
+      metrics_listen: None,
+      log_config: PathBuf::from(DEFAULT_LOG_CONFIG),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+  /// Asked for help, which isn't an error as such.
+  Help(String),
+  Usage(String),
+  Io(PathBuf, io::Error),
+  Parse(PathBuf, Vec<String>),
+  Decode(PathBuf, toml::DecodeError),
+  Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      &ConfigError::Help(ref usage) => write!(f, "{}", usage),
+      &ConfigError::Usage(ref usage) => write!(f, "{}", usage),
+      &ConfigError::Io(ref path, ref e) => write!(f, "Could not read {}: {}", path.display(), e),
+      &ConfigError::Parse(ref path, ref errors) => write!(f, "Could not parse {}:\n\t{}", path.display(), errors.join("\n\t")),
+      &ConfigError::Decode(ref path, ref e) => write!(f, "Bad setting in {}: {}", path.display(), e),
+      &ConfigError::Invalid(ref msg) => write!(f, "Invalid configuration: {}", msg),
+    }
+  }
+}
+
+impl Error for ConfigError {
+  fn description(&self) -> &str {
+    match self {
+      &ConfigError::Help(_) => "Help requested",
+      &ConfigError::Usage(_) => "Bad command line",
+      &ConfigError::Io(_, ref e) => e.description(),
+      &ConfigError::Parse(_, _) => "Could not parse config file",
+      &ConfigError::Decode(_, ref e) => e.description(),
+      &ConfigError::Invalid(_) => "Invalid configuration",
+    }
+  }
+}
+
+// Everything in the file is optional, and numbers are read signed so that
+// we can say what's wrong with a negative one.
+#[derive(Debug, Default, RustcDecodable)]
+struct ConfigFile {
+  server: Option<ServerSection>,
+  store: Option<StoreSection>,
+  chain: Option<ChainSection>,
+  timeouts: Option<TimeoutsSection>,
+  retention: Option<RetentionSection>,
+  metrics: Option<MetricsSection>,
+  logging: Option<LoggingSection>,
+}
+
+#[derive(Debug, Default, RustcDecodable)]
+struct ServerSection {
+  listen: Option<String>,
+  advertise: Option<String>,
+  session_workers: Option<i64>,
+  subscription_workers: Option<i64>,
+}
+
+#[derive(Debug, Default, RustcDecodable)]
+struct StoreSection {
+  backend: Option<String>,
+  path: Option<String>,
+  pool_size: Option<i64>,
+}
+
+#[derive(Debug, Default, RustcDecodable)]
+struct ChainSection {
+  next: Option<String>,
+}
+
+#[derive(Debug, Default, RustcDecodable)]
+struct TimeoutsSection {
+  client_write_ms: Option<i64>,
+  store_connection_ms: Option<i64>,
+  shutdown_ms: Option<i64>,
+}
+
+#[derive(Debug, Default, RustcDecodable)]
+struct RetentionSection {
+  max_records: Option<i64>,
+}
+
+#[derive(Debug, Default, RustcDecodable)]
+struct MetricsSection {
+  listen: Option<String>,
+}
+
+#[derive(Debug, Default, RustcDecodable)]
+struct LoggingSection {
+  config: Option<String>,
+}
+
+fn options() -> Options {
+  let mut opts = Options::new();
+  opts.optopt("c", "config", "read settings from a TOML file", "FILE");
+  opts.optopt("l", "listen", "address to listen on", "HOST:PORT");
+  opts.optopt("a", "advertise", "address to give clients, if not the listen address", "HOST:PORT");
+  opts.optopt("s", "store", "directory to keep the store in", "DIR");
+  opts.optopt("n", "next", "next node along the chain", "HOST:PORT");
+  opts.optopt("m", "metrics", "address to serve Prometheus metrics on", "HOST:PORT");
+  opts.optopt("", "log-config", "log4rs configuration file", "FILE");
+  opts.optflag("h", "help", "print this help");
+  opts
+}
+
+fn usage(program: &str, opts: &Options) -> String {
+  let brief = format!("Usage: {} [options] [STORE_DIR LISTEN_ADDR [NEXT_ADDR]]", program);
+  opts.usage(&brief)
+}
+
+/// Works out the server's settings from its command line. Options given on
+/// the command line take precedence over those in the config file.
+pub fn from_args<I: IntoIterator<Item=String>>(args: I) -> Result<Config, ConfigError> {
+  let mut args = args.into_iter();
+  let program = args.next().unwrap_or_else(|| "yak_server".to_string());
+  let args : Vec<String> = args.collect();
+  let opts = options();
+  let matches = match opts.parse(&args) {
+    Ok(matches) => matches,
+    Err(e) => return Err(ConfigError::Usage(format!("{}\n\n{}", e, usage(&program, &opts)))),
+  };
+  if matches.opt_present("h") {
+    return Err(ConfigError::Help(usage(&program, &opts)))
+  }
+  let file = match matches.opt_str("c") {
+    Some(path) => try!(read_file(Path::new(&path))),
+    None => ConfigFile::default(),
+  };
+  resolve(&matches, file).map_err(|e| match e {
+    ConfigError::Usage(msg) => ConfigError::Usage(format!("{}\n\n{}", msg, usage(&program, &opts))),
+    e => e,
+  })
+}
+
+fn read_file(path: &Path) -> Result<ConfigFile, ConfigError> {
+  let mut text = String::new();
+  try!(File::open(path).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| ConfigError::Io(path.to_path_buf(), e)));
+  parse(path, &text)
+}
+
+fn parse(path: &Path, text: &str) -> Result<ConfigFile, ConfigError> {
+  let mut parser = toml::Parser::new(text);
+  let table = match parser.parse() {
+    Some(table) => table,
+    None => {
+      let errors = parser.errors.iter().map(|e| {
+        let (line, col) = parser.to_linecol(e.lo);
+        format!("{}:{}: {}", line + 1, col + 1, e.desc)
+      }).collect();
+      return Err(ConfigError::Parse(path.to_path_buf(), errors))
+    },
+  };
+  let mut decoder = toml::Decoder::new(toml::Value::Table(table));
+  let file = try!(ConfigFile::decode(&mut decoder).map_err(|e| ConfigError::Decode(path.to_path_buf(), e)));
+  // Decoding takes out everything it knows of, so whatever's left is a
+  // mistake, and likely a typo for something that now takes its default.
+  let mut unknown = Vec::new();
+  if let Some(ref left) = decoder.toml {
+    unknown_settings("", left, &mut unknown);
+  }
+  if !unknown.is_empty() {
+    return Err(ConfigError::Invalid(format!("Unknown settings in {}: {}", path.display(), unknown.join(", "))))
+  }
+  Ok(file)
+}
+
+// Names each setting in what's left over, dotted with the section it's in.
+fn unknown_settings(prefix: &str, value: &toml::Value, names: &mut Vec<String>) {
+  match value {
+    &toml::Value::Table(ref table) if !table.is_empty() => {
+      for (name, value) in table {
+        let name = if prefix.is_empty() { name.clone() } else { format!("{}.{}", prefix, name) };
+        unknown_settings(&name, value, names);
+      }
+    },
+    _ => names.push(prefix.to_string()),
+  }
+}
+
+// The positional arguments are the ones the server took before it had any
+// options, and are kept so that existing scripts carry on working.
+fn resolve(matches: &Matches, file: ConfigFile) -> Result<Config, ConfigError> {
+  if matches.free.len() > 3 {
+    return Err(ConfigError::Usage(format!("Unexpected arguments: {:?}", &matches.free[3..])))
+  }
+  let positional = |n: usize| matches.free.get(n).cloned();
+  let server = file.server.unwrap_or_default();
+  let store = file.store.unwrap_or_default();
+  let chain = file.chain.unwrap_or_default();
+  let timeouts = file.timeouts.unwrap_or_default();
+  let retention = file.retention.unwrap_or_default();
+  let metrics = file.metrics.unwrap_or_default();
+  let logging = file.logging.unwrap_or_default();
+
+  let path = match matches.opt_str("s").or(positional(0)).or(store.path) {
+    Some(path) => PathBuf::from(path),
+    None => return Err(ConfigError::Usage("No store directory given".to_string())),
+  };
+  let listen = match matches.opt_str("l").or(positional(1)).or(server.listen) {
+    Some(listen) => listen,
+    None => return Err(ConfigError::Usage("No listen address given".to_string())),
+  };
+  try!(check_listen_address(&listen));
+  // YAK_ADVERTISE is how it was given before there were options for it.
+  let advertise = matches.opt_str("a").or(server.advertise).or(env::var("YAK_ADVERTISE").ok());
+  if let Some(ref advertise) = advertise {
+    try!(check_address("advertised address", advertise));
+  }
+  let next = matches.opt_str("n").or(positional(2)).or(chain.next);
+  if let Some(ref next) = next {
+    try!(check_address("next node", next));
+  }
+  let metrics_listen = matches.opt_str("m").or(metrics.listen);
+  if let Some(ref metrics_listen) = metrics_listen {
+    try!(check_listen_address(metrics_listen));
+  }
+
+  let backend = match store.backend.as_ref().map(|b| &b[..]) {
+    None | Some("sqlite") => Backend::Sqlite,
+    Some(other) => return Err(ConfigError::Invalid(format!("Unknown store backend {:?}; expected \"sqlite\"", other))),
+  };
+  let max_records = match retention.max_records {
+    None => None,
+    Some(n) => Some(try!(positive("retention.max_records", n)) as u64),
+  };
+
+  Ok(Config {
+    listen: listen,
+    advertise: advertise,
+    next: next,
+    store: StoreConfig {
+      backend: backend,
+      path: path,
+      pool_size: try!(positive("store.pool_size", store.pool_size.unwrap_or(DEFAULT_POOL_SIZE))) as u32,
+      connection_timeout_ms: try!(positive("timeouts.store_connection_ms",
+            timeouts.store_connection_ms.unwrap_or(DEFAULT_STORE_CONNECTION_TIMEOUT_MS))) as u32,
+      max_records: max_records,
+    },
+    session_workers: try!(positive("server.session_workers", server.session_workers.unwrap_or(DEFAULT_SESSION_WORKERS))) as usize,
+    subscription_workers: try!(positive("server.subscription_workers",
+          server.subscription_workers.unwrap_or(DEFAULT_SUBSCRIPTION_WORKERS))) as usize,
+    client_write_timeout_ms: try!(positive("timeouts.client_write_ms",
+          timeouts.client_write_ms.unwrap_or(DEFAULT_CLIENT_WRITE_TIMEOUT_MS))) as u64,
+    shutdown_timeout_ms: try!(positive("timeouts.shutdown_ms",
+          timeouts.shutdown_ms.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_MS))) as u64,
+    metrics_listen: metrics_listen,
+    log_config: PathBuf::from(matches.opt_str("log-config").or(logging.config).unwrap_or(DEFAULT_LOG_CONFIG.to_string())),
+  })
+}
+
+fn positive(setting: &str, value: i64) -> Result<i64, ConfigError> {
+  if value <= 0 || value > ::std::u32::MAX as i64 {
+    return Err(ConfigError::Invalid(format!("{} must be between 1 and {}; got {}", setting, ::std::u32::MAX, value)))
+  }
+  Ok(value)
+}
+
+// Other nodes may well not be up yet, so we only check that the address
+// looks right.
+fn check_address(what: &str, addr: &str) -> Result<(), ConfigError> {
+  let mut parts = addr.rsplitn(2, ':');
+  match (parts.next().map(|port| port.parse::<u16>()), parts.next()) {
+    (Some(Ok(_)), Some(host)) if !host.is_empty() => Ok(()),
+    _ => Err(ConfigError::Invalid(format!("The {} should be HOST:PORT; got {:?}", what, addr))),
+  }
+}
+
+fn check_listen_address(addr: &str) -> Result<(), ConfigError> {
+  try!(check_address("listen address", addr));
+  match addr.to_socket_addrs() {
+    Ok(_) => Ok(()),
+    Err(e) => Err(ConfigError::Invalid(format!("Cannot listen on {:?}: {}", addr, e))),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{options, parse, resolve, from_args, ConfigError, ConfigFile, Backend};
+  use std::path::{Path, PathBuf};
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|s| s.to_string()).collect()
+  }
+
+  fn resolve_with(cli: &[&str], file: &str) -> Result<super::Config, ConfigError> {
+    let matches = options().parse(&args(cli)).unwrap();
+    resolve(&matches, parse(Path::new("test.toml"), file).unwrap())
+  }
+
+  #[test]
+  fn positional_arguments_still_work() {
+    let config = from_args(args(&["yak_server", "/tmp/yak", "127.0.0.1:7700", "127.0.0.1:7701"])).unwrap();
+    assert_eq!(config.store.path, PathBuf::from("/tmp/yak"));
+    assert_eq!((&config.listen[..], config.advertise), ("127.0.0.1:7700", None));
+    assert_eq!(config.next, Some("127.0.0.1:7701".to_string()));
+    assert_eq!(config.store.backend, Backend::Sqlite);
+  }
+
+  #[test]
+  fn command_line_overrides_file() {
+    let file = "[server]\nlisten = \"127.0.0.1:7700\"\nsession_workers = 4\n\
+                [store]\npath = \"/var/lib/yak\"\n\
+                [retention]\nmax_records = 1000\n";
+    let config = resolve_with(&["--listen", "127.0.0.1:7800"], file).unwrap();
+    assert_eq!(config.listen, "127.0.0.1:7800");
+    assert_eq!(config.session_workers, 4);
+    assert_eq!(config.store.path, PathBuf::from("/var/lib/yak"));
+    assert_eq!(config.store.max_records, Some(1000));
+  }
+
+  #[test]
+  fn rejects_bad_settings() {
+    let bad = [
+      "[store]\nbackend = \"lmdb\"\n",
+      "[server]\nsubscription_workers = -1\n",
+      "[chain]\nnext = \"nowhere\"\n",
+      "[retention]\nmax_records = 0\n",
+    ];
+    for text in &bad {
+      match resolve_with(&["/tmp/yak", "127.0.0.1:7700"], text) {
+        Err(ConfigError::Invalid(_)) => (),
+        other => panic!("Expected {:?} to be invalid; got {:?}", text, other),
+      }
+    }
+  }
+
+  #[test]
+  fn rejects_unknown_settings() {
+    match parse(Path::new("test.toml"), "[server]\nsession_worker = 4\n[metric]\nlisten = \"127.0.0.1:9100\"\n") {
+      Err(ConfigError::Invalid(ref msg)) if msg.contains("server.session_worker") && msg.contains("metric.listen") => (),
+      other => panic!("Expected unknown settings to be named; got {:?}", other.map(|_: ConfigFile| ())),
+    }
+  }
+
+  #[test]
+  fn reports_where_parsing_failed() {
+    match parse(Path::new("test.toml"), "[server\nlisten = 1") {
+      Err(ConfigError::Parse(_, ref errors)) if errors[0].starts_with("1:") => (),
+      other => panic!("Expected a parse error; got {:?}", other.map(|_: ConfigFile| ())),
+    }
+  }
+}
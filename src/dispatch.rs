@@ -0,0 +1,515 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use yak_client::{Response, Delivery, SubscriptionId, YakError, HEARTBEAT_INTERVAL_MS, millis_since_epoch};
+use store::Polled;
+use subscription::Credit;
+use workers::WorkerPool;
+
+/// Looks for the next delivery without waiting for one.
+pub type Source = Box<FnMut() -> Result<Polled<Delivery>, Box<Error + Send>> + Send>;
+/// Sends a response on to the subscriber.
+pub type Sink = Box<FnMut(Response) -> Result<(), YakError> + Send>;
+
+/// What a subscription needs to hear about for it to be worth polling again.
+pub enum Watch {
+  Space(String),
+  /// Spaces matching a glob pattern, as in `Store::subscribe_pattern`.
+  Pattern(String),
+  /// Leases expire without anyone writing anything, so work queues are also
+  /// polled every `LEASE_CHECK_MS`.
+  Leases(String),
+}
+
+// How many deliveries one poll may send before letting other subscriptions
+// have a turn.
+const MAX_BATCH : usize = 64;
+const LEASE_CHECK_MS : u64 = 100;
+
+enum Job {
+  Poll(usize),
+  Heartbeat(usize),
+}
+
+enum Progress {
+  /// Nothing more to send until there's a write or more credit.
+  Parked,
+  /// There may well be more to send right away.
+  Busy,
+  /// The subscription has ended.
+  Done,
+}
+
+struct Subscriber {
+  id: SubscriptionId,
+  source: Source,
+  sink: Sink,
+  credit: Credit,
+  last_sent_ms: u64,
+}
+
+struct Entry {
+  // Set while there's a poll queued, so a burst of writes only queues one.
+  scheduled: AtomicBool,
+  watch: Watch,
+  subscriber: Mutex<Subscriber>,
+}
+
+struct Registry {
+  next_key: usize,
+  entries: HashMap<usize, Arc<Entry>>,
+}
+
+/// Runs every subscription on a fixed pool of workers. Rather than each
+/// subscription waiting on the store for its next record, it's only polled
+/// when a write may have given it something new, or it's been given credit.
+#[derive(Clone)]
+pub struct Dispatcher {
+  registry: Arc<Mutex<Registry>>,
+  workers: WorkerPool<Job>,
+  stopped: Arc<AtomicBool>,
+}
+
+impl Dispatcher {
+  /// `writes` should carry the name of each space written to the store.
+  pub fn new(workers: usize, writes: Receiver<String>) -> io::Result<Dispatcher> {
+    let registry = Arc::new(Mutex::new(Registry { next_key: 0, entries: HashMap::new() }));
+    let workers = {
+      let registry = registry.clone();
+      try!(WorkerPool::new("subscriptions", workers, move |workers, job| run(&registry, workers, job)))
+    };
+    let dispatcher = Dispatcher { registry: registry, workers: workers, stopped: Arc::new(AtomicBool::new(false)) };
+    {
+      let dispatcher = dispatcher.clone();
+      try!(thread::Builder::new().name("write-watcher".to_string()).spawn(move || dispatcher.watch_writes(writes)));
+    }
+    {
+      let dispatcher = dispatcher.clone();
+      try!(thread::Builder::new().name("heartbeats".to_string()).spawn(move || dispatcher.tick()));
+    }
+    Ok(dispatcher)
+  }
+
+  /// Starts delivering from `source` to `sink` for as long as `credit` is
+  /// open and the source has more to give.
+  pub fn register(&self, id: SubscriptionId, source: Source, sink: Sink, credit: Credit, watch: Watch) {
+    let subscriber = Subscriber { id: id, source: source, sink: sink, credit: credit.clone(), last_sent_ms: millis_since_epoch() };
+    let entry = Arc::new(Entry { scheduled: AtomicBool::new(false), watch: watch, subscriber: Mutex::new(subscriber) });
+    let key = {
+      let mut registry = self.registry.lock().unwrap();
+      let key = registry.next_key;
+      registry.next_key += 1;
+      registry.entries.insert(key, entry);
+      key
+    };
+    debug!("Registered subscription {} as #{}", id, key);
+    let dispatcher = self.clone();
+    credit.on_change(move || dispatcher.wake(key));
+    self.wake(key);
+  }
+
+  /// Stops all delivery, and the threads doing it.
+  pub fn stop(&self) {
+    self.stopped.store(true, Ordering::SeqCst);
+    self.workers.stop();
+    // Entries hold the credit whose waker holds us, so they have to go for
+    // any of it to be freed.
+    self.registry.lock().unwrap().entries.clear();
+  }
+
+  fn wake(&self, key: usize) {
+    let entry = match self.registry.lock().unwrap().entries.get(&key) {
+      Some(entry) => entry.clone(),
+      None => return,
+    };
+    if !entry.scheduled.swap(true, Ordering::SeqCst) {
+      self.workers.submit(Job::Poll(key));
+    }
+  }
+
+  fn wake_all<F: Fn(&Watch) -> bool>(&self, wanted: F) {
+    let keys : Vec<usize> = {
+      let registry = self.registry.lock().unwrap();
+      registry.entries.iter().filter(|&(_, entry)| wanted(&entry.watch)).map(|(key, _)| *key).collect()
+    };
+    for key in keys {
+      self.wake(key);
+    }
+  }
+
+  fn watch_writes(&self, writes: Receiver<String>) {
+    while !self.stopped.load(Ordering::SeqCst) {
+      let space = match writes.recv_timeout(Duration::from_millis(LEASE_CHECK_MS)) {
+        Ok(space) => space,
+        Err(RecvTimeoutError::Timeout) => continue,
+        Err(RecvTimeoutError::Disconnected) => break,
+      };
+      // Writes come in bursts, so we only wake each subscription once for
+      // everything that's arrived meanwhile.
+      let mut spaces = HashSet::new();
+      spaces.insert(space);
+      spaces.extend(writes.try_iter());
+      trace!("Writes to: {:?}", spaces);
+      self.wake_all(|watch| spaces.iter().any(|space| watch.covers(space)));
+    }
+    debug!("No longer watching for writes");
+  }
+
+  fn tick(&self) {
+    let mut last_heartbeat = millis_since_epoch();
+    while !self.stopped.load(Ordering::SeqCst) {
+      thread::sleep(Duration::from_millis(LEASE_CHECK_MS));
+      self.wake_all(|watch| match watch { &Watch::Leases(_) => true, _ => false });
+
+      let now = millis_since_epoch();
+      if now.saturating_sub(last_heartbeat) >= HEARTBEAT_INTERVAL_MS {
+        last_heartbeat = now;
+        let keys : Vec<usize> = self.registry.lock().unwrap().entries.keys().cloned().collect();
+        for key in keys {
+          self.workers.submit(Job::Heartbeat(key));
+        }
+      }
+    }
+  }
+}
+
+impl Watch {
+  fn covers(&self, space: &str) -> bool {
+    match self {
+      &Watch::Space(ref watched) | &Watch::Leases(ref watched) => watched == space,
+      &Watch::Pattern(ref pattern) => glob_matches(pattern, space),
+    }
+  }
+}
+
+// Matches `*`, `?` and `[...]` as SQLite's GLOB does, character by
+// character. Rather than try every way of splitting the name between the
+// stars, which takes exponentially long in the number of them, we only ever
+// go back to the last star, and have it take one more character each time.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+  let pattern : Vec<char> = pattern.chars().collect();
+  let name : Vec<char> = name.chars().collect();
+  let (mut p, mut n) = (0, 0);
+  // Just after the last star, and where in the name it stopped taking.
+  let mut last_star : Option<(usize, usize)> = None;
+  while n < name.len() {
+    let step = match pattern.get(p) {
+      Some(&'*') => {
+        last_star = Some((p + 1, n));
+        p += 1;
+        continue
+      },
+      Some(&'[') => match class_matches(&pattern[p + 1..], name[n]) {
+        Some((true, len)) => Some(1 + len),
+        _ => None,
+      },
+      Some(&'?') => Some(1),
+      Some(&c) if c == name[n] => Some(1),
+      _ => None,
+    };
+    match (step, last_star) {
+      (Some(len), _) => {
+        p += len;
+        n += 1;
+      },
+      (None, Some((after_star, taken))) => {
+        last_star = Some((after_star, taken + 1));
+        p = after_star;
+        n = taken + 1;
+      },
+      (None, None) => return false,
+    }
+  }
+  pattern[p..].iter().all(|&c| c == '*')
+}
+
+// Given the pattern just after a `[`, returns whether `c` is in the class
+// and how much of the pattern the class takes up, up to and including its
+// `]`, or `None` if the class is never closed. As in SQLite, a leading `^`
+// inverts the class, and a `]` straight after the opening (or the `^`) is
+// taken literally.
+fn class_matches(class: &[char], c: char) -> Option<(bool, usize)> {
+  let (inverted, mut i) = if class.first() == Some(&'^') { (true, 1) } else { (false, 0) };
+  let start = i;
+  let mut matched = false;
+  while i < class.len() {
+    if class[i] == ']' && i > start {
+      return Some((matched != inverted, i + 1))
+    }
+    if i + 2 < class.len() && class[i + 1] == '-' && class[i + 2] != ']' {
+      matched = matched || (class[i] <= c && c <= class[i + 2]);
+      i += 3;
+    } else {
+      matched = matched || class[i] == c;
+      i += 1;
+    }
+  }
+  None
+}
+
+impl Subscriber {
+  fn send(&mut self, resp: Response) -> Result<(), YakError> {
+    try!((self.sink)(resp));
+    self.last_sent_ms = millis_since_epoch();
+    Ok(())
+  }
+
+  // We wait for credit before pulling the next item, so that in work-queue
+  // mode we only lease what the consumer can take. Only catch-up
+  // subscriptions finish, at which point we end the stream ourselves,
+  // unless the subscriber has beaten us to it.
+  fn poll(&mut self) -> Result<Progress, Box<Error + Send>> {
+    for _ in 0..MAX_BATCH {
+      if self.credit.is_closed() {
+        return Ok(Progress::Done)
+      }
+      if self.credit.available() == 0 {
+        return Ok(Progress::Parked)
+      }
+      match try!((self.source)()) {
+        Polled::Ready(delivery) => {
+          self.credit.try_take();
+          let id = self.id;
+          try!(self.send(Response::Delivery(id, delivery)).map_err(|e| Box::new(e) as Box<Error + Send>));
+        },
+        Polled::NotYet => return Ok(Progress::Parked),
+        Polled::Finished => {
+          if self.credit.close() {
+            let id = self.id;
+            try!(self.send(Response::EndOfStream(id)).map_err(|e| Box::new(e) as Box<Error + Send>));
+          }
+          return Ok(Progress::Done)
+        },
+      }
+    }
+    Ok(Progress::Busy)
+  }
+
+  // Lets the subscriber know we're still here when there's been nothing
+  // else to send for a while.
+  fn heartbeat(&mut self) -> Result<(), YakError> {
+    if millis_since_epoch().saturating_sub(self.last_sent_ms) < HEARTBEAT_INTERVAL_MS / 2 {
+      return Ok(())
+    }
+    trace!("Heartbeat: {}", self.id);
+    let id = self.id;
+    self.send(Response::Heartbeat(id))
+  }
+}
+
+fn run(registry: &Arc<Mutex<Registry>>, workers: &WorkerPool<Job>, job: Job) {
+  let key = match job { Job::Poll(key) | Job::Heartbeat(key) => key };
+  let entry = match registry.lock().unwrap().entries.get(&key) {
+    Some(entry) => entry.clone(),
+    None => return,
+  };
+
+  let finished = match job {
+    Job::Poll(_) => {
+      entry.scheduled.store(false, Ordering::SeqCst);
+      let mut subscriber = entry.subscriber.lock().unwrap();
+      match subscriber.poll() {
+        Ok(Progress::Parked) => false,
+        Ok(Progress::Busy) => {
+          if !entry.scheduled.swap(true, Ordering::SeqCst) {
+            workers.submit(Job::Poll(key));
+          }
+          false
+        },
+        Ok(Progress::Done) => true,
+        Err(e) => {
+          error!("Subscription {} failed: {}", subscriber.id, e);
+          subscriber.credit.close();
+          true
+        },
+      }
+    },
+    // A subscription that's busy being polled has no need of heartbeats.
+    Job::Heartbeat(_) => match entry.subscriber.try_lock() {
+      Ok(mut subscriber) => match subscriber.heartbeat() {
+        Ok(()) => false,
+        Err(e) => {
+          warn!("Subscriber for {} has gone away: {}", subscriber.id, e);
+          subscriber.credit.close();
+          true
+        },
+      },
+      Err(_) => false,
+    },
+  };
+
+  if finished {
+    debug!("Subscription #{} finished", key);
+    registry.lock().unwrap().entries.remove(&key);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::collections::VecDeque;
+  use std::sync::{Arc, Mutex};
+  use std::sync::mpsc::{self, Receiver, Sender};
+  use std::time::{Duration, Instant};
+  use yak_client::{Response, Delivery};
+  use store::Polled;
+  use subscription::Credit;
+  use super::{Dispatcher, Source, Sink, Watch, glob_matches};
+
+  const SPACE : &'static str = "space";
+  const WAIT_MS : u64 = 1000;
+
+  // Deliveries for a subscriber to pick up, and whether there'll be more.
+  type Queue = Arc<Mutex<(VecDeque<Delivery>, bool)>>;
+
+  fn delivery(offset: u64) -> Delivery {
+    Delivery { space: SPACE.to_string(), offset: offset, key: b"key".to_vec(), content: b"value".to_vec() }
+  }
+
+  fn source(queue: &Queue) -> Source {
+    let queue = queue.clone();
+    Box::new(move || {
+      let mut queue = queue.lock().unwrap();
+      match queue.0.pop_front() {
+        Some(delivery) => Ok(Polled::Ready(delivery)),
+        None if queue.1 => Ok(Polled::Finished),
+        None => Ok(Polled::NotYet),
+      }
+    })
+  }
+
+  fn sink(sent: Sender<Response>) -> Sink {
+    Box::new(move |resp| {
+      let _ = sent.send(resp);
+      Ok(())
+    })
+  }
+
+  // A dispatcher with one subscription to `SPACE`, along with where to
+  // write and what it's sent.
+  fn subscribed(queue: &Queue, credit: &Credit) -> (Dispatcher, Sender<String>, Receiver<Response>) {
+    let (writes, written) = mpsc::channel();
+    let dispatcher = Dispatcher::new(1, written).unwrap();
+    let (sent, received) = mpsc::channel();
+    dispatcher.register(7, source(queue), sink(sent), credit.clone(), Watch::Space(SPACE.to_string()));
+    (dispatcher, writes, received)
+  }
+
+  fn next_offset(received: &Receiver<Response>) -> Option<u64> {
+    loop {
+      match received.recv_timeout(Duration::from_millis(WAIT_MS)) {
+        Ok(Response::Delivery(7, delivery)) => return Some(delivery.offset),
+        Ok(Response::Heartbeat(_)) => continue,
+        Ok(other) => panic!("Unexpected response: {:?}", other),
+        Err(_) => return None,
+      }
+    }
+  }
+
+  fn nothing_sent(received: &Receiver<Response>) -> bool {
+    match received.recv_timeout(Duration::from_millis(WAIT_MS / 10)) {
+      Ok(Response::Heartbeat(_)) | Err(_) => true,
+      Ok(_) => false,
+    }
+  }
+
+  #[test]
+  fn registered_subscriptions_deliver_only_as_much_as_their_credit() {
+    let queue : Queue = Arc::new(Mutex::new(((0..3).map(delivery).collect(), false)));
+    let credit = Credit::new();
+    credit.grant(2);
+    let (dispatcher, _writes, received) = subscribed(&queue, &credit);
+    assert_eq!(next_offset(&received), Some(0));
+    assert_eq!(next_offset(&received), Some(1));
+    assert!(nothing_sent(&received));
+    assert_eq!(queue.lock().unwrap().0.len(), 1);
+    dispatcher.stop();
+  }
+
+  #[test]
+  fn granting_credit_wakes_a_subscription() {
+    let queue : Queue = Arc::new(Mutex::new(((0..2).map(delivery).collect(), false)));
+    let credit = Credit::new();
+    let (dispatcher, _writes, received) = subscribed(&queue, &credit);
+    assert!(nothing_sent(&received));
+    credit.grant(1);
+    assert_eq!(next_offset(&received), Some(0));
+    credit.grant(1);
+    assert_eq!(next_offset(&received), Some(1));
+    dispatcher.stop();
+  }
+
+  #[test]
+  fn writes_wake_subscriptions_to_their_space() {
+    let queue : Queue = Arc::new(Mutex::new((VecDeque::new(), false)));
+    let credit = Credit::new();
+    credit.grant(10);
+    let (dispatcher, writes, received) = subscribed(&queue, &credit);
+    assert!(nothing_sent(&received));
+
+    queue.lock().unwrap().0.push_back(delivery(0));
+    writes.send("elsewhere".to_string()).unwrap();
+    assert!(nothing_sent(&received));
+    writes.send(SPACE.to_string()).unwrap();
+    assert_eq!(next_offset(&received), Some(0));
+    dispatcher.stop();
+  }
+
+  #[test]
+  fn finished_subscriptions_end_their_stream() {
+    let queue : Queue = Arc::new(Mutex::new(((0..1).map(delivery).collect(), true)));
+    let credit = Credit::new();
+    credit.grant(10);
+    let (dispatcher, _writes, received) = subscribed(&queue, &credit);
+    assert_eq!(next_offset(&received), Some(0));
+    match received.recv_timeout(Duration::from_millis(WAIT_MS)) {
+      Ok(Response::EndOfStream(7)) => (),
+      other => panic!("Expected end of stream, got: {:?}", other),
+    }
+    assert!(credit.is_closed());
+    dispatcher.stop();
+  }
+
+  #[test]
+  fn glob_matches_wildcards() {
+    assert!(glob_matches("orders-*", "orders-eu"));
+    assert!(glob_matches("orders-*", "orders-"));
+    assert!(glob_matches("*-eu", "orders-eu"));
+    assert!(glob_matches("orders-??", "orders-eu"));
+    assert!(!glob_matches("orders-??", "orders-eur"));
+    assert!(!glob_matches("orders-*", "invoices-eu"));
+  }
+
+  #[test]
+  fn glob_matches_character_classes() {
+    assert!(glob_matches("orders-[a-z]*", "orders-eu"));
+    assert!(!glob_matches("orders-[a-z]*", "orders-EU"));
+    assert!(glob_matches("orders-[ue][ue]", "orders-eu"));
+    assert!(glob_matches("orders-[^0-9]*", "orders-eu"));
+    assert!(!glob_matches("orders-[^e]*", "orders-eu"));
+    assert!(glob_matches("orders-[]x]", "orders-]"));
+    assert!(glob_matches("orders-[a-]", "orders--"));
+    assert!(!glob_matches("orders-[eu", "orders-eu"));
+  }
+
+  #[test]
+  fn glob_matches_characters_rather_than_bytes() {
+    assert!(glob_matches("commandes-?t?", "commandes-été"));
+    assert!(glob_matches("commandes-[à-ÿ]*", "commandes-été"));
+    assert!(!glob_matches("commandes-[^é]*", "commandes-été"));
+  }
+
+  #[test]
+  fn glob_matches_many_stars_quickly() {
+    let name : String = ::std::iter::repeat('a').take(200).collect();
+    let started = Instant::now();
+    assert!(!glob_matches("*a*a*a*a*a*a*a*a*a*a*b", &name));
+    assert!(glob_matches("*a*a*a*a*a*a*a*a*a*a*", &name));
+    assert!(started.elapsed() < Duration::from_secs(1));
+  }
+}
@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{self, SocketAddr, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::{Events, Poll, PollOpt, Ready, Registration, Token};
+use mio::net::TcpListener;
+use mio::unix::EventedFd;
+
+use server::{Session, DownStream, Peers, ServerError, report_session_errors};
+use config::Config;
+use dispatch::Dispatcher;
+use metrics::Metrics;
+use store::Store;
+use workers::WorkerPool;
+
+const LISTENER : Token = Token(0);
+const SHUTDOWN : Token = Token(1);
+const READ_CHUNK : usize = 64 * 1024;
+const DRAIN_POLL_MS : u64 = 10;
+
+/// How serving came to an end.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Shutdown {
+  /// Every request we'd started on was finished.
+  Clean,
+  /// We gave up waiting for this many connections to finish what they
+  /// were doing.
+  Abandoned(usize),
+}
+
+struct Connection<ST> {
+  stream: TcpStream,
+  outgoing: Outgoing,
+  session: Session<SocketAddr, TcpStream, ST>,
+  // Set once we've hung up, so that nobody reads from it afterwards.
+  closed: bool,
+}
+
+impl<ST: Store + Send + 'static> Connection<ST> {
+  // Ends every subscription, and once the client has been sent all that
+  // we have for it, hangs up. Returns whether we're done with it.
+  fn hang_up(&mut self) -> bool {
+    if !self.closed {
+      self.closed = true;
+      if let Err(e) = self.session.end_subscriptions() {
+        report_session_errors(&e);
+      }
+    }
+    if !self.outgoing.sent_all() {
+      return false
+    }
+    let _ = self.stream.shutdown(net::Shutdown::Both);
+    true
+  }
+}
+
+// What we've yet to send a client. Writing to it never blocks, so a client
+// that has stopped reading can't hold up the worker sending to it; whatever
+// the socket won't take straight away waits here until it's next writable.
+struct Outbox {
+  stream: TcpStream,
+  pending: Vec<u8>,
+  // How much of `pending` the client has already taken.
+  sent: usize,
+  // When we last found the client unwilling to take any more, if it hasn't
+  // taken anything since.
+  stalled_since: Option<Instant>,
+  timeout: Duration,
+}
+
+#[derive(Clone)]
+struct Outgoing(Arc<Mutex<Outbox>>);
+
+type Connections<ST> = Arc<Mutex<HashMap<Token, Arc<Mutex<Connection<ST>>>>>>;
+type Outboxes = Arc<Mutex<HashMap<Token, Outgoing>>>;
+
+/// Accepts connections on `listener`, and hands whatever clients send to a
+/// pool of session workers, so an idle client costs us a socket rather
+/// than a thread. Each connection is only read by one worker at a time, so
+/// its requests are still handled in the order they were sent.
+///
+/// Once `shutdown` becomes readable, we stop accepting connections and
+/// reading requests, wait for those we've started on to finish, and then end
+/// every subscription and hang up.
+pub fn serve<ST>(listener: net::TcpListener, config: &Config, store: ST, next: Option<DownStream<TcpStream>>,
+    dispatcher: Dispatcher, metrics: Metrics, shutdown: &Registration) -> Result<Shutdown, ServerError>
+    where ST: Store + Send + 'static {
+  let poll = Arc::new(try!(Poll::new()));
+  let listener = try!(TcpListener::from_std(listener));
+  try!(poll.register(&listener, LISTENER, Ready::readable(), PollOpt::level()));
+  try!(poll.register(shutdown, SHUTDOWN, Ready::readable(), PollOpt::edge()));
+
+  let connections : Connections<ST> = Arc::new(Mutex::new(HashMap::new()));
+  let outboxes : Outboxes = Arc::new(Mutex::new(HashMap::new()));
+  let peers = Peers::new();
+  let workers = {
+    let poll = poll.clone();
+    let connections = connections.clone();
+    let outboxes = outboxes.clone();
+    try!(WorkerPool::new("sessions", config.session_workers,
+      move |_, token| handle_readable(&poll, &connections, &outboxes, token)))
+  };
+
+  let mut events = Events::with_capacity(1024);
+  let mut next_token = SHUTDOWN.0 + 1;
+  'accepting: loop {
+    try!(poll.poll(&mut events, None));
+    for event in events.iter() {
+      match event.token() {
+        SHUTDOWN => break 'accepting,
+        LISTENER => (),
+        token if event.readiness().is_writable() => {
+          handle_writable(&outboxes, token);
+          continue
+        },
+        token => {
+          workers.submit(token);
+          continue
+        },
+      }
+      loop {
+        let (sock, peer) = match listener.accept_std() {
+          Ok(accepted) => accepted,
+          Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+          Err(e) => {
+            // Most likely out of file descriptors; we'll try again once
+            // the listener is next readable.
+            warn!("Could not accept connection: {}", e);
+            break
+          },
+        };
+        debug!("Accept stream from {:?}", peer);
+        let token = Token(next_token);
+        next_token += 2;
+        let connection = match open(sock, peer, config, &store, &next, &dispatcher, &peers, &metrics) {
+          Ok(connection) => connection,
+          Err(e) => {
+            report_session_errors(&e);
+            continue
+          },
+        };
+        let fd = connection.stream.as_raw_fd();
+        let out_fd = connection.outgoing.as_raw_fd();
+        outboxes.lock().unwrap().insert(writer_token(token), connection.outgoing.clone());
+        connections.lock().unwrap().insert(token, Arc::new(Mutex::new(connection)));
+        let registered = poll.register(&EventedFd(&fd), token, Ready::readable(), PollOpt::level() | PollOpt::oneshot())
+          .and_then(|()| poll.register(&EventedFd(&out_fd), writer_token(token), Ready::writable(), PollOpt::edge()));
+        if let Err(e) = registered {
+          warn!("Could not watch connection from {}: {}", peer, e);
+          connections.lock().unwrap().remove(&token);
+          outboxes.lock().unwrap().remove(&writer_token(token));
+        }
+      }
+    }
+  }
+
+  info!("Shutting down; no longer accepting connections");
+  try!(poll.deregister(&listener));
+  drop(listener);
+  let abandoned = drain(&connections, Duration::from_millis(config.shutdown_timeout_ms));
+  workers.stop();
+  dispatcher.stop();
+  match abandoned {
+    0 => Ok(Shutdown::Clean),
+    abandoned => Ok(Shutdown::Abandoned(abandoned)),
+  }
+}
+
+// Takes every connection out of circulation, so nothing more is read from
+// them, then hangs up on each once it's finished the request it's working
+// on, if any, and the client has taken what's left to send. Returns how
+// many we gave up waiting for.
+fn drain<ST>(connections: &Connections<ST>, timeout: Duration) -> usize
+    where ST: Store + Send + 'static {
+  let deadline = Instant::now() + timeout;
+  let mut pending : Vec<Arc<Mutex<Connection<ST>>>> = connections.lock().unwrap().drain().map(|(_, entry)| entry).collect();
+  debug!("Draining {} connections", pending.len());
+  loop {
+    pending.retain(|entry| match entry.try_lock() {
+      Ok(mut connection) => !connection.hang_up(),
+      Err(TryLockError::WouldBlock) => true,
+      Err(TryLockError::Poisoned(_)) => false,
+    });
+    if pending.is_empty() || Instant::now() >= deadline {
+      break
+    }
+    thread::sleep(Duration::from_millis(DRAIN_POLL_MS));
+  }
+  pending.len()
+}
+
+// The socket never blocks: we only read from it once we've been told
+// there's something to read, and responses go through its outbox. Its
+// writes are watched under a token of their own, on a copy of the socket,
+// so that reads can be watched one at a time while writes are not.
+fn open<ST>(sock: TcpStream, peer: SocketAddr, config: &Config, store: &ST, next: &Option<DownStream<TcpStream>>,
+    dispatcher: &Dispatcher, peers: &Peers, metrics: &Metrics) -> Result<Connection<ST>, ServerError>
+    where ST: Store + Send + 'static {
+  try!(sock.set_nonblocking(true));
+  let outgoing = Outgoing::new(try!(sock.try_clone()), Duration::from_millis(config.client_write_timeout_ms));
+  let advertise = config.advertise.as_ref().unwrap_or(&config.listen);
+  let session = Session::new(peer, advertise, outgoing.clone(), store.clone(), next.clone(), dispatcher.clone(),
+    peers.clone(), metrics.clone());
+  Ok(Connection { stream: sock, outgoing: outgoing, session: session, closed: false })
+}
+
+fn writer_token(token: Token) -> Token {
+  Token(token.0 + 1)
+}
+
+fn handle_writable(outboxes: &Outboxes, token: Token) {
+  let outgoing = match outboxes.lock().unwrap().get(&token) {
+    Some(outgoing) => outgoing.clone(),
+    None => return,
+  };
+  if let Err(e) = outgoing.0.lock().unwrap().push() {
+    // We'll hear about it when next reading, and hang up then.
+    debug!("Could not write to connection {:?}: {}", token, e);
+  }
+}
+
+fn handle_readable<ST>(poll: &Poll, connections: &Connections<ST>, outboxes: &Outboxes, token: Token)
+    where ST: Store + Send + 'static {
+  let entry = match connections.lock().unwrap().get(&token) {
+    Some(entry) => entry.clone(),
+    None => return,
+  };
+  let mut guard = entry.lock().unwrap();
+  let connection = &mut *guard;
+  if connection.closed {
+    return
+  }
+  let mut chunk = [0; READ_CHUNK];
+  let open = match connection.stream.read(&mut chunk) {
+    Ok(0) => false,
+    Ok(n) => match connection.session.feed(&chunk[..n]) {
+      Ok(()) => true,
+      Err(e) => {
+        report_session_errors(&e);
+        false
+      },
+    },
+    Err(ref e) if e.kind() == ErrorKind::Interrupted || e.kind() == ErrorKind::WouldBlock => true,
+    Err(e) => {
+      report_session_errors(&ServerError::from(e));
+      false
+    },
+  };
+
+  let fd = connection.stream.as_raw_fd();
+  if open {
+    match poll.reregister(&EventedFd(&fd), token, Ready::readable(), PollOpt::level() | PollOpt::oneshot()) {
+      Ok(()) => return,
+      Err(e) => report_session_errors(&ServerError::from(e)),
+    }
+  }
+  debug!("Closing connection {:?}", token);
+  connection.session.close();
+  let _ = poll.deregister(&EventedFd(&fd));
+  let _ = poll.deregister(&EventedFd(&connection.outgoing.as_raw_fd()));
+  outboxes.lock().unwrap().remove(&writer_token(token));
+  connections.lock().unwrap().remove(&token);
+}
+
+impl Outbox {
+  // Sends as much as the client will take without waiting.
+  fn push(&mut self) -> io::Result<()> {
+    while self.sent < self.pending.len() {
+      match self.stream.write(&self.pending[self.sent..]) {
+        Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "client took nothing")),
+        Ok(n) => {
+          self.sent += n;
+          self.stalled_since = None;
+        },
+        Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+          if self.stalled_since.is_none() {
+            self.stalled_since = Some(Instant::now());
+          }
+          break
+        },
+        Err(e) => return Err(e),
+      }
+    }
+    // Only moves what's left once it's no bigger than what's gone, so each
+    // byte is moved at most once on average.
+    if self.sent * 2 >= self.pending.len() {
+      self.pending.drain(..self.sent);
+      self.sent = 0;
+    }
+    Ok(())
+  }
+}
+
+impl Outgoing {
+  fn new(stream: TcpStream, timeout: Duration) -> Outgoing {
+    Outgoing(Arc::new(Mutex::new(Outbox { stream: stream, pending: Vec::new(), sent: 0, stalled_since: None, timeout: timeout })))
+  }
+
+  // Whether there's nothing left we could send; we count a client we can no
+  // longer write to as having everything.
+  fn sent_all(&self) -> bool {
+    let mut outbox = self.0.lock().unwrap();
+    outbox.push().is_err() || outbox.pending.is_empty()
+  }
+}
+
+impl AsRawFd for Outgoing {
+  fn as_raw_fd(&self) -> RawFd {
+    self.0.lock().unwrap().stream.as_raw_fd()
+  }
+}
+
+impl Write for Outgoing {
+  // A subscriber that has stopped reading altogether would otherwise have
+  // us hold on to its heartbeats and deliveries forever.
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let mut outbox = self.0.lock().unwrap();
+    if let Some(since) = outbox.stalled_since {
+      if since.elapsed() >= outbox.timeout {
+        return Err(io::Error::new(ErrorKind::TimedOut, "client has stopped reading"))
+      }
+    }
+    outbox.pending.extend_from_slice(buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.0.lock().unwrap().push()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::env;
+  use std::fs;
+  use std::io::Write;
+  use std::net::TcpStream;
+  use std::path::PathBuf;
+  use std::thread;
+  use std::time::Duration;
+  use rand::{self, Rng};
+  use yak_client::{self, Client, WireProtocol, Request, Response, Operation, KeyFilter};
+  use config::Config;
+  use server::{Server, RunningServer};
+  use testing::faults::{Fault, Proxy};
+  use super::Shutdown;
+
+  const READ_TIMEOUT_MS : u64 = 5000;
+  // How long the head waits for the tail to answer each write.
+  const LINK_DELAY_MS : u64 = 300;
+
+  fn temp_dir() -> PathBuf {
+    let dir = env::temp_dir().join(format!("yak-event-loop-{}", rand::thread_rng().gen_ascii_chars().take(12).collect::<String>()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  // Has one worker of each kind, so anything that holds one up holds up
+  // everyone.
+  fn start() -> (RunningServer, PathBuf) {
+    let dir = temp_dir();
+    let server = Server::new(&dir).session_workers(1).subscription_workers(1).start().unwrap();
+    (server, dir)
+  }
+
+  // Shuts down a head that's part way through a write, which is held up on
+  // its way to the tail, and returns how that went.
+  fn shut_down_mid_write(shutdown_timeout_ms: u64) -> (Shutdown, thread::JoinHandle<bool>) {
+    let (head_dir, tail_dir) = (temp_dir(), temp_dir());
+    let tail = Server::new(&tail_dir).start().unwrap();
+    let link = Proxy::start(tail.local_addr()).unwrap();
+    let mut config = Config::new(&head_dir, "127.0.0.1:0");
+    config.next = Some(link.local_addr().to_string());
+    config.shutdown_timeout_ms = shutdown_timeout_ms;
+    let head = Server::from_config(config).start().unwrap();
+    link.faults().set(Fault::Delay(Duration::from_millis(LINK_DELAY_MS)));
+
+    let url = head.url("in-flight");
+    let writer = thread::spawn(move || Client::connect(&url).and_then(|mut client| client.write(b"key", b"value")).is_ok());
+    thread::sleep(Duration::from_millis(LINK_DELAY_MS / 3));
+    let outcome = head.shutdown().unwrap();
+
+    link.faults().clear();
+    tail.shutdown().unwrap();
+    fs::remove_dir_all(&head_dir).unwrap();
+    fs::remove_dir_all(&tail_dir).unwrap();
+    (outcome, writer)
+  }
+
+  fn request(seq: u64, space: &str, operation: Operation) -> Request {
+    Request { sequence: seq, space: space.to_string(), deadline: None, operation: operation }
+  }
+
+  #[test]
+  fn requests_are_answered_however_they_arrive() {
+    let (server, dir) = start();
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream.set_nodelay(true).unwrap();
+    let write = request(1, "split", Operation::Write { key: b"key".to_vec(), value: b"value".to_vec(), expected: None,
+      producer: None });
+    for byte in yak_client::encode_packed(&write).unwrap() {
+      stream.write_all(&[byte]).unwrap();
+    }
+    stream.set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT_MS))).unwrap();
+    let mut protocol = WireProtocol::new(stream);
+    let resp : Response = protocol.read().unwrap().unwrap();
+    assert_eq!(resp.expect_written().map(|(seq, _)| seq).ok(), Some(1));
+
+    server.shutdown().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn a_subscriber_that_stops_reading_holds_up_nobody_else() {
+    let (server, dir) = start();
+    // Far more than the socket buffers will take.
+    let value = vec![b'x'; 64 * 1024];
+    let mut writer = Client::connect(&server.url("big")).unwrap();
+    for _ in 0..256 {
+      writer.write(b"key", &value).unwrap();
+    }
+    let mut stalled = WireProtocol::connect(server.local_addr()).unwrap();
+    stalled.send(&request(1, "big", Operation::Subscribe { filter: KeyFilter::All, space_pattern: None, catch_up: false,
+      start: 0, consumer: None })).unwrap();
+    stalled.send(&request(2, "big", Operation::Credit { subscription: 1, amount: 1000 })).unwrap();
+
+    let reader = Client::connect(&server.url("small")).unwrap();
+    let mut subscription = reader.subscribe().unwrap();
+    Client::connect(&server.url("small")).unwrap().write(b"key", b"value").unwrap();
+    let delivery = subscription.fetch_next_within(READ_TIMEOUT_MS).unwrap().expect("delivery");
+    assert_eq!(delivery.content, b"value".to_vec());
+
+    drop(stalled);
+    server.shutdown().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn shutting_down_lets_requests_in_flight_finish() {
+    let (outcome, writer) = shut_down_mid_write(10 * LINK_DELAY_MS);
+    assert_eq!(outcome, Shutdown::Clean);
+    assert!(writer.join().unwrap(), "The write in flight should have been answered");
+  }
+
+  #[test]
+  fn shutting_down_gives_up_on_requests_that_take_too_long() {
+    let (outcome, writer) = shut_down_mid_write(LINK_DELAY_MS / 10);
+    assert_eq!(outcome, Shutdown::Abandoned(1));
+    // The client goes on trying to reconnect for a while; there's no need
+    // to wait for it to give up.
+    drop(writer);
+  }
+
+  #[test]
+  fn shutting_down_ends_every_subscription() {
+    let (server, dir) = start();
+    let client = Client::connect(&server.url("ending")).unwrap();
+    let mut subscription = client.subscribe().unwrap();
+    Client::connect(&server.url("ending")).unwrap().write(b"key", b"value").unwrap();
+    assert!(subscription.fetch_next_within(READ_TIMEOUT_MS).unwrap().is_some());
+
+    assert_eq!(server.shutdown().unwrap(), Shutdown::Clean);
+    assert_eq!(subscription.fetch_next_within(READ_TIMEOUT_MS).unwrap().map(|d| d.offset), None);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,40 @@
+#[macro_use] extern crate log;
+extern crate yak_client;
+extern crate capnp;
+extern crate rusqlite;
+extern crate mio;
+extern crate getopts;
+extern crate toml;
+extern crate rustc_serialize;
+#[cfg(any(test, feature = "testing"))]
+extern crate rand;
+#[cfg(test)]
+extern crate quickcheck;
+#[cfg(test)]
+extern crate log4rs;
+
+macro_rules! try_box {
+    ($expr:expr) => (match $expr {
+        Ok(val) => val,
+        Err(err) => {
+            return Err(From::from(Box::new(err) as Box<::std::error::Error + Send + 'static>))
+        }
+    })
+}
+
+#[macro_use] mod store;
+pub mod config;
+mod sqlite_store;
+mod subscription;
+mod workers;
+mod dispatch;
+mod event_loop;
+mod server;
+pub mod metrics;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+pub use config::{Config, ConfigError};
+pub use event_loop::Shutdown;
+pub use server::{Server, RunningServer, Stopper, ServerError};
+pub use metrics::Metrics;
@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// Upper bounds, in seconds, of the latency histograms' buckets.
+const LATENCY_BUCKETS : &'static [f64] = &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+const CONTENT_TYPE : &'static str = "text/plain; version=0.0.4";
+
+#[derive(Default)]
+struct Counter(AtomicUsize);
+
+impl Counter {
+  fn add(&self, n: usize) {
+    self.0.fetch_add(n, Ordering::Relaxed);
+  }
+
+  fn get(&self) -> usize {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+#[derive(Default)]
+struct Gauge(AtomicIsize);
+
+impl Gauge {
+  fn add(&self, n: isize) {
+    self.0.fetch_add(n, Ordering::Relaxed);
+  }
+
+  fn set(&self, n: isize) {
+    self.0.store(n, Ordering::Relaxed);
+  }
+
+  fn get(&self) -> isize {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+struct Histogram {
+  // Not cumulative; we add them up when rendering.
+  buckets: Vec<AtomicUsize>,
+  count: AtomicUsize,
+  sum_micros: AtomicUsize,
+}
+
+impl Histogram {
+  fn new() -> Histogram {
+    Histogram {
+      buckets: LATENCY_BUCKETS.iter().map(|_| AtomicUsize::new(0)).collect(),
+      count: AtomicUsize::new(0),
+      sum_micros: AtomicUsize::new(0),
+    }
+  }
+
+  fn observe(&self, elapsed: Duration) {
+    let micros = elapsed.as_secs() as usize * 1_000_000 + elapsed.subsec_nanos() as usize / 1000;
+    let seconds = micros as f64 / 1e6;
+    if let Some(i) = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound) {
+      self.buckets[i].fetch_add(1, Ordering::Relaxed);
+    }
+    self.count.fetch_add(1, Ordering::Relaxed);
+    self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+  }
+}
+
+// One of a kind of metric for each value of a label.
+struct Family<M> {
+  label: &'static str,
+  members: Mutex<BTreeMap<String, Arc<M>>>,
+}
+
+impl<M: Default> Family<M> {
+  fn new(label: &'static str) -> Family<M> {
+    Family { label: label, members: Mutex::new(BTreeMap::new()) }
+  }
+
+  fn get(&self, value: &str) -> Arc<M> {
+    let mut members = self.members.lock().unwrap();
+    if let Some(member) = members.get(value) {
+      return member.clone()
+    }
+    let member = Arc::new(M::default());
+    members.insert(value.to_string(), member.clone());
+    member
+  }
+
+  fn snapshot(&self) -> Vec<(String, Arc<M>)> {
+    self.members.lock().unwrap().iter().map(|(value, member)| (value.clone(), member.clone())).collect()
+  }
+}
+
+struct Registry {
+  requests: Family<Counter>,
+  write_latency: Histogram,
+  read_latency: Histogram,
+  downstream_latency: Histogram,
+  bytes_received: Counter,
+  bytes_sent: Counter,
+  sessions: Gauge,
+  subscriptions: Gauge,
+  last_offsets: Family<Gauge>,
+}
+
+/// What the server is up to, shared between everything that contributes to
+/// it. Clones all update the same figures.
+#[derive(Clone)]
+pub struct Metrics {
+  registry: Arc<Registry>,
+}
+
+impl Metrics {
+  pub fn new() -> Metrics {
+    Metrics {
+      registry: Arc::new(Registry {
+        requests: Family::new("operation"),
+        write_latency: Histogram::new(),
+        read_latency: Histogram::new(),
+        downstream_latency: Histogram::new(),
+        bytes_received: Counter::default(),
+        bytes_sent: Counter::default(),
+        sessions: Gauge::default(),
+        subscriptions: Gauge::default(),
+        last_offsets: Family::new("space"),
+      })
+    }
+  }
+
+  pub fn request(&self, operation: &str) {
+    self.registry.requests.get(operation).add(1);
+  }
+
+  pub fn wrote(&self, elapsed: Duration) {
+    self.registry.write_latency.observe(elapsed);
+  }
+
+  pub fn read(&self, elapsed: Duration) {
+    self.registry.read_latency.observe(elapsed);
+  }
+
+  pub fn downstream_round_trip(&self, elapsed: Duration) {
+    self.registry.downstream_latency.observe(elapsed);
+  }
+
+  pub fn received(&self, bytes: usize) {
+    self.registry.bytes_received.add(bytes);
+  }
+
+  pub fn sent(&self, bytes: usize) {
+    self.registry.bytes_sent.add(bytes);
+  }
+
+  pub fn session_opened(&self) {
+    self.registry.sessions.add(1);
+  }
+
+  pub fn session_closed(&self) {
+    self.registry.sessions.add(-1);
+  }
+
+  pub fn subscriptions_changed(&self, by: isize) {
+    self.registry.subscriptions.add(by);
+  }
+
+  pub fn appended(&self, space: &str, offset: i64) {
+    self.registry.last_offsets.get(space).set(offset as isize);
+  }
+
+  /// Everything, in the Prometheus text exposition format.
+  pub fn render(&self) -> String {
+    let r = &self.registry;
+    let mut out = String::new();
+    family(&mut out, "yak_requests_total", "counter", "Requests handled, by operation.", &r.requests,
+      |c: &Counter| c.get() as f64);
+    histogram(&mut out, "yak_write_duration_seconds", "Time taken to write, including the rest of the chain.", &r.write_latency);
+    histogram(&mut out, "yak_read_duration_seconds", "Time taken to read.", &r.read_latency);
+    histogram(&mut out, "yak_downstream_round_trip_seconds", "Time taken for the next node to respond.", &r.downstream_latency);
+    single(&mut out, "yak_received_bytes_total", "counter", "Bytes received from clients.", r.bytes_received.get() as f64);
+    single(&mut out, "yak_sent_bytes_total", "counter", "Bytes sent to clients.", r.bytes_sent.get() as f64);
+    single(&mut out, "yak_sessions", "gauge", "Connected clients.", r.sessions.get() as f64);
+    single(&mut out, "yak_subscriptions", "gauge", "Open subscriptions.", r.subscriptions.get() as f64);
+    family(&mut out, "yak_space_last_offset", "gauge", "Offset of the last record appended to each space.", &r.last_offsets,
+      |g: &Gauge| g.get() as f64);
+    out
+  }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+  let _ = writeln!(out, "# HELP {} {}", name, help);
+  let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn single(out: &mut String, name: &str, kind: &str, help: &str, value: f64) {
+  header(out, name, kind, help);
+  let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn family<M: Default, F: Fn(&M) -> f64>(out: &mut String, name: &str, kind: &str, help: &str, family: &Family<M>, value: F) {
+  header(out, name, kind, help);
+  for (label, member) in family.snapshot() {
+    let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, family.label, escape(&label), value(&*member));
+  }
+}
+
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+  header(out, name, "histogram", help);
+  let mut cumulative = 0;
+  for (bound, bucket) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+    cumulative += bucket.load(Ordering::Relaxed);
+    let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+  }
+  let count = histogram.count.load(Ordering::Relaxed);
+  let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+  let _ = writeln!(out, "{}_sum {}", name, histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+  let _ = writeln!(out, "{}_count {}", name, count);
+}
+
+fn escape(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves `GET /metrics` over HTTP, one request at a time, until dropped.
+pub struct Endpoint {
+  local_addr: SocketAddr,
+  stopped: Arc<AtomicBool>,
+}
+
+impl Endpoint {
+  pub fn start(addr: &str, metrics: Metrics) -> io::Result<Endpoint> {
+    let listener = try!(TcpListener::bind(addr));
+    let local_addr = try!(listener.local_addr());
+    let stopped = Arc::new(AtomicBool::new(false));
+    {
+      let stopped = stopped.clone();
+      try!(thread::Builder::new().name("metrics".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+          if stopped.load(Ordering::SeqCst) {
+            break
+          }
+          if let Err(e) = stream.and_then(|stream| respond(stream, &metrics)) {
+            warn!("Could not serve metrics: {}", e);
+          }
+        }
+      }));
+    }
+    info!("Serving metrics on http://{}/metrics", local_addr);
+    Ok(Endpoint { local_addr: local_addr, stopped: stopped })
+  }
+
+  pub fn local_addr(&self) -> SocketAddr {
+    self.local_addr
+  }
+}
+
+impl Drop for Endpoint {
+  fn drop(&mut self) {
+    self.stopped.store(true, Ordering::SeqCst);
+    // Wakes the listener, so it notices.
+    let _ = TcpStream::connect(self.local_addr);
+  }
+}
+
+// We only care about the request line; the headers are read and ignored.
+fn respond(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+  try!(stream.set_read_timeout(Some(Duration::from_secs(5))));
+  let mut reader = BufReader::new(try!(stream.try_clone()));
+  let mut request = String::new();
+  try!(reader.read_line(&mut request));
+  loop {
+    let mut header = String::new();
+    if try!(reader.read_line(&mut header)) == 0 || header.trim().is_empty() {
+      break
+    }
+  }
+  let mut parts = request.split_whitespace();
+  let (status, body) = match (parts.next(), parts.next()) {
+    (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+    (Some("GET"), Some(_)) => ("404 Not Found", "Not found\n".to_string()),
+    _ => ("405 Method Not Allowed", "Only GET is supported\n".to_string()),
+  };
+  let mut stream = stream;
+  try!(write!(stream, "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+    status, CONTENT_TYPE, body.len()));
+  try!(stream.write_all(body.as_bytes()));
+  stream.flush()
+}
+
+#[cfg(test)]
+mod test {
+  use super::Metrics;
+  use std::time::Duration;
+
+  #[test]
+  fn renders_labelled_counters() {
+    let metrics = Metrics::new();
+    metrics.request("write");
+    metrics.request("write");
+    metrics.request("read");
+    let text = metrics.render();
+    assert!(text.contains("yak_requests_total{operation=\"read\"} 1\n"));
+    assert!(text.contains("yak_requests_total{operation=\"write\"} 2\n"));
+  }
+
+  #[test]
+  fn histogram_buckets_are_cumulative() {
+    let metrics = Metrics::new();
+    metrics.read(Duration::from_millis(2));
+    metrics.read(Duration::from_millis(20));
+    let text = metrics.render();
+    assert!(text.contains("yak_read_duration_seconds_bucket{le=\"0.001\"} 0\n"));
+    assert!(text.contains("yak_read_duration_seconds_bucket{le=\"0.0025\"} 1\n"));
+    assert!(text.contains("yak_read_duration_seconds_bucket{le=\"0.025\"} 2\n"));
+    assert!(text.contains("yak_read_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+    assert!(text.contains("yak_read_duration_seconds_count 2\n"));
+  }
+
+  #[test]
+  fn space_names_are_escaped() {
+    let metrics = Metrics::new();
+    metrics.appended("a \"quoted\" space", 7);
+    assert!(metrics.render().contains("yak_space_last_offset{space=\"a \\\"quoted\\\" space\"} 7\n"));
+  }
+}
@@ -0,0 +1,1226 @@
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::io::{self,Read,Write};
+use std::fmt;
+use std::sync::{Arc,Mutex};
+use std::clone::Clone;
+use std::error::Error;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::Instant;
+
+use capnp;
+use yak_client;
+use mio::{Registration, SetReadiness, Ready};
+use yak_client::{WireProtocol,Request,Response,Operation,Datum,Delivery,SeqNo,Offset,SubscriptionId,YakError,QueueConfig};
+use yak_client::{Expected,Record,Producer,NodeStatus,Role,DownstreamStatus,SessionStatus,SubscriptionStatus,Decoder};
+
+use config::Config;
+use event_loop::{self, Shutdown};
+use metrics::{self, Metrics};
+use sqlite_store::SqliteStore;
+use subscription::Credit;
+use dispatch::{Dispatcher, Source, Sink, Watch};
+use store::{Store, Waiting};
+
+#[derive(Debug)]
+pub enum ServerError {
+  CapnpError(capnp::Error),
+  CapnpNotInSchema(capnp::NotInSchema),
+  IoError(io::Error),
+  DownstreamError(YakError),
+  StoreError(Box<Error + Send>),
+  UnexpectedOperation(Operation),
+}
+
+impl fmt::Display for ServerError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    match self {
+      &ServerError::CapnpError(ref e) => e.fmt(f),
+      &ServerError::CapnpNotInSchema(ref e) => e.fmt(f),
+      &ServerError::IoError(ref e) => e.fmt(f),
+      &ServerError::DownstreamError(ref e) => e.fmt(f),
+      &ServerError::StoreError(ref e) => write!(f, "{}", e),
+      &ServerError::UnexpectedOperation(ref op) => write!(f, "Unexpected operation: {:?}", op),
+    }
+  }
+}
+
+impl Error for ServerError {
+  fn description(&self) -> &str {
+    match self {
+      &ServerError::CapnpError(ref e) => e.description(),
+      &ServerError::CapnpNotInSchema(ref e) => e.description(),
+      &ServerError::IoError(ref e) => e.description(),
+      &ServerError::DownstreamError(ref e) => e.description(),
+      &ServerError::StoreError(ref e) => e.description(),
+      &ServerError::UnexpectedOperation(_) => "Unexpected operation",
+    }
+  }
+}
+
+/// Opens a fresh connection to the next node.
+type Connect<S> = Arc<Fn() -> Result<WireProtocol<S>, ServerError> + Send + Sync>;
+
+pub struct DownStream<S: Read+Write> {
+  address: String,
+  protocol: Arc<Mutex<WireProtocol<S>>>,
+  // Whether the last exchange succeeded, if there's been one; once one
+  // fails, the connection is no use to anyone, so the next exchange starts
+  // on a new one if we can.
+  healthy: Arc<Mutex<Option<bool>>>,
+  connect: Option<Connect<S>>,
+  // What we've told the next node about ourselves, so that we can tell it
+  // again on a new connection.
+  announced: Arc<Mutex<Option<String>>>,
+  metrics: Metrics,
+}
+
+impl <S: ::std::fmt::Debug + Read + Write> ::std::fmt::Debug for DownStream<S>
+ where S: ::std::fmt::Debug + 'static {
+    fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self.protocol.try_lock() {
+            Ok(ref proto) => write!(fmt, "DownStream{{ protocol: {:?} }}", &**proto),
+            Err(_) => write!(fmt, "DownStream{{ protocol: <locked> }}"),
+        }
+    }
+}
+
+impl<S> Clone for DownStream<S> where S: Read+Write {
+  fn clone(&self) -> DownStream<S> {
+    DownStream { address: self.address.clone(), protocol: self.protocol.clone(), healthy: self.healthy.clone(),
+      connect: self.connect.clone(), announced: self.announced.clone(), metrics: self.metrics.clone() }
+  }
+}
+
+/// What a node's sessions know of each other: who each is with, and what
+/// they're subscribed to, and the address the node before this one in the
+/// chain advertises, once it has connected and said so.
+#[derive(Clone)]
+pub struct Peers {
+  upstream: Arc<Mutex<Option<String>>>,
+  sessions: Arc<Mutex<Sessions>>,
+}
+
+struct Sessions {
+  next_key: usize,
+  // Each session's peer, and its subscriptions.
+  entries: BTreeMap<usize, (String, Subscriptions)>,
+}
+
+// What each of a session's subscriptions is to, and the credit that keeps
+// it going.
+struct Subscribed {
+  space: String,
+  credit: Credit,
+}
+
+type Subscriptions = Arc<Mutex<HashMap<SubscriptionId, Subscribed>>>;
+
+impl Peers {
+  pub fn new() -> Peers {
+    Peers {
+      upstream: Arc::new(Mutex::new(None)),
+      sessions: Arc::new(Mutex::new(Sessions { next_key: 0, entries: BTreeMap::new() })),
+    }
+  }
+
+  // Returns the key to leave with.
+  fn join(&self, peer: &str, subscriptions: &Subscriptions) -> usize {
+    let mut sessions = self.sessions.lock().unwrap();
+    let key = sessions.next_key;
+    sessions.next_key += 1;
+    sessions.entries.insert(key, (peer.to_string(), subscriptions.clone()));
+    key
+  }
+
+  fn leave(&self, key: usize) {
+    self.sessions.lock().unwrap().entries.remove(&key);
+  }
+
+  // In peer order, leaving out subscriptions that have already ended.
+  fn sessions(&self) -> Vec<SessionStatus> {
+    let sessions = self.sessions.lock().unwrap();
+    let mut statuses : Vec<SessionStatus> = sessions.entries.values().map(|&(ref peer, ref subscriptions)| {
+      let mut open : Vec<SubscriptionStatus> = subscriptions.lock().unwrap().iter()
+        .filter(|&(_, subscribed)| !subscribed.credit.is_closed())
+        .map(|(&id, subscribed)| SubscriptionStatus { id: id, space: subscribed.space.clone() })
+        .collect();
+      open.sort_by(|a, b| a.id.cmp(&b.id));
+      SessionStatus { peer: peer.clone(), subscriptions: open }
+    }).collect();
+    statuses.sort_by(|a, b| a.peer.cmp(&b.peer));
+    statuses
+  }
+
+  fn upstream(&self) -> Option<String> {
+    self.upstream.lock().unwrap().clone()
+  }
+
+  fn set_upstream(&self, address: &str) {
+    *self.upstream.lock().unwrap() = Some(address.to_string());
+  }
+
+  // Unless it has already reconnected by now.
+  fn forget_upstream(&self, address: &str) {
+    let mut upstream = self.upstream.lock().unwrap();
+    if upstream.as_ref().map(|a| a == address).unwrap_or(false) {
+      *upstream = None;
+    }
+  }
+}
+
+/// Settings for a node of the chain, which `start` sets running. By default
+/// it listens on a port of the operating system's choosing on localhost.
+pub struct Server {
+  config: Config,
+}
+
+impl Server {
+  pub fn new(store_dir: &Path) -> Server {
+    Server::from_config(Config::new(store_dir, "127.0.0.1:0"))
+  }
+
+  pub fn from_config(config: Config) -> Server {
+    Server { config: config }
+  }
+
+  pub fn listen(mut self, addr: &str) -> Server {
+    self.config.listen = addr.to_string();
+    self
+  }
+
+  /// The address given to clients, if not the one we're listening on.
+  pub fn advertise(mut self, addr: &str) -> Server {
+    self.config.advertise = Some(addr.to_string());
+    self
+  }
+
+  /// The next node along the chain, which must already be running.
+  pub fn next(mut self, addr: &str) -> Server {
+    self.config.next = Some(addr.to_string());
+    self
+  }
+
+  pub fn session_workers(mut self, workers: usize) -> Server {
+    self.config.session_workers = workers;
+    self
+  }
+
+  pub fn subscription_workers(mut self, workers: usize) -> Server {
+    self.config.subscription_workers = workers;
+    self
+  }
+
+  /// Serves metrics over HTTP on `addr`.
+  pub fn metrics_listen(mut self, addr: &str) -> Server {
+    self.config.metrics_listen = Some(addr.to_string());
+    self
+  }
+
+  /// Keeps only the last `max_records` records of each space.
+  pub fn max_records(mut self, max_records: u64) -> Server {
+    self.config.store.max_records = Some(max_records);
+    self
+  }
+
+  /// Starts serving from a thread of its own.
+  pub fn start(self) -> Result<RunningServer, ServerError> {
+    let mut config = self.config;
+    debug!("Configuration: {:?}", config);
+    let metrics = Metrics::new();
+    let next = match config.next {
+        Some(ref addr) => Some(try!(DownStream::new(addr, metrics.clone()))),
+        None => None
+    };
+
+    let listener = try!(TcpListener::bind(&config.listen as &str));
+    let local_addr = try!(listener.local_addr());
+    let advertised = config.advertise.clone().unwrap_or_else(|| local_addr.to_string());
+    config.advertise = Some(advertised.clone());
+    if let Some(ref next) = next {
+      try!(next.announce(&advertised));
+    }
+    let store = try_box!(SqliteStore::open(&config.store)).with_metrics(metrics.clone());
+    // From here on, writes keep these up to date.
+    for space in try_box!(store.spaces()) {
+      metrics.appended(&space.name, space.last as i64);
+    }
+    let dispatcher = try!(Dispatcher::new(config.subscription_workers, store.watch()));
+    let endpoint = match config.metrics_listen {
+      Some(ref addr) => Some(try!(metrics::Endpoint::start(addr, metrics.clone()))),
+      None => None,
+    };
+    let (registration, stop) = Registration::new2();
+    let session_metrics = metrics.clone();
+    let thread = try!(thread::Builder::new().name(format!("server-{}", local_addr)).spawn(move || -> Result<Shutdown, ServerError> {
+      let outcome = try!(event_loop::serve(listener, &config, store.clone(), next, dispatcher, session_metrics, &registration));
+      try_box!(store.flush());
+      Ok(outcome)
+    }));
+    info!("listening started on {}, ready to accept", local_addr);
+    Ok(RunningServer { local_addr: local_addr, advertised: advertised, stop: Stopper(stop), thread: thread,
+      metrics: metrics, endpoint: endpoint })
+  }
+}
+
+/// Asks a running server to shut down.
+#[derive(Clone)]
+pub struct Stopper(SetReadiness);
+
+impl Stopper {
+  pub fn stop(&self) -> Result<(), ServerError> {
+    try!(self.0.set_readiness(Ready::readable()));
+    Ok(())
+  }
+}
+
+pub struct RunningServer {
+  local_addr: SocketAddr,
+  advertised: String,
+  stop: Stopper,
+  thread: thread::JoinHandle<Result<Shutdown, ServerError>>,
+  metrics: Metrics,
+  endpoint: Option<metrics::Endpoint>,
+}
+
+impl RunningServer {
+  pub fn local_addr(&self) -> SocketAddr {
+    self.local_addr
+  }
+
+  /// The URL clients should use for `space` on this node.
+  pub fn url(&self, space: &str) -> String {
+    format!("yak://{}/{}", self.advertised, space)
+  }
+
+  pub fn metrics(&self) -> &Metrics {
+    &self.metrics
+  }
+
+  /// Where metrics are being served, if they are.
+  pub fn metrics_addr(&self) -> Option<SocketAddr> {
+    self.endpoint.as_ref().map(|endpoint| endpoint.local_addr())
+  }
+
+  pub fn stopper(&self) -> Stopper {
+    self.stop.clone()
+  }
+
+  /// Shuts the server down, and waits for it to finish.
+  pub fn shutdown(self) -> Result<Shutdown, ServerError> {
+    try!(self.stop.stop());
+    self.wait()
+  }
+
+  /// Waits for the server to be shut down.
+  pub fn wait(self) -> Result<Shutdown, ServerError> {
+    match self.thread.join() {
+      Ok(outcome) => outcome,
+      Err(_) => Err(ServerError::IoError(io::Error::new(io::ErrorKind::Other, "Server thread panicked"))),
+    }
+  }
+}
+
+pub fn report_session_errors(error: &Error) {
+  error!("Session failed with: {}", error);
+  while let Some(error) = error.cause() {
+    error!("\tCaused by: {}", error);
+  }
+}
+
+impl DownStream<TcpStream> {
+  fn new(addr: &str, metrics: Metrics) -> Result<DownStream<TcpStream>, ServerError> {
+    debug!("Connect downstream: {:?}", addr);
+    let proto = try_box!(WireProtocol::connect(addr));
+    debug!("Connected downstream: {:?}", proto);
+
+    let target = addr.to_string();
+    let connect : Connect<TcpStream> = Arc::new(move || -> Result<WireProtocol<TcpStream>, ServerError> {
+      Ok(try_box!(WireProtocol::connect(&target as &str)))
+    });
+    Ok(DownStream { connect: Some(connect), ..DownStream::over(addr, proto, metrics) })
+  }
+}
+
+
+fn ptr_addr<T>(obj:&T) -> usize {
+  return obj as *const T as usize;
+}
+
+impl<S: Read+Write> DownStream<S> {
+  /// Talks to the node at `address` over `protocol`, and only that; once
+  /// it fails, every exchange after it fails too.
+  pub fn over(address: &str, protocol: WireProtocol<S>, metrics: Metrics) -> DownStream<S> {
+    DownStream { address: address.to_string(), protocol: Arc::new(Mutex::new(protocol)),
+      healthy: Arc::new(Mutex::new(None)), connect: None, announced: Arc::new(Mutex::new(None)), metrics: metrics }
+  }
+
+  /// Lets the next node know that we come before it, and where we are.
+  fn announce(&self, address: &str) -> Result<(), ServerError> {
+    *self.announced.lock().unwrap() = Some(address.to_string());
+    try!(try!(self.handle(&Request::upstream(0, address))).expect_ok());
+    Ok(())
+  }
+
+  fn status(&self) -> DownstreamStatus {
+    DownstreamStatus { address: self.address.clone(), healthy: *self.healthy.lock().unwrap() }
+  }
+
+  fn handle(&self, msg: &Request) -> Result<Response, ServerError> {
+    let resp = self.exchange(msg);
+    *self.healthy.lock().unwrap() = Some(resp.is_ok());
+    resp
+  }
+
+  fn exchange(&self, msg: &Request) -> Result<Response, ServerError> {
+    let mut wire = self.protocol.lock().unwrap();
+    if *self.healthy.lock().unwrap() == Some(false) {
+      try!(self.reconnect(&mut wire));
+    }
+    let sent = Instant::now();
+    let resp = try!(round_trip(&mut wire, msg));
+    self.metrics.downstream_round_trip(sent.elapsed());
+    Ok(resp)
+  }
+
+  // Nothing is resent; whatever failed before stays failed.
+  fn reconnect(&self, wire: &mut WireProtocol<S>) -> Result<(), ServerError> {
+    let connect = match self.connect {
+      Some(ref connect) => connect,
+      None => return Ok(()),
+    };
+    debug!("Reconnecting downstream to {}", self.address);
+    *wire = try!(connect());
+    if let Some(ref address) = *self.announced.lock().unwrap() {
+      try!(try!(round_trip(wire, &Request::upstream(0, address))).expect_ok());
+    }
+    Ok(())
+  }
+}
+
+fn round_trip<S: Read+Write>(wire: &mut WireProtocol<S>, msg: &Request) -> Result<Response, ServerError> {
+  debug!("Downstream: -> {:x}", ptr_addr(&msg));
+  try!(wire.send(msg));
+  debug!("Downstream wait:  {:x}", ptr_addr(&msg));
+  let resp = try!(wire.read::<Response>());
+  debug!("Downstream: <- {:x}", ptr_addr(&resp));
+  resp.map(Ok).unwrap_or(Err(ServerError::DownstreamError(YakError::ProtocolError)))
+}
+
+// Sessions don't read from the client themselves; instead they're fed
+// whatever arrives, and act on each request once it's all there.
+pub struct Session<Id, S:Read+Write+'static, ST> {
+  id: Id,
+  /// The address we advertise to clients for this node.
+  address: String,
+  input: Decoder,
+  writer: Arc<Mutex<WireProtocol<Counted>>>,
+  store: ST,
+  next: Option<DownStream<S>>,
+  dispatcher: Dispatcher,
+  peers: Peers,
+  /// The address of the node before us, if that's who this session is with.
+  upstream: Option<String>,
+  subscriptions: Subscriptions,
+  // Our key in `peers`.
+  registration: usize,
+  metrics: Metrics,
+}
+
+// Counts what we send to the client. Sessions never read from it, as
+// whatever the client sends is fed to them.
+struct Counted {
+  inner: Box<Write + Send>,
+  metrics: Metrics,
+}
+
+impl Read for Counted {
+  fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+    Ok(0)
+  }
+}
+
+impl Write for Counted {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let n = try!(self.inner.write(buf));
+    self.metrics.sent(n);
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+
+impl<Id: fmt::Display, S: Read+Write+Send, ST:Store+Send+'static> Session<Id, S, ST> {
+  pub fn new<W: Write + Send + 'static>(id: Id, address: &str, writer: W, store: ST, next: Option<DownStream<S>>, dispatcher: Dispatcher,
+      peers: Peers, metrics: Metrics) -> Session<Id, S, ST> {
+    metrics.session_opened();
+    let subscriptions = Arc::new(Mutex::new(HashMap::new()));
+    let registration = peers.join(&id.to_string(), &subscriptions);
+    Session {
+    	id: id,
+	address: address.to_string(),
+	input: Decoder::new(),
+	writer: Arc::new(Mutex::new(WireProtocol::new(Counted { inner: Box::new(writer), metrics: metrics.clone() }))),
+	store: store,
+	next: next,
+	dispatcher: dispatcher,
+	peers: peers,
+	upstream: None,
+	subscriptions: subscriptions,
+	registration: registration,
+	metrics: metrics,
+    }
+  }
+
+  pub fn feed(&mut self, data: &[u8]) -> Result<(), ServerError> {
+    self.metrics.received(data.len());
+    self.input.feed(data);
+    while let Some(msg) = try!(self.input.next::<Request>()) {
+      try!(self.process_one(msg));
+    }
+    Ok(())
+  }
+
+  /// Ends the session's subscriptions, once the client has gone.
+  pub fn close(&mut self) {
+    let mut subscriptions = self.subscriptions.lock().unwrap();
+    self.metrics.subscriptions_changed(-(subscriptions.len() as isize));
+    for (_, subscribed) in subscriptions.drain() {
+      subscribed.credit.close();
+    }
+  }
+
+  /// Ends the session's subscriptions, and lets the client know that there
+  /// won't be any more deliveries.
+  pub fn end_subscriptions(&mut self) -> Result<(), ServerError> {
+    let ended : Vec<SubscriptionId> = {
+      let mut subscriptions = self.subscriptions.lock().unwrap();
+      self.metrics.subscriptions_changed(-(subscriptions.len() as isize));
+      subscriptions.drain()
+        .filter(|&(_, ref subscribed)| subscribed.credit.close())
+        .map(|(id, _)| id)
+        .collect()
+    };
+    for id in ended {
+      debug!("{}: ending subscription {}", self.id, id);
+      try!(self.send(&Response::EndOfStream(id)));
+    }
+    Ok(())
+  }
+
+  fn process_one(&mut self, msg: Request) -> Result<(), ServerError> {
+    trace!("{}: Handle message: {:x}", self.id, ptr_addr(&msg));
+
+    // The client has given up on it, so there's no point starting.
+    if msg.deadline.map(|deadline| deadline <= yak_client::millis_since_epoch()).unwrap_or(false) {
+      debug!("{}/{:?}: abandoning request {} past its deadline", self.id, msg.space, msg.sequence);
+      return self.send(&Response::TimedOut(msg.sequence))
+    }
+
+    let operation = operation_name(&msg.operation);
+    self.metrics.request(operation);
+    if let Some(resp) = try!(self.tail_only(&msg)) {
+      return self.send(&resp)
+    }
+    let started = Instant::now();
+    let resp = match msg.operation {
+        // Duplicates are still passed on, in case we applied the original
+        // but failed before the rest of the chain did.
+        Operation::Write { ref key, ref value, expected: None, ref producer } => {
+          let resp = try!(self.write(msg.sequence, &msg.space, &key, &value, producer));
+          Some(try!(self.send_downstream_or(&msg, resp)))
+        },
+        Operation::Write { ref key, ref value, expected: Some(ref expected), .. } =>
+          Some(try!(self.write_if(&msg, &key, &value, expected))),
+        Operation::Read { key } =>
+          Some(try!(self.read(msg.sequence, &msg.space, &key))),
+      Operation::Subscribe { .. } => {
+        try!(self.subscribe(&msg));
+        None
+      },
+      Operation::ConfigureQueue(ref config) => {
+        let resp = try!(self.configure_queue(msg.sequence, &msg.space, config));
+        Some(try!(self.send_downstream_or(&msg, resp)))
+      },
+      Operation::Layout =>
+        Some(try!(self.layout(msg.sequence, &msg))),
+      Operation::Status =>
+        Some(try!(self.status(msg.sequence))),
+      Operation::Upstream { ref address } =>
+        Some(try!(self.upstream(msg.sequence, address))),
+      // Replicated as the one request, so each node applies it atomically.
+      Operation::Transaction { ref records } => {
+        let resp = try!(self.write_all(msg.sequence, records));
+        Some(try!(self.send_downstream_or(&msg, resp)))
+      },
+      Operation::Ack { offset, ref consumer } => {
+        try!(self.ack(&msg.space, offset, &self.consumer(consumer)));
+        None
+      },
+      Operation::Nack { offset, ref reason, ref consumer } => {
+        try!(self.nack(&msg.space, offset, reason, &self.consumer(consumer)));
+        None
+      },
+      Operation::Credit { subscription, amount } => {
+        try!(self.credit(subscription, amount));
+        None
+      },
+      Operation::Unsubscribe { subscription } =>
+        Some(try!(self.unsubscribe(msg.sequence, subscription))),
+    };
+
+    match operation {
+      "write" | "transaction" => self.metrics.wrote(started.elapsed()),
+      "read" => self.metrics.read(started.elapsed()),
+      _ => (),
+    }
+
+    if let Some(resp) = resp {
+      trace!("Response: {:?}", resp);
+      try!(self.send(&resp));
+    }
+    Ok(())
+  }
+
+  // Leases and dead letters are kept by the tail alone, as it's the node
+  // that hands out queued messages. So anywhere else we refuse to consume a
+  // queue, and pass reads of dead letters along to where they are. Dead
+  // letters are only ever appended by the queue itself.
+  fn tail_only(&self, msg: &Request) -> Result<Option<Response>, ServerError> {
+    let refuse = |space: &str, why: &str| Some(Response::Refused(msg.sequence, format!("{:?} {}", space, why)));
+    match msg.operation {
+      Operation::Write { .. } | Operation::Read { .. } | Operation::Subscribe { space_pattern: None, .. } => (),
+      Operation::Transaction { ref records } => {
+        for r in records {
+          if try_box!(self.store.is_dead_letter_space(&r.space)) {
+            return Ok(refuse(&r.space, "is a dead-letter space"))
+          }
+        }
+        return Ok(None)
+      },
+      _ => return Ok(None),
+    }
+
+    let dead_letters = try_box!(self.store.is_dead_letter_space(&msg.space));
+    match (&msg.operation, &self.next) {
+      (&Operation::Write { .. }, _) if dead_letters => Ok(refuse(&msg.space, "is a dead-letter space")),
+      (_, &None) => Ok(None),
+      (&Operation::Read { .. }, &Some(ref d)) if dead_letters => Ok(Some(try!(d.handle(msg)))),
+      (&Operation::Subscribe { .. }, _) if dead_letters => Ok(refuse(&msg.space, "is a dead-letter space, kept by the tail")),
+      (&Operation::Subscribe { catch_up: false, .. }, _) => match try_box!(self.store.queue_config(&msg.space)) {
+        Some(_) => Ok(refuse(&msg.space, "is a work queue, consumed from the tail")),
+        None => Ok(None),
+      },
+      _ => Ok(None),
+    }
+  }
+
+  fn send(&self, resp: &Response) -> Result<(), ServerError> {
+    try!(self.writer.lock().unwrap().send(resp));
+    Ok(())
+  }
+
+  // Only called once we've applied the request ourselves, after which the
+  // rest of the chain has to apply it too, however late it is: were a node
+  // further along to abandon it, we'd hold a write that the tail never saw.
+  // Requests we haven't acted on, like reads and layouts, are passed along
+  // with their deadline as is.
+  fn send_downstream_or(&self, msg: &Request, default: Response) -> Result<Response, ServerError> {
+    match self.next {
+      Some(ref d) if msg.deadline.is_some() => d.handle(&Request { deadline: None, ..msg.clone() }),
+      Some(ref d) => d.handle(msg),
+      None => Ok(default),
+    }
+  }
+
+  fn read(&self, seq: SeqNo, space: &str, key: &[u8]) -> Result<Response, ServerError> {
+    let val = try_box!(self.store.read(space, key));
+    trace!("{}/{:?}: read:{:?}: -> {:?}", self.id, space, key, val);
+    let data = val.iter().map(|c| Datum { key: Vec::new(), content: c.clone() }).collect();
+    Ok(Response::OkayData(seq, data))
+  }
+
+  fn write(&self, seq: SeqNo, space: &str, key: &[u8], val: &[u8], producer: &Option<Producer>) -> Result<Response, ServerError> {
+    trace!("{}/{:?}: write:{:?} -> {:?} by {:?}", self.id, space, key, val, producer);
+    match producer {
+      &Some(ref producer) => {
+        if !try_box!(self.store.write_from(space, key, val, producer)) {
+          debug!("{}/{:?}: ignoring duplicate write by {:?}", self.id, space, producer);
+        }
+      },
+      &None => try_box!(self.store.write(space, key, val)),
+    }
+    Ok(Response::Okay(seq))
+  }
+
+  // Only the head checks the expectation. Once it has passed, the write is
+  // replicated like any other, as the nodes downstream can't have seen
+  // anything that the head hasn't. Conditional writes never come from a
+  // producer, as a retry needs no telling apart: it simply conflicts.
+  fn write_if(&self, msg: &Request, key: &[u8], val: &[u8], expected: &Expected) -> Result<Response, ServerError> {
+    trace!("{}/{:?}: write_if:{:?} -> {:?} expecting {:?}", self.id, msg.space, key, val, expected);
+    match try_box!(self.store.write_if(&msg.space, key, val, expected)) {
+      Ok(offset) => {
+        let replica = Request { sequence: msg.sequence, space: msg.space.clone(), deadline: None,
+          operation: Operation::Write { key: key.to_vec(), value: val.to_vec(), expected: None, producer: None } };
+        try!(try!(self.send_downstream_or(&replica, Response::Okay(msg.sequence))).expect_ok());
+        Ok(Response::Written(msg.sequence, offset))
+      },
+      Err(conflict) => {
+        debug!("{}/{:?}: write conflict: {:?}", self.id, msg.space, conflict);
+        Ok(Response::Conflict(msg.sequence, conflict))
+      },
+    }
+  }
+
+  // Each node only knows the next one along, so we ask it for the rest. A
+  // client that asked us directly may have started part way down the chain,
+  // so we also point it at the node before us, if we've heard from it.
+  fn layout(&self, seq: SeqNo, msg: &Request) -> Result<Response, ServerError> {
+    let mut nodes = match (&self.upstream, self.peers.upstream()) {
+      (&None, Some(upstream)) => vec![upstream],
+      _ => vec![],
+    };
+    nodes.push(self.address.clone());
+    if let Some(ref d) = self.next {
+      let (_, rest) = try!(try!(d.handle(msg)).expect_layout());
+      nodes.extend(rest);
+    }
+    trace!("{}: layout: {:?}", self.id, nodes);
+    Ok(Response::Layout(seq, nodes))
+  }
+
+  // Everything else the node before us sends comes over this session.
+  fn upstream(&mut self, seq: SeqNo, address: &str) -> Result<Response, ServerError> {
+    debug!("{}: upstream is {:?}", self.id, address);
+    self.upstream = Some(address.to_string());
+    self.peers.set_upstream(address);
+    Ok(Response::Okay(seq))
+  }
+
+  fn status(&self, seq: SeqNo) -> Result<Response, ServerError> {
+    let status = NodeStatus {
+      address: self.address.clone(),
+      role: match (&self.next, self.peers.upstream()) {
+        (&None, _) => Role::Tail,
+        (&Some(_), None) => Role::Head,
+        (&Some(_), Some(_)) => Role::Middle,
+      },
+      downstream: self.next.as_ref().map(|d| d.status()),
+      spaces: try_box!(self.store.spaces()),
+      sessions: self.peers.sessions(),
+    };
+    trace!("{}: status: {:?}", self.id, status);
+    Ok(Response::Status(seq, status))
+  }
+
+  fn write_all(&self, seq: SeqNo, records: &[Record]) -> Result<Response, ServerError> {
+    trace!("{}: write_all:{:?}", self.id, records);
+    try_box!(self.store.write_all(records));
+    Ok(Response::Okay(seq))
+  }
+
+  fn configure_queue(&self, seq: SeqNo, space: &str, config: &QueueConfig) -> Result<Response, ServerError> {
+    trace!("{}/{:?}: configure_queue:{:?}", self.id, space, config);
+    try_box!(self.store.configure_queue(space, config));
+    Ok(Response::Okay(seq))
+  }
+
+  // Deliveries are sent by the dispatcher, so that the session can carry on
+  // handling credit, acks and other requests meanwhile.
+  //
+  // In work-queue mode leases are taken in the name the client gives, so
+  // that acks can be matched up with them even once the client has had to
+  // reconnect; older clients make do with the session's id. Pattern and
+  // catch-up subscriptions never lease; they just observe. Only plain
+  // single-space subscriptions can start part way through the log.
+  fn subscribe(&mut self, msg: &Request) -> Result<(), ServerError> {
+    let (seq, space) = (msg.sequence, &msg.space);
+    let (filter, space_pattern, catch_up, start, consumer) = match msg.operation {
+      Operation::Subscribe { ref filter, ref space_pattern, catch_up, start, ref consumer } =>
+        (filter.clone(), space_pattern.clone(), catch_up, start, self.consumer(consumer)),
+      ref op => return Err(ServerError::UnexpectedOperation(op.clone())),
+    };
+    // Only this subscription's business, not the rest of the session's.
+    if self.subscriptions.lock().unwrap().contains_key(&seq) {
+      return self.send(&Response::Refused(seq, "subscription id already in use".to_string()))
+    }
+    let queue = space_pattern.is_none() && !catch_up && try_box!(self.store.queue_config(space)).is_some();
+
+    let space = space.to_string();
+    let subscribed_to = space_pattern.clone().unwrap_or_else(|| space.clone());
+    let (source, watch) : (Source, Watch) = match space_pattern {
+      Some(pattern) => {
+        let mut iter = if catch_up {
+          try_box!(self.store.catch_up_pattern(&pattern, &filter))
+        } else {
+          try_box!(self.store.subscribe_pattern(&pattern, &filter))
+        };
+        let source : Source = Box::new(move || iter.poll_next().map(|p| p.map(|(space, offset, d)| tag(&space, offset, d))));
+        (source, Watch::Pattern(pattern))
+      },
+      None => {
+        let mut iter : Box<Waiting<Item=(Offset, Datum)>> = if queue {
+          Box::new(try_box!(self.store.consume(&space, &consumer, &filter)))
+        } else if catch_up {
+          Box::new(try_box!(self.store.catch_up(&space, &filter, start)))
+        } else {
+          Box::new(try_box!(self.store.subscribe(&space, &filter, start)))
+        };
+        let watch = if queue { Watch::Leases(space.clone()) } else { Watch::Space(space.clone()) };
+        let source : Source = Box::new(move || iter.poll_next().map(|p| p.map(|(offset, d)| tag(&space, offset, d))));
+        (source, watch)
+      },
+    };
+    // Once we know we can follow the space, but before anything from it.
+    try!(self.send(&Response::Okay(seq)));
+
+    let credit = Credit::new();
+    let writer = self.writer.clone();
+    let sink_credit = credit.clone();
+    let sink : Sink = Box::new(move |resp| {
+      let mut writer = writer.lock().unwrap();
+      // The end of stream marker is sent by whoever closes the credit, so
+      // we must not send anything after it.
+      match resp {
+        Response::EndOfStream(_) => (),
+        _ if sink_credit.is_closed() => return Ok(()),
+        _ => (),
+      }
+      writer.send(&resp)
+    });
+    self.dispatcher.register(seq, source, sink, credit.clone(), watch);
+    self.subscriptions.lock().unwrap().insert(seq, Subscribed { space: subscribed_to, credit: credit });
+    self.metrics.subscriptions_changed(1);
+    Ok(())
+  }
+
+  fn credit(&self, subscription: SubscriptionId, amount: u64) -> Result<(), ServerError> {
+    let credit = self.subscriptions.lock().unwrap().get(&subscription).map(|subscribed| subscribed.credit.clone());
+    match credit {
+      Some(credit) => {
+        credit.grant(amount);
+        Ok(())
+      },
+      None => {
+        // The subscription may have ended while the credit was in flight.
+        debug!("{}: credit for unknown subscription {}", self.id, subscription);
+        Ok(())
+      },
+    }
+  }
+
+  fn unsubscribe(&mut self, seq: SeqNo, subscription: SubscriptionId) -> Result<Response, ServerError> {
+    let removed = self.subscriptions.lock().unwrap().remove(&subscription);
+    if let Some(subscribed) = removed {
+      debug!("{}: unsubscribe {}", self.id, subscription);
+      self.metrics.subscriptions_changed(-1);
+      if subscribed.credit.close() {
+        try!(self.send(&Response::EndOfStream(subscription)));
+      }
+    }
+    Ok(Response::Okay(seq))
+  }
+
+  fn consumer(&self, named: &Option<String>) -> String {
+    named.clone().unwrap_or_else(|| self.id.to_string())
+  }
+
+  fn ack(&self, space: &str, offset: Offset, consumer: &str) -> Result<(), ServerError> {
+    // Acks for a space that isn't a work queue have nothing to release.
+    if !try_box!(self.store.ack(space, consumer, offset)) && try_box!(self.store.queue_config(space)).is_some() {
+      warn!("{}/{:?}: ack by {:?} for expired lease @{}", self.id, space, consumer, offset);
+    }
+    Ok(())
+  }
+
+  fn nack(&self, space: &str, offset: Offset, reason: &str, consumer: &str) -> Result<(), ServerError> {
+    if !try_box!(self.store.nack(space, consumer, offset, reason)) {
+      warn!("{}/{:?}: nack by {:?} for expired lease @{}", self.id, space, consumer, offset);
+    }
+    Ok(())
+  }
+}
+
+impl<Id, S: Read+Write+'static, ST> Drop for Session<Id, S, ST> {
+  fn drop(&mut self) {
+    if let Some(ref address) = self.upstream {
+      self.peers.forget_upstream(address);
+    }
+    self.peers.leave(self.registration);
+    self.metrics.session_closed();
+  }
+}
+
+fn operation_name(operation: &Operation) -> &'static str {
+  match operation {
+    &Operation::Read { .. } => "read",
+    &Operation::Write { .. } => "write",
+    &Operation::Subscribe { .. } => "subscribe",
+    &Operation::Ack { .. } => "ack",
+    &Operation::Nack { .. } => "nack",
+    &Operation::Credit { .. } => "credit",
+    &Operation::Unsubscribe { .. } => "unsubscribe",
+    &Operation::ConfigureQueue(_) => "configure_queue",
+    &Operation::Transaction { .. } => "transaction",
+    &Operation::Layout => "layout",
+    &Operation::Status => "status",
+    &Operation::Upstream { .. } => "upstream",
+  }
+}
+
+fn tag(space: &str, offset: Offset, d: Datum) -> Delivery {
+  Delivery { space: space.to_string(), offset: offset, key: d.key, content: d.content }
+}
+
+impl From<capnp::Error> for ServerError {
+  fn from(err: capnp::Error) -> ServerError {
+    ServerError::CapnpError(err)
+  }
+}
+
+impl From<capnp::NotInSchema> for ServerError {
+  fn from(err: capnp::NotInSchema) -> ServerError {
+    ServerError::CapnpNotInSchema(err)
+  }
+}
+
+impl From<io::Error> for ServerError {
+  fn from(err: io::Error) -> ServerError {
+    ServerError::IoError(err)
+  }
+}
+
+impl From<YakError> for ServerError {
+  fn from(err: YakError) -> ServerError {
+    ServerError::DownstreamError(err)
+  }
+}
+
+impl From<Box<Error + Send>> for ServerError {
+  fn from(err: Box<Error + Send>) -> ServerError {
+    ServerError::StoreError(err)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{Session, DownStream, Peers};
+  use std::env;
+  use std::fs;
+  use std::net::{TcpListener, TcpStream};
+  use std::time::{Duration, Instant};
+  use rand::{self, Rng};
+  use yak_client::{self, WireProtocol, Request, Response, Operation, Role, DownstreamStatus, SpaceStatus, SessionStatus, SubscriptionStatus};
+  use yak_client::{Expected, Producer};
+  use yak_client::{KeyFilter, QueueConfig, Client, RetryPolicy};
+  use dispatch::Dispatcher;
+  use metrics::Metrics;
+  use sqlite_store::SqliteStore;
+  use store::Store;
+  use testing::Chain;
+  use testing::faults::{Fault, Faults, FaultyStream};
+
+  const SPACE : &'static str = "faults";
+  // Long enough for anything that's coming to arrive on localhost.
+  const READ_TIMEOUT_MS : u64 = 500;
+
+  type Stream = FaultyStream<TcpStream>;
+
+  fn write(seq: u64) -> Request {
+    Request { sequence: seq, space: SPACE.to_string(), deadline: None,
+      operation: Operation::Write { key: b"key".to_vec(), value: b"value".to_vec(), expected: None, producer: None } }
+  }
+
+  fn read(seq: u64) -> Request {
+    Request { sequence: seq, space: SPACE.to_string(), deadline: None, operation: Operation::Read { key: b"key".to_vec() } }
+  }
+
+  fn status(seq: u64) -> Request {
+    Request { sequence: seq, space: SPACE.to_string(), deadline: None, operation: Operation::Status }
+  }
+
+  fn connected_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT_MS))).unwrap();
+    (client, server)
+  }
+
+  fn downstream_to(chain: &Chain, fault: Option<Fault>) -> DownStream<Stream> {
+    let stream = TcpStream::connect(chain.head().local_addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT_MS))).unwrap();
+    let faults = Faults::new();
+    if let Some(fault) = fault {
+      faults.set(fault);
+    }
+    DownStream::over(&chain.head().local_addr().to_string(), WireProtocol::new(FaultyStream::new(stream, faults)), Metrics::new())
+  }
+
+  fn new_store() -> SqliteStore {
+    let dir = env::temp_dir().join(format!("yak-session-{}", rand::thread_rng().gen_ascii_chars().take(12).collect::<String>()));
+    fs::create_dir_all(&dir).unwrap();
+    SqliteStore::new(&dir).unwrap()
+  }
+
+  // A session whose responses go through `faults` to the client we return.
+  fn session(faults: &Faults, next: Option<DownStream<Stream>>)
+      -> (Session<&'static str, Stream, SqliteStore>, WireProtocol<TcpStream>) {
+    session_over("test", new_store(), faults, next)
+  }
+
+  fn session_over(id: &'static str, store: SqliteStore, faults: &Faults, next: Option<DownStream<Stream>>)
+      -> (Session<&'static str, Stream, SqliteStore>, WireProtocol<TcpStream>) {
+    let dispatcher = Dispatcher::new(1, store.watch()).unwrap();
+    let (client, server) = connected_pair();
+    let session = Session::new(id, "127.0.0.1:0", FaultyStream::new(server, faults.clone()), store, next, dispatcher,
+      Peers::new(), Metrics::new());
+    (session, WireProtocol::new(client))
+  }
+
+  fn feed(session: &mut Session<&'static str, Stream, SqliteStore>, req: &Request) -> bool {
+    session.feed(&yak_client::encode_packed(req).unwrap()).is_ok()
+  }
+
+  #[test]
+  fn downstream_forwards_over_a_healthy_link() {
+    let chain = Chain::start(1).unwrap();
+    let downstream = downstream_to(&chain, None);
+    assert_eq!(downstream.handle(&write(1)).unwrap().expect_ok().ok(), Some(1));
+  }
+
+  #[test]
+  fn downstream_waits_out_delays() {
+    let chain = Chain::start(1).unwrap();
+    let downstream = downstream_to(&chain, Some(Fault::Delay(Duration::from_millis(50))));
+    let started = Instant::now();
+    assert_eq!(downstream.handle(&write(1)).unwrap().expect_ok().ok(), Some(1));
+    assert!(started.elapsed() >= Duration::from_millis(50));
+  }
+
+  #[test]
+  fn downstream_gives_up_on_dropped_requests() {
+    let chain = Chain::start(1).unwrap();
+    let downstream = downstream_to(&chain, Some(Fault::Drop));
+    assert!(downstream.handle(&write(1)).is_err());
+  }
+
+  #[test]
+  fn downstream_fails_when_truncated() {
+    let chain = Chain::start(1).unwrap();
+    let downstream = downstream_to(&chain, Some(Fault::Truncate(4)));
+    assert!(downstream.handle(&write(1)).is_err());
+  }
+
+  #[test]
+  fn downstream_gets_no_answer_to_corrupted_requests() {
+    let chain = Chain::start(1).unwrap();
+    let downstream = downstream_to(&chain, Some(Fault::Corrupt));
+    assert!(downstream.handle(&write(1)).is_err());
+  }
+
+  #[test]
+  fn downstream_fails_when_partitioned() {
+    let chain = Chain::start(1).unwrap();
+    let downstream = downstream_to(&chain, Some(Fault::Partition));
+    assert!(downstream.handle(&write(1)).is_err());
+  }
+
+  #[test]
+  fn nodes_report_where_they_are_in_the_chain() {
+    let chain = Chain::start(3).unwrap();
+    let roles : Vec<Role> = chain.nodes().iter()
+      .map(|node| Client::connect(&node.url(SPACE)).unwrap().status().unwrap().role)
+      .collect();
+    assert_eq!(roles, vec![Role::Head, Role::Middle, Role::Tail]);
+  }
+
+  #[test]
+  fn clients_see_a_partitioned_chain_fail_until_it_heals() {
+    let chain = Chain::start_faulty(2).unwrap();
+    let connect = |url: &str| {
+      let mut client = Client::connect(url).unwrap();
+      client.set_retry_policy(RetryPolicy::never());
+      client
+    };
+    connect(&chain.head_url(SPACE)).write(b"key", b"before").unwrap();
+
+    chain.link(0).set(Fault::Partition);
+    match connect(&chain.head_url(SPACE)).write(b"key", b"during") {
+      Err(ref e) if e.is_connection_error() => (),
+      other => panic!("Expected the head to hang up, got {:?}", other),
+    }
+    // The tail can still be read from, but never heard about the write.
+    let seen = |url: &str| -> Vec<Vec<u8>> {
+      connect(url).read(b"key").unwrap().into_iter().map(|datum| datum.content).collect()
+    };
+    assert_eq!(seen(&chain.tail_url(SPACE)), vec![b"before".to_vec()]);
+
+    chain.link(0).clear();
+    connect(&chain.head_url(SPACE)).write(b"key", b"after").unwrap();
+    assert_eq!(seen(&chain.tail_url(SPACE)), vec![b"before".to_vec(), b"after".to_vec()]);
+  }
+
+  #[test]
+  fn session_answers_over_a_healthy_link() {
+    let faults = Faults::new();
+    let (mut session, mut client) = session(&faults, None);
+    assert!(feed(&mut session, &write(1)));
+    assert_eq!(client.read::<Response>().unwrap().unwrap().expect_ok().ok(), Some(1));
+  }
+
+  #[test]
+  fn session_answers_late_when_delayed() {
+    let faults = Faults::new();
+    faults.set(Fault::Delay(Duration::from_millis(50)));
+    let (mut session, mut client) = session(&faults, None);
+    let started = Instant::now();
+    assert!(feed(&mut session, &write(1)));
+    assert_eq!(client.read::<Response>().unwrap().unwrap().expect_ok().ok(), Some(1));
+    assert!(started.elapsed() >= Duration::from_millis(50));
+  }
+
+  #[test]
+  fn session_still_writes_when_the_response_is_dropped() {
+    let faults = Faults::new();
+    faults.set(Fault::Drop);
+    let (mut session, mut client) = session(&faults, None);
+    assert!(feed(&mut session, &write(1)));
+    faults.clear();
+    assert!(feed(&mut session, &read(2)));
+    match client.read::<Response>().unwrap() {
+      Some(Response::OkayData(2, ref data)) => assert_eq!(data.len(), 1),
+      other => panic!("Expected the read's response, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn session_fails_when_its_response_is_truncated() {
+    let faults = Faults::new();
+    faults.set(Fault::Truncate(3));
+    let (mut session, mut client) = session(&faults, None);
+    assert!(!feed(&mut session, &write(1)));
+    assert!(client.read::<Response>().is_err());
+  }
+
+  #[test]
+  fn session_response_is_not_understood_when_corrupted() {
+    let faults = Faults::new();
+    faults.set(Fault::Corrupt);
+    let (mut session, mut client) = session(&faults, None);
+    assert!(feed(&mut session, &write(1)));
+    match client.read::<Response>() {
+      Ok(Some(Response::Okay(1))) => panic!("Corrupted response read as intact"),
+      _ => (),
+    }
+  }
+
+  #[test]
+  fn session_fails_when_the_client_is_partitioned() {
+    let faults = Faults::new();
+    faults.set(Fault::Partition);
+    let (mut session, _client) = session(&faults, None);
+    assert!(!feed(&mut session, &write(1)));
+  }
+
+  #[test]
+  fn session_does_not_acknowledge_writes_the_next_node_never_saw() {
+    let chain = Chain::start(1).unwrap();
+    let next = downstream_to(&chain, Some(Fault::Partition));
+    let (mut session, mut client) = session(&Faults::new(), Some(next));
+    assert!(!feed(&mut session, &write(1)));
+    assert!(client.read::<Response>().is_err());
+  }
+
+  #[test]
+  fn session_refuses_a_conditional_write_from_a_producer() {
+    let chain = Chain::start(1).unwrap();
+    let next = downstream_to(&chain, None);
+    let (mut session, _client) = session(&Faults::new(), Some(next));
+    let conditional = Request { sequence: 1, space: SPACE.to_string(), deadline: None,
+      operation: Operation::Write { key: b"key".to_vec(), value: b"value".to_vec(), expected: Some(Expected::Space(None)),
+        producer: Some(Producer { id: 7, sequence: 0 }) } };
+    assert!(!feed(&mut session, &conditional));
+
+    // Nothing was written, here or downstream.
+    let downstream = downstream_to(&chain, None);
+    let (_, values) = downstream.handle(&read(2)).unwrap().expect_datum_list().unwrap();
+    assert!(values.is_empty());
+  }
+
+  #[test]
+  fn session_takes_acks_for_leases_its_consumer_took_over_another() {
+    let store = new_store();
+    let consumer = Some("consumer".to_string());
+    let (mut first, mut client) = session_over("first", store.clone(), &Faults::new(), None);
+    let config = QueueConfig { visibility_timeout_ms: 60000, max_deliveries: 3, dead_letter_space: None };
+    assert!(feed(&mut first, &Request { sequence: 1, space: SPACE.to_string(), deadline: None, operation: Operation::ConfigureQueue(config) }));
+    assert!(feed(&mut first, &write(2)));
+    assert!(feed(&mut first, &Request { sequence: 3, space: SPACE.to_string(), deadline: None,
+      operation: Operation::Subscribe { filter: KeyFilter::All, space_pattern: None, catch_up: false, start: 0, consumer: consumer.clone() } }));
+    assert!(feed(&mut first, &Request { sequence: 4, space: SPACE.to_string(), deadline: None,
+      operation: Operation::Credit { subscription: 3, amount: 1 } }));
+    let responses : Vec<Response> = (0..4).map(|_| client.read::<Response>().unwrap().unwrap()).collect();
+    assert_eq!(responses[3].expect_delivery().unwrap().offset, 0);
+    first.close();
+    drop(first);
+
+    // As if the client had lost its connection, and come back.
+    let (mut second, _client) = session_over("second", store.clone(), &Faults::new(), None);
+    assert!(feed(&mut second, &Request { sequence: 1, space: SPACE.to_string(), deadline: None,
+      operation: Operation::Ack { offset: 0, consumer: consumer.clone() } }));
+    assert!(!store.ack(SPACE, "consumer", 0).unwrap());
+  }
+
+  #[test]
+  fn session_refuses_a_subscription_id_in_use_and_carries_on() {
+    let (mut session, mut client) = session(&Faults::new(), None);
+    let subscribe = Request { sequence: 1, space: SPACE.to_string(), deadline: None,
+      operation: Operation::Subscribe { filter: KeyFilter::All, space_pattern: None, catch_up: false, start: 0, consumer: None } };
+    assert!(feed(&mut session, &subscribe));
+    assert!(feed(&mut session, &subscribe));
+    assert!(feed(&mut session, &status(2)));
+    client.read::<Response>().unwrap().unwrap().expect_ok().unwrap();
+    match client.read::<Response>().unwrap().unwrap() {
+      Response::Refused(1, _) => (),
+      other => panic!("Expected the second subscription to be refused, got {:?}", other),
+    }
+    let (_, status) = client.read::<Response>().unwrap().unwrap().expect_status().unwrap();
+    assert_eq!(status.sessions[0].subscriptions, vec![SubscriptionStatus { id: 1, space: SPACE.to_string() }]);
+  }
+
+  #[test]
+  fn session_reports_what_it_holds_and_a_failed_downstream() {
+    let chain = Chain::start(1).unwrap();
+    let next = downstream_to(&chain, Some(Fault::Partition));
+    let (mut session, mut client) = session(&Faults::new(), Some(next));
+    assert!(!feed(&mut session, &write(1)));
+    assert!(feed(&mut session, &status(2)));
+    let (seq, status) = client.read::<Response>().unwrap().unwrap().expect_status().unwrap();
+    assert_eq!(seq, 2);
+    assert_eq!(status.role, Role::Head);
+    assert_eq!(status.downstream, Some(DownstreamStatus { address: chain.head().local_addr().to_string(), healthy: Some(false) }));
+    assert_eq!(status.spaces, vec![SpaceStatus { name: SPACE.to_string(), first: 0, last: 0, bytes: 8 }]);
+    assert_eq!(status.sessions, vec![SessionStatus { peer: "test".to_string(), subscriptions: vec![] }]);
+  }
+
+  #[test]
+  fn session_reports_its_subscriptions_and_a_downstream_not_yet_heard_from() {
+    let chain = Chain::start(1).unwrap();
+    let next = downstream_to(&chain, None);
+    let (mut session, mut client) = session(&Faults::new(), Some(next));
+    assert!(feed(&mut session, &Request { sequence: 1, space: SPACE.to_string(), deadline: None,
+      operation: Operation::Subscribe { filter: KeyFilter::All, space_pattern: None, catch_up: false, start: 0, consumer: None } }));
+    assert!(feed(&mut session, &status(2)));
+    client.read::<Response>().unwrap().unwrap().expect_ok().unwrap();
+    let (_, status) = client.read::<Response>().unwrap().unwrap().expect_status().unwrap();
+    assert_eq!(status.downstream, Some(DownstreamStatus { address: chain.head().local_addr().to_string(), healthy: None }));
+    assert_eq!(status.sessions, vec![SessionStatus { peer: "test".to_string(),
+      subscriptions: vec![SubscriptionStatus { id: 1, space: SPACE.to_string() }] }]);
+  }
+}
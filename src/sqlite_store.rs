@@ -1,25 +1,45 @@
 use std::path::{Path,PathBuf};
 use std::fmt;
 use std::thread;
-use store::{Store, Values};
+use store::{Store, Waiting, Polled, Values};
 use std::sync::{Arc,Mutex, Condvar};
+use std::sync::mpsc::{channel, Sender, Receiver};
 use std::error::Error;
 use std::io::{self, Write};
-use yak_client::Datum;
+use std::time::{SystemTime, UNIX_EPOCH};
+use config::StoreConfig;
+use metrics::Metrics;
+use yak_client::{Datum, Offset, QueueConfig, DeadLetter, KeyFilter, Expected, Conflict, Record, Producer, SpaceStatus};
 use rusqlite;
+use rusqlite::types::ToSql;
 extern crate r2d2;
 extern crate r2d2_sqlite;
 
+type ConnectionPool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 type DatabaseConnection = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
 
-type SeqCVar = Arc<(Mutex<i64>, Condvar)>;
+// Counts the writes made to the store, so that subscribers can wait for
+// new data to arrive, and tells anyone watching which spaces they went to.
+struct Notifier {
+  writes: Mutex<i64>,
+  cvar: Condvar,
+  watchers: Mutex<Vec<Sender<String>>>,
+}
+
+type SeqCVar = Arc<Notifier>;
 
 #[derive(Clone)]
 pub struct SqliteStore {
-  pool:  r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>,
-  seqnotify: SeqCVar
+  pool:  ConnectionPool,
+  seqnotify: SeqCVar,
+  metrics: Metrics,
+  clock: Clock,
 }
 
+/// Tells the time that leases are taken and expire by, in milliseconds
+/// since the epoch.
+pub type Clock = Arc<Fn() -> i64 + Send + Sync>;
+
 #[automatically_derived]
 impl fmt::Debug for SqliteStore {
   fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
@@ -27,18 +47,47 @@ impl fmt::Debug for SqliteStore {
   }
 }
 
+// Iterators take a connection from the pool only while they look for the
+// next record, so idle subscriptions don't tie connections up.
 struct SqliteIterator{ 
-  db: DatabaseConnection,
+  pool: ConnectionPool,
   space: String,
+  filter: KeyFilter,
   next_idx: i64,
+  end: Option<i64>,
+  idle: IdleHook,
   seqnotify: SeqCVar
 }
 
+struct SqlitePatternIterator {
+  pool: ConnectionPool,
+  pattern: String,
+  filter: KeyFilter,
+  next_rowid: i64,
+  end: Option<i64>,
+  idle: IdleHook,
+  seqnotify: SeqCVar
+}
+
+struct SqliteLeaseIterator {
+  pool: ConnectionPool,
+  space: String,
+  consumer: String,
+  filter: KeyFilter,
+  config: QueueConfig,
+  idle: IdleHook,
+  seqnotify: SeqCVar,
+  metrics: Metrics,
+  clock: Clock,
+}
+
 #[derive(Debug)]
 pub enum SqliteError {
   SqliteError(rusqlite::SqliteError),
   PoolError(r2d2::GetTimeout),
   IoError(io::Error),
+  /// Asked to lease messages from a space that isn't a work queue.
+  NotAQueue(String),
 }
 
 impl fmt::Display for SqliteError {
@@ -47,6 +96,7 @@ impl fmt::Display for SqliteError {
       &SqliteError::SqliteError(ref err) => write!(fmt, "Store error:{}", err),
       &SqliteError::PoolError(ref err) => write!(fmt, "Pool error:{}", err),
       &SqliteError::IoError(ref err) => write!(fmt, "IO error:{}", err),
+      &SqliteError::NotAQueue(ref space) => write!(fmt, "{:?} is not a work queue", space),
     }
   }
 }
@@ -57,18 +107,203 @@ impl Error for SqliteError {
       &SqliteError::SqliteError(ref mdb) => mdb.description(),
       &SqliteError::PoolError(ref err) => err.description(),
       &SqliteError::IoError(ref err) => err.description(),
+      &SqliteError::NotAQueue(_) => "not a work queue",
+    }
+  }
+}
+
+const NO_WRITES_INIT : i64 = 0;
+const MAX_LEASE_WAIT_MS : u32 = 1000;
+const MAX_WRITE_WAIT_MS : u32 = 1000;
+
+fn now_ms() -> i64 {
+  let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+  (now.as_secs() * 1000 + (now.subsec_nanos() / 1000000) as u64) as i64
+}
+
+fn append(db: &rusqlite::SqliteConnection, space: &str, key: &[u8], val: &[u8]) -> Result<i64, SqliteError> {
+  let sql = "SELECT seq+1 FROM logs WHERE space = ? ORDER BY seq DESC LIMIT 1";
+  trace!("{}@[{:?}]", sql, space);
+  let mut stmt = try!(db.prepare(sql));
+  let idxo = try!(stmt.query_map(&[&space], |r| r.get(0))).next();
+  let idx = try!(idxo.unwrap_or(Ok(0)));
+
+  let sql = "INSERT INTO logs (seq, space, key, value) VALUES (?, ?, ?, ?)";
+  trace!("{}@[{:?}, {:?}, {:?}, {:?}]", sql, idx, space, key, val);
+  try!(db.execute(sql, &[&idx, &space, &key, &val]));
+  Ok(idx)
+}
+
+// Calls an iterator's idle callback, if it has one, at most once an interval.
+struct IdleHook {
+  interval_ms: i64,
+  last: i64,
+  idle: Option<Box<FnMut() -> bool + Send>>,
+}
+
+impl IdleHook {
+  fn none() -> IdleHook {
+    IdleHook { interval_ms: 0, last: 0, idle: None }
+  }
+
+  fn new(interval_ms: u64, idle: Box<FnMut() -> bool + Send>) -> IdleHook {
+    IdleHook { interval_ms: interval_ms as i64, last: now_ms(), idle: Some(idle) }
+  }
+
+  // How long to wait for writes before checking in with the callback.
+  fn wait_ms(&self) -> u32 {
+    match self.idle {
+      Some(_) => ::std::cmp::min(::std::cmp::max(self.interval_ms, 1), MAX_WRITE_WAIT_MS as i64) as u32,
+      None => MAX_WRITE_WAIT_MS,
+    }
+  }
+
+  // Returns false once the callback asks us to stop waiting.
+  fn tick(&mut self) -> bool {
+    let now = now_ms();
+    if now - self.last < self.interval_ms {
+      return true
+    }
+    match self.idle {
+      Some(ref mut idle) => {
+        self.last = now;
+        (&mut **idle)()
+      },
+      None => true,
+    }
+  }
+}
+
+// Waits until there have been writes since we saw `seen` of them. We don't
+// hold the lock while the idle hook runs, as it may well block.
+fn await_writes(seqnotify: &SeqCVar, seen: i64, idle: &mut IdleHook) -> bool {
+  loop {
+    {
+      let writes = seqnotify.writes.lock().unwrap();
+      if *writes != seen {
+        return true
+      }
+      trace!("Wait! writes seen:{:?}", seen);
+      let (writes, _no_timeout) = seqnotify.cvar.wait_timeout_ms(writes, idle.wait_ms()).unwrap();
+      trace!("Awoken! writes:{:?}; timeout? {:?}", &*writes, _no_timeout);
+      if *writes != seen {
+        return true
+      }
+    }
+    if !idle.tick() {
+      return false
     }
   }
 }
 
-const EMPTY_SEQ_INIT : i64 = -1;
+fn notify_write(seqnotify: &SeqCVar, space: &str) {
+  debug!("Notify of write to: {:?}", space);
+  {
+    let mut writes = seqnotify.writes.lock().unwrap();
+    debug!("Obtained lock! writes: {}", &*writes);
+    *writes += 1;
+    seqnotify.cvar.notify_all();
+  }
+  // Watchers that have gone away are forgotten.
+  seqnotify.watchers.lock().unwrap().retain(|watcher| watcher.send(space.to_string()).is_ok());
+}
+
+fn writes_seen(seqnotify: &SeqCVar) -> i64 {
+  let writes = seqnotify.writes.lock().unwrap();
+  *writes
+}
+
+// Renders `filter` as an SQL condition on `column`, with its parameters
+// numbered from `first_param`.
+fn filter_sql(column: &str, filter: &KeyFilter, first_param: usize) -> (String, Vec<Vec<u8>>) {
+  match filter {
+    &KeyFilter::All => ("1".to_string(), vec![]),
+    &KeyFilter::Exact(ref key) =>
+      (format!("{} = ?{}", column, first_param), vec![key.clone()]),
+    &KeyFilter::Prefix(ref prefix) =>
+      (format!("substr({0}, 1, length(?{1})) = ?{1}", column, first_param), vec![prefix.clone()]),
+    &KeyFilter::Range(ref start, ref end) =>
+      (format!("{0} >= ?{1} AND {0} < ?{2}", column, first_param, first_param + 1), vec![start.clone(), end.clone()]),
+  }
+}
+
+// Trims each space to its last `max_records` records as new ones are
+// appended. Doing this with a trigger means that nothing that appends to the
+// log can forget to.
+fn set_retention(db: &rusqlite::SqliteConnection, max_records: Option<u64>) -> Result<(), SqliteError> {
+  try!(db.execute("DROP TRIGGER IF EXISTS retention", &[]));
+  if let Some(max_records) = max_records {
+    debug!("Keeping the last {} records of each space", max_records);
+    try!(db.execute(&format!("CREATE TRIGGER retention AFTER INSERT ON logs BEGIN
+                 DELETE FROM logs WHERE space = NEW.space AND seq <= NEW.seq - {0};
+                 DELETE FROM leases WHERE space = NEW.space AND seq <= NEW.seq - {0};
+               END", max_records), &[]));
+  }
+  Ok(())
+}
+
+// Settles the lease on a message, and appends it to the queue's
+// dead-letter space, if there is one. Returns the index of the dead letter,
+// for the caller to count in the metrics once it has committed.
+fn dead_letter(db: &rusqlite::SqliteConnection, space: &str, seq: i64, deliveries: i64, reason: &str,
+    config: &QueueConfig) -> Result<Option<i64>, SqliteError> {
+  let sql = "UPDATE leases SET acked = 1 WHERE space = ? AND seq = ?";
+  trace!("{}@[{:?}, {:?}]", sql, space, seq);
+  try!(db.execute(sql, &[&space, &seq]));
+
+  let dead_letter_space = match config.dead_letter_space {
+    Some(ref dead_letter_space) => dead_letter_space,
+    None => {
+      warn!("Dropping {:?}@{:?} with no dead-letter space: {}", space, seq, reason);
+      return Ok(None)
+    },
+  };
+
+  let sql = "SELECT key, value FROM logs WHERE space = ? AND seq = ?";
+  trace!("{}@[{:?}, {:?}]", sql, space, seq);
+  let mut stmt = try!(db.prepare(sql));
+  let row = try!(stmt.query_map(&[&space, &seq], |r| (r.get::<Vec<u8>>(0), r.get::<Vec<u8>>(1)))).next();
+  let (key, content) = match row {
+    Some(row) => try!(row),
+    None => return Ok(None),
+  };
+
+  let letter = DeadLetter {
+    space: space.to_string(),
+    offset: seq as Offset,
+    deliveries: deliveries as u32,
+    reason: reason.to_string(),
+    content: content,
+  };
+  debug!("Dead letter: {:?} → {:?}", letter, dead_letter_space);
+  let idx = try!(append(db, dead_letter_space, &key, &letter.to_bytes()));
+  Ok(Some(idx))
+}
 
 impl SqliteStore {
   pub fn new(path: &Path) -> Result<SqliteStore, SqliteError> {
-    let mut buf = path.to_path_buf();
+    SqliteStore::open(&StoreConfig::at(path))
+  }
+
+  /// Has writes update `metrics`, rather than a set of our own.
+  pub fn with_metrics(mut self, metrics: Metrics) -> SqliteStore {
+    self.metrics = metrics;
+    self
+  }
+
+  /// Has leases go by `clock`, rather than the system clock.
+  pub fn with_clock(mut self, clock: Clock) -> SqliteStore {
+    self.clock = clock;
+    self
+  }
+
+  pub fn open(options: &StoreConfig) -> Result<SqliteStore, SqliteError> {
+    let mut buf = options.path.clone();
     buf.push("queues.sqlite");
 
     let config = r2d2::Config::builder()
+        .pool_size(options.pool_size)
+        .connection_timeout_ms(options.connection_timeout_ms)
         .error_handler(Box::new(r2d2::LoggingErrorHandler))
         .build();
     let manager = r2d2_sqlite::SqliteConnectionManager::new(&buf.to_string_lossy()).unwrap();
@@ -76,8 +311,19 @@ impl SqliteStore {
     let pool = r2d2::Pool::new(config, manager).unwrap();
 
 
-    let store = SqliteStore { pool: pool, seqnotify: Arc::new((Mutex::new(EMPTY_SEQ_INIT), Condvar::new())) };
+    let notifier = Notifier { writes: Mutex::new(NO_WRITES_INIT), cvar: Condvar::new(), watchers: Mutex::new(Vec::new()) };
+    let store = SqliteStore { pool: pool, seqnotify: Arc::new(notifier), metrics: Metrics::new(), clock: Arc::new(now_ms) };
     let mut db = try!(store.open_db());
+    // Readers and the writer then don't hold each other up, and there's a
+    // log for `flush` to checkpoint. The mode sticks to the database file,
+    // so every connection in the pool uses it.
+    {
+      let mut stmt = try!(db.prepare("PRAGMA journal_mode=WAL"));
+      let rows = try!(stmt.query_map(&[], |r| r.get::<String>(0)));
+      for mode in rows {
+        debug!("Journal mode: {}", try!(mode));
+      }
+    }
     try!(db.execute("CREATE TABLE IF NOT EXISTS logs (
                  space           VARCHAR NOT NULL,
                  seq             INT NOT NULL,
@@ -85,6 +331,28 @@ impl SqliteStore {
                  value           BLOB NOT NULL,
                  PRIMARY KEY (space, seq)
                )", &[]));
+    try!(db.execute("CREATE TABLE IF NOT EXISTS queue_configs (
+                 space                 VARCHAR NOT NULL PRIMARY KEY,
+                 visibility_timeout_ms INT NOT NULL,
+                 max_deliveries        INT NOT NULL,
+                 dead_letter_space     VARCHAR
+               )", &[]));
+    try!(db.execute("CREATE TABLE IF NOT EXISTS leases (
+                 space           VARCHAR NOT NULL,
+                 seq             INT NOT NULL,
+                 consumer        VARCHAR NOT NULL,
+                 deadline        INT NOT NULL,
+                 deliveries      INT NOT NULL,
+                 acked           INT NOT NULL,
+                 PRIMARY KEY (space, seq)
+               )", &[]));
+    try!(db.execute("CREATE TABLE IF NOT EXISTS producers (
+                 space           VARCHAR NOT NULL,
+                 producer        INT NOT NULL,
+                 sequence        INT NOT NULL,
+                 PRIMARY KEY (space, producer)
+               )", &[]));
+    try!(set_retention(&db, options.max_records));
     Ok(store)
   }
 
@@ -96,6 +364,8 @@ impl SqliteStore {
 
 impl Store for SqliteStore {
   type Iter = SqliteIterator;
+  type Leases = SqliteLeaseIterator;
+  type PatternIter = SqlitePatternIterator;
   type Error = SqliteError;
 
   fn read(&self, space: &str, key: &[u8]) -> Result<Values, SqliteError> {
@@ -120,75 +390,336 @@ impl Store for SqliteStore {
   fn write(&self, space: &str, key: &[u8], val: &[u8]) -> Result<(), SqliteError> {
     trace!("#write: {:?}/{:?}={:?}", space, key, val);
     let db = try!(self.open_db());
+    let idx = try!(append(&db, space, key, val));
+    self.metrics.appended(space, idx);
+    notify_write(&self.seqnotify, space);
+    Ok(())
+  }
 
-    let sql = "SELECT seq+1 FROM logs WHERE space = ? ORDER BY seq DESC LIMIT 1";
-    trace!("{}@[{:?}]", sql, space);
-    let mut stmt = try!(db.prepare(sql));
-    let idxo = try!(stmt.query_map(&[&space], |r| r.get(0))).next();
-    let idx = try!(idxo.unwrap_or(Ok(0)));
+  fn write_from(&self, space: &str, key: &[u8], val: &[u8], producer: &Producer) -> Result<bool, SqliteError> {
+    trace!("#write_from: {:?}/{:?}={:?} by {:?}", space, key, val, producer);
+    let db = try!(self.open_db());
+    let tx = try!(db.transaction_with_behavior(rusqlite::SqliteTransactionBehavior::Immediate));
+    let id = producer.id as i64;
+    let sequence = producer.sequence as i64;
+    let last = {
+      let sql = "SELECT sequence FROM producers WHERE space = ? AND producer = ?";
+      trace!("{}@[{:?}, {:?}]", sql, space, id);
+      let mut stmt = try!(db.prepare(sql));
+      let last = try!(stmt.query_map(&[&space, &id], |r| r.get::<i64>(0))).next();
+      match last {
+        Some(last) => Some(try!(last)),
+        None => None,
+      }
+    };
+    if last.map(|last| sequence <= last).unwrap_or(false) {
+      debug!("Duplicate write: {:?} by {:?}; already seen up to {:?}", space, producer, last);
+      return Ok(false)
+    }
+    let idx = try!(append(&db, space, key, val));
+    let sql = "INSERT OR REPLACE INTO producers (space, producer, sequence) VALUES (?, ?, ?)";
+    trace!("{}@[{:?}, {:?}, {:?}]", sql, space, id, sequence);
+    try!(db.execute(sql, &[&space, &id, &sequence]));
+    try!(tx.commit());
+    self.metrics.appended(space, idx);
+    notify_write(&self.seqnotify, space);
+    Ok(true)
+  }
 
-    let sql = "INSERT INTO logs (seq, space, key, value) VALUES (?, ?, ?, ?)";
-    trace!("{}@[{:?}, {:?}, {:?}, {:?}]", sql, idx, space, key, val);
-    try!(db.execute(sql, &[&idx, &space, &key, &val]));
+  fn write_if(&self, space: &str, key: &[u8], val: &[u8], expected: &Expected) -> Result<Result<Offset, Conflict>, SqliteError> {
+    trace!("#write_if: {:?}/{:?}={:?} expecting {:?}", space, key, val, expected);
+    let db = try!(self.open_db());
+    // Taking the write lock up front means nobody can append between our
+    // check and our own append.
+    let tx = try!(db.transaction_with_behavior(rusqlite::SqliteTransactionBehavior::Immediate));
+    let (sql, wanted) = match expected {
+      &Expected::Space(offset) => ("SELECT seq FROM logs WHERE space = ?1 ORDER BY seq DESC LIMIT 1", offset),
+      &Expected::Key(offset) => ("SELECT seq FROM logs WHERE space = ?1 AND key = ?2 ORDER BY seq DESC LIMIT 1", offset),
+    };
+    let actual = {
+      trace!("{}@[{:?}, {:?}]", sql, space, key);
+      let mut stmt = try!(db.prepare(sql));
+      let params : Vec<&ToSql> = match expected {
+        &Expected::Space(_) => vec![&space],
+        &Expected::Key(_) => vec![&space, &key],
+      };
+      let seq = try!(stmt.query_map(&params, |r| r.get::<i64>(0))).next();
+      match seq {
+        Some(seq) => Some(try!(seq) as Offset),
+        None => None,
+      }
+    };
+    if actual != wanted {
+      debug!("Conflict: {:?} expected {:?}; actually {:?}", space, expected, actual);
+      return Ok(Err(Conflict { expected: expected.clone(), actual: actual }))
+    }
+    let idx = try!(append(&db, space, key, val));
+    try!(tx.commit());
+    self.metrics.appended(space, idx);
+    notify_write(&self.seqnotify, space);
+    Ok(Ok(idx as Offset))
+  }
 
-    {
-      debug!("Notify of new idx: {}", idx);
-      let &(ref lock, ref cvar) = &*self.seqnotify;
-      let mut idx_ref = lock.lock().unwrap();
-      debug!("Obtained lock! {} → {}", &*idx_ref, idx);
-      *idx_ref = idx;
-      cvar.notify_all();
+  // Subscribers only ever see committed records, so committing them all at
+  // once is enough to keep them from seeing part of the transaction.
+  fn write_all(&self, records: &[Record]) -> Result<(), SqliteError> {
+    trace!("#write_all: {:?}", records);
+    let db = try!(self.open_db());
+    let tx = try!(db.transaction_with_behavior(rusqlite::SqliteTransactionBehavior::Immediate));
+    let mut appended = Vec::new();
+    for r in records {
+      appended.push(try!(append(&db, &r.space, &r.key, &r.content)));
+    }
+    try!(tx.commit());
+    for (r, idx) in records.iter().zip(appended) {
+      self.metrics.appended(&r.space, idx);
+      notify_write(&self.seqnotify, &r.space);
     }
+    Ok(())
+  }
+
+  fn subscribe(&self, space: &str, filter: &KeyFilter, start: Offset) -> Result<Self::Iter, SqliteError> {
+    trace!("#subscribe: {:?} {:?} from {}", space, filter, start);
+    Ok(SqliteIterator{ pool: self.pool.clone(), space: space.to_string(), filter: filter.clone(), next_idx: start as i64, end: None,
+        idle: IdleHook::none(), seqnotify: self.seqnotify.clone() })
+  }
+
+  fn subscribe_pattern(&self, pattern: &str, filter: &KeyFilter) -> Result<Self::PatternIter, SqliteError> {
+    trace!("#subscribe_pattern: {:?} {:?}", pattern, filter);
+    Ok(SqlitePatternIterator{ pool: self.pool.clone(), pattern: pattern.to_string(), filter: filter.clone(), next_rowid: 0, end: None,
+        idle: IdleHook::none(), seqnotify: self.seqnotify.clone() })
+  }
+
+  fn catch_up(&self, space: &str, filter: &KeyFilter, start: Offset) -> Result<Self::Iter, SqliteError> {
+    trace!("#catch_up: {:?} {:?} from {}", space, filter, start);
+    let db = try!(self.open_db());
+    let end = {
+      let sql = "SELECT seq FROM logs WHERE space = ? ORDER BY seq DESC LIMIT 1";
+      trace!("{}@[{:?}]", sql, space);
+      let mut stmt = try!(db.prepare(sql));
+      let endo = try!(stmt.query_map(&[&space], |r| r.get(0))).next();
+      try!(endo.unwrap_or(Ok(-1)))
+    };
+    Ok(SqliteIterator{ pool: self.pool.clone(), space: space.to_string(), filter: filter.clone(), next_idx: start as i64, end: Some(end),
+        idle: IdleHook::none(), seqnotify: self.seqnotify.clone() })
+  }
 
+  fn catch_up_pattern(&self, pattern: &str, filter: &KeyFilter) -> Result<Self::PatternIter, SqliteError> {
+    trace!("#catch_up_pattern: {:?} {:?}", pattern, filter);
+    let db = try!(self.open_db());
+    let end = {
+      let sql = "SELECT rowid FROM logs ORDER BY rowid DESC LIMIT 1";
+      trace!("{}@[]", sql);
+      let mut stmt = try!(db.prepare(sql));
+      let endo = try!(stmt.query_map(&[], |r| r.get(0))).next();
+      try!(endo.unwrap_or(Ok(-1)))
+    };
+    Ok(SqlitePatternIterator{ pool: self.pool.clone(), pattern: pattern.to_string(), filter: filter.clone(), next_rowid: 0, end: Some(end),
+        idle: IdleHook::none(), seqnotify: self.seqnotify.clone() })
+  }
+
+  fn configure_queue(&self, space: &str, config: &QueueConfig) -> Result<(), SqliteError> {
+    trace!("#configure_queue: {:?}: {:?}", space, config);
+    let db = try!(self.open_db());
+    let sql = "INSERT OR REPLACE INTO queue_configs (space, visibility_timeout_ms, max_deliveries, dead_letter_space) VALUES (?, ?, ?, ?)";
+    let timeout = config.visibility_timeout_ms as i64;
+    let max_deliveries = config.max_deliveries as i64;
+    trace!("{}@[{:?}, {:?}, {:?}, {:?}]", sql, space, timeout, max_deliveries, config.dead_letter_space);
+    try!(db.execute(sql, &[&space, &timeout, &max_deliveries, &config.dead_letter_space]));
     Ok(())
   }
 
-  fn subscribe(&self, space: &str) -> Result<Self::Iter, SqliteError> {
-    trace!("#subscribe: {:?}", space);
+  fn queue_config(&self, space: &str) -> Result<Option<QueueConfig>, SqliteError> {
+    trace!("#queue_config: {:?}", space);
     let db = try!(self.open_db());
-    Ok(SqliteIterator{ db: db, space: space.to_string(), next_idx: 0, seqnotify: self.seqnotify.clone() })
+    let sql = "SELECT visibility_timeout_ms, max_deliveries, dead_letter_space FROM queue_configs WHERE space = ?";
+    let mut stmt = try!(db.prepare(sql));
+    let config = try!(stmt.query_map(&[&space], |r| QueueConfig {
+          visibility_timeout_ms: r.get::<i64>(0) as u64,
+          max_deliveries: r.get::<i64>(1) as u32,
+          dead_letter_space: r.get::<Option<String>>(2),
+          })).next();
+    match config {
+      Some(config) => Ok(Some(try!(config))),
+      None => Ok(None),
+    }
+  }
+
+  fn is_dead_letter_space(&self, space: &str) -> Result<bool, SqliteError> {
+    trace!("#is_dead_letter_space: {:?}", space);
+    let db = try!(self.open_db());
+    let mut stmt = try!(db.prepare("SELECT 1 FROM queue_configs WHERE dead_letter_space = ? LIMIT 1"));
+    let found = try!(stmt.query_map(&[&space], |_| ())).next().is_some();
+    Ok(found)
+  }
+
+  fn consume(&self, space: &str, consumer: &str, filter: &KeyFilter) -> Result<Self::Leases, SqliteError> {
+    trace!("#consume: {:?} {:?} by {:?}", space, filter, consumer);
+    // Otherwise every message would be leased once, expire at once, and be
+    // dropped for want of a dead-letter space.
+    let config = match try!(self.queue_config(space)) {
+      Some(config) => config,
+      None => return Err(SqliteError::NotAQueue(space.to_string())),
+    };
+    Ok(SqliteLeaseIterator{ pool: self.pool.clone(), space: space.to_string(), consumer: consumer.to_string(),
+        filter: filter.clone(), config: config, idle: IdleHook::none(), seqnotify: self.seqnotify.clone(),
+        metrics: self.metrics.clone(), clock: self.clock.clone() })
+  }
+
+  fn ack(&self, space: &str, consumer: &str, offset: Offset) -> Result<bool, SqliteError> {
+    trace!("#ack: {:?}@{:?} by {:?}", space, offset, consumer);
+    let db = try!(self.open_db());
+    let sql = "UPDATE leases SET acked = 1 WHERE space = ? AND seq = ? AND consumer = ? AND acked = 0";
+    let seq = offset as i64;
+    trace!("{}@[{:?}, {:?}, {:?}]", sql, space, seq, consumer);
+    let updated = try!(db.execute(sql, &[&space, &seq, &consumer]));
+    Ok(updated > 0)
+  }
+
+  fn nack(&self, space: &str, consumer: &str, offset: Offset, reason: &str) -> Result<bool, SqliteError> {
+    trace!("#nack: {:?}@{:?} by {:?}: {}", space, offset, consumer, reason);
+    let config = match try!(self.queue_config(space)) {
+      Some(config) => config,
+      None => return Ok(false),
+    };
+    let db = try!(self.open_db());
+    // As when leasing, so that a nack racing a lease or another nack waits
+    // for it rather than failing.
+    let tx = try!(db.transaction_with_behavior(rusqlite::SqliteTransactionBehavior::Immediate));
+    let seq = offset as i64;
+    let deliveries = {
+      let sql = "SELECT deliveries FROM leases WHERE space = ? AND seq = ? AND consumer = ? AND acked = 0";
+      trace!("{}@[{:?}, {:?}, {:?}]", sql, space, seq, consumer);
+      let mut stmt = try!(db.prepare(sql));
+      let deliveries = try!(stmt.query_map(&[&space, &seq, &consumer], |r| r.get::<i64>(0))).next();
+      match deliveries {
+        Some(deliveries) => try!(deliveries),
+        None => return Ok(false),
+      }
+    };
+    let dead = try!(dead_letter(&db, space, seq, deliveries, reason, &config));
+    try!(tx.commit());
+    if let (Some(idx), Some(ref dead_letter_space)) = (dead, config.dead_letter_space) {
+      self.metrics.appended(dead_letter_space, idx);
+      notify_write(&self.seqnotify, dead_letter_space);
+    }
+    Ok(true)
+  }
+
+  // Retention may have trimmed the start of a space, so its first offset
+  // isn't always zero.
+  fn spaces(&self) -> Result<Vec<SpaceStatus>, SqliteError> {
+    trace!("#spaces");
+    let db = try!(self.open_db());
+    let sql = "SELECT space, MIN(seq), MAX(seq), SUM(LENGTH(key) + LENGTH(value)) FROM logs GROUP BY space ORDER BY space";
+    trace!("{}@[]", sql);
+    let mut stmt = try!(db.prepare(sql));
+    let rows = try!(stmt.query_map(&[], |r| SpaceStatus {
+          name: r.get(0),
+          first: r.get::<i64>(1) as Offset,
+          last: r.get::<i64>(2) as Offset,
+          bytes: r.get::<i64>(3) as u64,
+          }));
+    let mut spaces = Vec::new();
+    for space in rows {
+      spaces.push(try!(space));
+    }
+    Ok(spaces)
+  }
+
+  fn watch(&self) -> Receiver<String> {
+    let (tx, rx) = channel();
+    self.seqnotify.watchers.lock().unwrap().push(tx);
+    rx
+  }
+
+  // Each write is on disk once it has committed, so all that's left is to
+  // checkpoint the write-ahead log into the database proper.
+  fn flush(&self) -> Result<(), SqliteError> {
+    trace!("#flush");
+    let db = try!(self.open_db());
+    let mut stmt = try!(db.prepare("PRAGMA wal_checkpoint(TRUNCATE)"));
+    let rows = try!(stmt.query_map(&[], |_| ()));
+    for row in rows {
+      try!(row);
+    }
+    Ok(())
   }
 }
 
 impl SqliteIterator {
-  fn fetch_next(&mut self) -> Result<Option<Datum>, SqliteError> {
-    trace!("#fetch_next: {:?}", self);
-    loop {
-      let sql = "SELECT seq, key, value FROM logs WHERE space = ? AND seq = ? ORDER BY seq ASC /* LIMIT 1 */";
-      let mut q = try!(self.db.prepare(sql));
-      trace!("{}@[{}, {}]", sql, self.space, self.next_idx);
-
-      let mut results = try!(q.query(&[&self.space, &self.next_idx]));
-
-      while let Some(mut rowp) = results.next() {
-        let row = try!(rowp);
-        let seq : i64 = row.get::<i64>(0);
-        let key = row.get(1);
-        let value = row.get(2);
-        let datum = Datum { key: key, content: value };
+  fn try_fetch(&mut self) -> Result<Polled<(Offset, Datum)>, SqliteError> {
+    let db = try!(self.pool.get());
+    let found = {
+      let (filter, filter_params) = filter_sql("key", &self.filter, 3);
+      let sql = format!("SELECT seq, key, value FROM logs WHERE space = ?1 AND seq >= ?2 AND {} ORDER BY seq ASC LIMIT 1", filter);
+      let mut q = try!(db.prepare(&sql));
+      trace!("{}@[{}, {}, {:?}]", sql, self.space, self.next_idx, filter_params);
+
+      let mut params : Vec<&ToSql> = vec![&self.space, &self.next_idx];
+      params.extend(filter_params.iter().map(|p| p as &ToSql));
+      let mut results = try!(q.query(&params));
+
+      match results.next() {
+        Some(rowp) => {
+          let row = try!(rowp);
+          let seq : i64 = row.get::<i64>(0);
+          let key = row.get(1);
+          let value = row.get(2);
+          Some((seq, Datum { key: key, content: value }))
+        },
+        None => None,
+      }
+    };
+
+    // Everything up to a catch-up subscription's end was already written
+    // when it started, so there is nothing to wait for.
+    match (found, self.end) {
+      (Some((seq, _)), Some(end)) if seq > end => Ok(Polled::Finished),
+      (Some((seq, datum)), _) => {
         debug!("Result: @{:?} {:?}", seq, datum);
         self.next_idx = seq+1;
-        return Ok(Some(datum))
+        Ok(Polled::Ready((seq as Offset, datum)))
+      },
+      (None, Some(_)) => Ok(Polled::Finished),
+      (None, None) => Ok(Polled::NotYet),
+    }
+  }
+
+  fn fetch_next(&mut self) -> Result<Option<(Offset, Datum)>, SqliteError> {
+    trace!("#fetch_next: {:?}", self);
+    loop {
+      // Records that don't match the filter are skipped by the query, so we
+      // can't tell from the index alone whether there's anything new; instead
+      // we wait for any write made after we looked.
+      let seen = writes_seen(&self.seqnotify);
+      match try!(self.try_fetch()) {
+        Polled::Ready(item) => return Ok(Some(item)),
+        Polled::Finished => return Ok(None),
+        Polled::NotYet => (),
       }
 
-      {
-        let &(ref lock, ref cvar) = &*self.seqnotify;
-        trace!("Nothing found: @{:?}; waiting", self);
-        let mut seq = lock.lock().unwrap();
-        trace!("Current seq: {:?}; db seq: {:?};", self.next_idx, *seq);
-        while self.next_idx > *seq {
-          trace!("Wait! want next-idx:{:?} > current:{:?}", &*seq, self.next_idx);
-          let (lockp, _no_timeout) = cvar.wait_timeout_ms(seq, 1000).unwrap();
-          seq = lockp;
-          trace!("Awoken! current:{:?}; expected next-idx:{:?}; timeout? {:?}", &*seq, self.next_idx, _no_timeout);
-        }
+      trace!("Nothing found: @{:?}; waiting", self);
+      if !await_writes(&self.seqnotify, seen, &mut self.idle) {
+        return Ok(None)
       }
     }
   }
 
 }
+
+impl Waiting for SqliteIterator {
+  fn on_idle(&mut self, interval_ms: u64, idle: Box<FnMut() -> bool + Send>) {
+    self.idle = IdleHook::new(interval_ms, idle);
+  }
+
+  fn poll_next(&mut self) -> Result<Polled<Self::Item>, Box<Error + Send>> {
+    self.try_fetch().map_err(|e| Box::new(e) as Box<Error + Send>)
+  }
+}
+
 impl Iterator for SqliteIterator {
-  type Item = Datum;
+  type Item = (Offset, Datum);
 
   fn next(&mut self) -> Option<Self::Item> {
     trace!("Iterator#next {:?}", self);
@@ -199,8 +730,227 @@ impl Iterator for SqliteIterator {
 
 impl fmt::Debug for SqliteIterator {
   fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-    write!(fmt, "SqliteIterator{{ db: <db>, next_idx:{:?}, space:{:?} }}",
-      &self.next_idx, &self.space)
+    write!(fmt, "SqliteIterator{{ db: <db>, next_idx:{:?}, end:{:?}, space:{:?}, filter:{:?} }}",
+      &self.next_idx, &self.end, &self.space, &self.filter)
+  }
+}
+
+// Offsets are per-space, so to merge several spaces in write order we walk
+// the table by rowid instead, which only ever grows as records are appended.
+impl SqlitePatternIterator {
+  fn try_fetch(&mut self) -> Result<Polled<(String, Offset, Datum)>, SqliteError> {
+    let db = try!(self.pool.get());
+    let found = {
+      let (filter, filter_params) = filter_sql("key", &self.filter, 3);
+      let sql = format!("SELECT rowid, space, seq, key, value FROM logs WHERE rowid >= ?1 AND space GLOB ?2 AND {} ORDER BY rowid ASC LIMIT 1", filter);
+      let mut q = try!(db.prepare(&sql));
+      trace!("{}@[{}, {}, {:?}]", sql, self.next_rowid, self.pattern, filter_params);
+
+      let mut params : Vec<&ToSql> = vec![&self.next_rowid, &self.pattern];
+      params.extend(filter_params.iter().map(|p| p as &ToSql));
+      let mut results = try!(q.query(&params));
+
+      match results.next() {
+        Some(rowp) => {
+          let row = try!(rowp);
+          let rowid : i64 = row.get(0);
+          let space : String = row.get(1);
+          let seq : i64 = row.get(2);
+          let key = row.get(3);
+          let value = row.get(4);
+          Some((rowid, space, seq, Datum { key: key, content: value }))
+        },
+        None => None,
+      }
+    };
+
+    match (found, self.end) {
+      (Some((rowid, _, _, _)), Some(end)) if rowid > end => Ok(Polled::Finished),
+      (Some((rowid, space, seq, datum)), _) => {
+        debug!("Result: {:?}@{:?} {:?}", space, seq, datum);
+        self.next_rowid = rowid+1;
+        Ok(Polled::Ready((space, seq as Offset, datum)))
+      },
+      (None, Some(_)) => Ok(Polled::Finished),
+      (None, None) => Ok(Polled::NotYet),
+    }
+  }
+
+  fn fetch_next(&mut self) -> Result<Option<(String, Offset, Datum)>, SqliteError> {
+    trace!("#fetch_next: {:?}", self);
+    loop {
+      let seen = writes_seen(&self.seqnotify);
+      match try!(self.try_fetch()) {
+        Polled::Ready(item) => return Ok(Some(item)),
+        Polled::Finished => return Ok(None),
+        Polled::NotYet => (),
+      }
+
+      trace!("Nothing found: @{:?}; waiting", self);
+      if !await_writes(&self.seqnotify, seen, &mut self.idle) {
+        return Ok(None)
+      }
+    }
+  }
+}
+
+impl Waiting for SqlitePatternIterator {
+  fn on_idle(&mut self, interval_ms: u64, idle: Box<FnMut() -> bool + Send>) {
+    self.idle = IdleHook::new(interval_ms, idle);
+  }
+
+  fn poll_next(&mut self) -> Result<Polled<Self::Item>, Box<Error + Send>> {
+    self.try_fetch().map_err(|e| Box::new(e) as Box<Error + Send>)
+  }
+}
+
+impl Iterator for SqlitePatternIterator {
+  type Item = (String, Offset, Datum);
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.fetch_next().unwrap()
+  }
+}
+
+impl fmt::Debug for SqlitePatternIterator {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmt, "SqlitePatternIterator{{ db: <db>, next_rowid:{:?}, end:{:?}, pattern:{:?}, filter:{:?} }}",
+      &self.next_rowid, &self.end, &self.pattern, &self.filter)
+  }
+}
+
+impl SqliteLeaseIterator {
+  // Dead-letters any messages whose final lease has expired without being
+  // acknowledged, and returns where each went in the dead-letter space.
+  fn expire_exhausted(&self, db: &rusqlite::SqliteConnection, now: i64) -> Result<Vec<i64>, SqliteError> {
+    let exhausted : Vec<(i64, i64)> = {
+      let sql = "SELECT seq, deliveries FROM leases
+                 WHERE space = ?1 AND acked = 0 AND deadline <= ?2 AND deliveries >= ?3";
+      let max_deliveries = self.config.max_deliveries as i64;
+      trace!("{}@[{:?}, {:?}, {:?}]", sql, self.space, now, max_deliveries);
+      let mut stmt = try!(db.prepare(sql));
+      let rows = try!(stmt.query_map(&[&self.space, &now, &max_deliveries], |r| (r.get(0), r.get(1))));
+      try!(rows.collect())
+    };
+
+    let mut dead = Vec::new();
+    for (seq, deliveries) in exhausted {
+      let reason = format!("Not acknowledged after {} deliveries", deliveries);
+      if let Some(idx) = try!(dead_letter(db, &self.space, seq, deliveries, &reason, &self.config)) {
+        dead.push(idx);
+      }
+    }
+    Ok(dead)
+  }
+
+  fn try_lease(&mut self) -> Result<Option<(Offset, Datum)>, SqliteError> {
+    let now = (self.clock)();
+    let db = try!(self.pool.get());
+    // Taking the write lock up front means that another consumer can't lease
+    // the same message between our looking for it and leasing it, and that
+    // we wait for them rather than fail to upgrade a read lock.
+    let tx = try!(db.transaction_with_behavior(rusqlite::SqliteTransactionBehavior::Immediate));
+    let dead = try!(self.expire_exhausted(&db, now));
+    let found = {
+      let (filter, filter_params) = filter_sql("l.key", &self.filter, 4);
+      let sql = format!("SELECT l.seq, l.key, l.value, IFNULL(q.deliveries, 0) FROM logs l
+                 LEFT JOIN leases q ON q.space = l.space AND q.seq = l.seq
+                 WHERE l.space = ?1
+                 AND (q.seq IS NULL OR (q.acked = 0 AND q.deadline <= ?2 AND q.deliveries < ?3))
+                 AND {}
+                 ORDER BY l.seq ASC LIMIT 1", filter);
+      let max_deliveries = self.config.max_deliveries as i64;
+      trace!("{}@[{:?}, {:?}, {:?}, {:?}]", sql, self.space, now, max_deliveries, filter_params);
+      let mut q = try!(db.prepare(&sql));
+      let mut params : Vec<&ToSql> = vec![&self.space, &now, &max_deliveries];
+      params.extend(filter_params.iter().map(|p| p as &ToSql));
+      let mut results = try!(q.query(&params));
+      match results.next() {
+        Some(rowp) => {
+          let row = try!(rowp);
+          let seq : i64 = row.get(0);
+          let datum = Datum { key: row.get(1), content: row.get(2) };
+          let deliveries : i64 = row.get(3);
+          Some((seq, datum, deliveries))
+        },
+        None => None,
+      }
+    };
+
+    let res = match found {
+      Some((seq, datum, deliveries)) => {
+        let deadline = now + self.config.visibility_timeout_ms as i64;
+        let sql = "INSERT OR REPLACE INTO leases (space, seq, consumer, deadline, deliveries, acked) VALUES (?, ?, ?, ?, ?, 0)";
+        let deliveries = deliveries + 1;
+        trace!("{}@[{:?}, {:?}, {:?}, {:?}, {:?}]", sql, self.space, seq, self.consumer, deadline, deliveries);
+        try!(db.execute(sql, &[&self.space, &seq, &self.consumer, &deadline, &deliveries]));
+        debug!("Leased: {:?}@{:?} to {:?} (delivery #{})", self.space, seq, self.consumer, deliveries);
+        Some((seq as Offset, datum))
+      },
+      None => None,
+    };
+    try!(tx.commit());
+    if let Some(ref dead_letter_space) = self.config.dead_letter_space {
+      for idx in dead {
+        self.metrics.appended(dead_letter_space, idx);
+        notify_write(&self.seqnotify, dead_letter_space);
+      }
+    }
+    Ok(res)
+  }
+
+  fn fetch_next(&mut self) -> Result<Option<(Offset, Datum)>, SqliteError> {
+    trace!("#fetch_next: {:?}", self);
+    loop {
+      if let Some(leased) = try!(self.try_lease()) {
+        return Ok(Some(leased))
+      }
+
+      // Expired leases do not trigger a notification, so we also need to
+      // wake up often enough to notice them.
+      let timeout = ::std::cmp::min(::std::cmp::max(self.config.visibility_timeout_ms, 1), MAX_LEASE_WAIT_MS as u64) as u32;
+      let timeout = ::std::cmp::min(timeout, self.idle.wait_ms());
+      {
+        let writes = self.seqnotify.writes.lock().unwrap();
+        trace!("Nothing to lease: {:?}; waiting up to {}ms", self, timeout);
+        let _ = self.seqnotify.cvar.wait_timeout_ms(writes, timeout).unwrap();
+      }
+      if !self.idle.tick() {
+        return Ok(None)
+      }
+    }
+  }
+}
+
+impl Waiting for SqliteLeaseIterator {
+  fn on_idle(&mut self, interval_ms: u64, idle: Box<FnMut() -> bool + Send>) {
+    self.idle = IdleHook::new(interval_ms, idle);
+  }
+
+  // A queue never runs dry for good, as leases may yet expire.
+  fn poll_next(&mut self) -> Result<Polled<Self::Item>, Box<Error + Send>> {
+    match self.try_lease() {
+      Ok(Some(leased)) => Ok(Polled::Ready(leased)),
+      Ok(None) => Ok(Polled::NotYet),
+      Err(e) => Err(Box::new(e)),
+    }
+  }
+}
+
+impl Iterator for SqliteLeaseIterator {
+  type Item = (Offset, Datum);
+
+  fn next(&mut self) -> Option<Self::Item> {
+    trace!("Iterator#next {:?}", self);
+
+    self.fetch_next().unwrap()
+  }
+}
+
+impl fmt::Debug for SqliteLeaseIterator {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmt, "SqliteLeaseIterator{{ db: <db>, space:{:?}, consumer:{:?}, filter:{:?}, config:{:?} }}",
+      &self.space, &self.consumer, &self.filter, &self.config)
   }
 }
 impl From<rusqlite::SqliteError> for SqliteError {
@@ -223,21 +973,130 @@ impl From<io::Error> for SqliteError {
 
 #[cfg(test)]
 mod test {
-  use super::SqliteStore;
+  use super::{SqliteStore, Clock};
+  use config::StoreConfig;
+  use metrics::Metrics;
+  use store::{Store, Waiting, Polled};
   use store::test::TestableStore;
+  use yak_client::{KeyFilter, QueueConfig};
   use quickcheck::TestResult;
   use rand::Rng;
   use std::path::PathBuf;
   use std::fs;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
   
+  fn store_dir() -> PathBuf {
+    let mut rng = ::rand::thread_rng();
+    let p = PathBuf::from(format!("target/sqlite3_store/{}", rng.gen_ascii_chars().take(16).collect::<String>()));
+    fs::create_dir_all(&p).unwrap();
+    p
+  }
+
   impl TestableStore for SqliteStore {
     fn build() -> SqliteStore {
-      let mut rng = ::rand::thread_rng();
-      let p = PathBuf::from(format!("target/sqlite3_store/{}", rng.gen_ascii_chars().take(16).collect::<String>()));
-      fs::create_dir_all(&p).unwrap();
-      SqliteStore::new(&p).unwrap()
+      SqliteStore::new(&store_dir()).unwrap()
+    }
+  }
+
+  #[test]
+  fn retention_keeps_the_last_records() {
+    let mut config = StoreConfig::at(&store_dir());
+    config.max_records = Some(3);
+    let store = SqliteStore::open(&config).unwrap();
+    for i in 0..5u8 {
+      store.write("retained", b"key", &[i]).unwrap();
+    }
+    store.write("other", b"key", b"untouched").unwrap();
+
+    let kept : Vec<_> = store.catch_up("retained", &KeyFilter::All, 0).unwrap().map(|(offset, d)| (offset, d.content)).collect();
+    assert_eq!(kept, vec![(2, vec![2]), (3, vec![3]), (4, vec![4])]);
+    assert_eq!(store.read("other", b"key").unwrap(), vec![b"untouched".to_vec()]);
+  }
+
+  fn leased_offset<W: Waiting<Item=(u64, ::yak_client::Datum)>>(leases: &mut W) -> Option<u64> {
+    match leases.poll_next().unwrap() {
+      Polled::Ready((offset, _)) => Some(offset),
+      _ => None,
     }
   }
 
+  #[test]
+  fn leases_expire_by_the_stores_clock() {
+    let now = Arc::new(AtomicUsize::new(0));
+    let clock : Clock = {
+      let now = now.clone();
+      Arc::new(move || now.load(Ordering::SeqCst) as i64)
+    };
+    let store = SqliteStore::new(&store_dir()).unwrap().with_clock(clock);
+    let config = QueueConfig { visibility_timeout_ms: 1000, max_deliveries: 2, dead_letter_space: None };
+    store.configure_queue("leased", &config).unwrap();
+    store.write("leased", b"key", b"value").unwrap();
+
+    assert_eq!(leased_offset(&mut store.consume("leased", "first", &KeyFilter::All).unwrap()), Some(0));
+    now.store(999, Ordering::SeqCst);
+    assert_eq!(leased_offset(&mut store.consume("leased", "second", &KeyFilter::All).unwrap()), None);
+    now.store(1000, Ordering::SeqCst);
+    assert_eq!(leased_offset(&mut store.consume("leased", "second", &KeyFilter::All).unwrap()), Some(0));
+  }
+
+  #[test]
+  fn consumers_racing_on_a_queue_lease_each_message_once() {
+    let store = SqliteStore::new(&store_dir()).unwrap();
+    let config = QueueConfig { visibility_timeout_ms: 60000, max_deliveries: 2, dead_letter_space: None };
+    store.configure_queue("raced", &config).unwrap();
+    for i in 0..100u8 {
+      store.write("raced", b"key", &[i]).unwrap();
+    }
+
+    let consumers : Vec<_> = (0..4).map(|n| {
+      let store = store.clone();
+      thread::spawn(move || {
+        let mut leases = store.consume("raced", &format!("consumer-{}", n), &KeyFilter::All).unwrap();
+        let mut leased = Vec::new();
+        while let Some(offset) = leased_offset(&mut leases) {
+          leased.push(offset);
+        }
+        leased
+      })
+    }).collect();
+    let mut leased : Vec<u64> = consumers.into_iter().flat_map(|c| c.join().unwrap()).collect();
+    leased.sort();
+    assert_eq!(leased, (0..100).collect::<Vec<u64>>());
+  }
+
+  #[test]
+  fn dead_letters_count_towards_their_spaces_last_offset() {
+    let metrics = Metrics::new();
+    let store = SqliteStore::new(&store_dir()).unwrap().with_metrics(metrics.clone());
+    let config = QueueConfig { visibility_timeout_ms: 60000, max_deliveries: 2, dead_letter_space: Some("rejected".to_string()) };
+    store.configure_queue("rejecting", &config).unwrap();
+    store.write("rejecting", b"key", b"value").unwrap();
+    let offset = leased_offset(&mut store.consume("rejecting", "consumer", &KeyFilter::All).unwrap()).unwrap();
+    assert!(store.nack("rejecting", "consumer", offset, "no thanks").unwrap());
+
+    let text = metrics.render();
+    assert!(text.contains("yak_space_last_offset{space=\"rejected\"} 0\n"), "{}", text);
+  }
+
+  #[test]
+  fn flushing_empties_the_write_ahead_log() {
+    let dir = store_dir();
+    let store = SqliteStore::new(&dir).unwrap();
+    store.write("flushed", b"key", b"value").unwrap();
+    let wal = dir.join("queues.sqlite-wal");
+    assert!(fs::metadata(&wal).unwrap().len() > 0);
+    store.flush().unwrap();
+    assert_eq!(fs::metadata(&wal).unwrap().len(), 0);
+  }
+
+  #[test]
+  fn only_work_queues_can_be_consumed() {
+    let store = SqliteStore::new(&store_dir()).unwrap();
+    store.write("plain", b"key", b"value").unwrap();
+    assert!(store.consume("plain", "consumer", &KeyFilter::All).is_err());
+  }
+
   build_store_tests!(SqliteStore);
 }
@@ -1,19 +1,86 @@
-extern crate quickcheck;
-
 use std::error::Error;
 use std::any::Any;
-use yak_client::Datum;
+use std::sync::mpsc::Receiver;
+use yak_client::{Datum, Offset, QueueConfig, KeyFilter, Expected, Conflict, Record, Producer, SpaceStatus};
 
 pub type Key = (String, Vec<u8>);
 pub type Val = Vec<u8>;
 pub type Values = Vec<Val>;
 
+/// What a non-blocking look for the next item found.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Polled<T> {
+  Ready(T),
+  /// Nothing yet; it's worth looking again once the store has been written to.
+  NotYet,
+  /// Nothing, and there never will be.
+  Finished,
+}
+
+impl<T> Polled<T> {
+  pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Polled<U> {
+    match self {
+      Polled::Ready(item) => Polled::Ready(f(item)),
+      Polled::NotYet => Polled::NotYet,
+      Polled::Finished => Polled::Finished,
+    }
+  }
+}
+
+/// An iterator that blocks until there is something for it to return.
+pub trait Waiting : Iterator + Send {
+  /// Has `next` call `idle` roughly every `interval_ms` while it waits, and
+  /// give up and return `None` once `idle` returns false.
+  fn on_idle(&mut self, interval_ms: u64, idle: Box<FnMut() -> bool + Send>);
+  /// Like `next`, but returns `NotYet` rather than waiting.
+  fn poll_next(&mut self) -> Result<Polled<Self::Item>, Box<Error + Send>>;
+}
+
 pub trait Store : Clone {
-  type Iter: Iterator<Item=Datum>;
+  type Iter: Waiting<Item=(Offset, Datum)> + 'static;
+  type Leases: Waiting<Item=(Offset, Datum)> + 'static;
+  type PatternIter: Waiting<Item=(String, Offset, Datum)> + 'static;
   type Error: Error + Any + Send + 'static;
   fn read(&self, space: &str, key: &[u8]) -> Result<Values, Self::Error>;
   fn write(&self, space: &str, key: &[u8], val: &[u8]) -> Result<(), Self::Error>;
-  fn subscribe(&self, space: &str) -> Result<Self::Iter, Self::Error> ;
+  /// Appends unless `producer` has already written its sequence number (or
+  /// a later one) to the space, and returns whether it did.
+  fn write_from(&self, space: &str, key: &[u8], val: &[u8], producer: &Producer) -> Result<bool, Self::Error>;
+  /// Appends only if the log is where `expected` says it should be, checked
+  /// atomically with the append. A conflict is an expected outcome rather
+  /// than a failure of the store, so it's returned as the inner error.
+  fn write_if(&self, space: &str, key: &[u8], val: &[u8], expected: &Expected) -> Result<Result<Offset, Conflict>, Self::Error>;
+  /// Appends all of `records` or none of them. Subscribers see either all
+  /// of them or none.
+  fn write_all(&self, records: &[Record]) -> Result<(), Self::Error>;
+  /// Yields the space's records from offset `start` onwards.
+  fn subscribe(&self, space: &str, filter: &KeyFilter, start: Offset) -> Result<Self::Iter, Self::Error> ;
+  /// Like `subscribe`, but over every space whose name matches `pattern`,
+  /// including spaces first written to after the subscription starts.
+  /// Records are yielded in the order they were written.
+  fn subscribe_pattern(&self, pattern: &str, filter: &KeyFilter) -> Result<Self::PatternIter, Self::Error>;
+  /// Like `subscribe`, but the iterator ends after the last record written
+  /// before the call.
+  fn catch_up(&self, space: &str, filter: &KeyFilter, start: Offset) -> Result<Self::Iter, Self::Error>;
+  fn catch_up_pattern(&self, pattern: &str, filter: &KeyFilter) -> Result<Self::PatternIter, Self::Error>;
+
+  fn configure_queue(&self, space: &str, config: &QueueConfig) -> Result<(), Self::Error>;
+  fn queue_config(&self, space: &str) -> Result<Option<QueueConfig>, Self::Error>;
+  /// Whether any work queue sends its dead letters to `space`.
+  fn is_dead_letter_space(&self, space: &str) -> Result<bool, Self::Error>;
+  /// Blocks to lease each available message in a work-queue space to `consumer`.
+  fn consume(&self, space: &str, consumer: &str, filter: &KeyFilter) -> Result<Self::Leases, Self::Error>;
+  /// Returns false if `consumer` no longer holds the lease on `offset`.
+  fn ack(&self, space: &str, consumer: &str, offset: Offset) -> Result<bool, Self::Error>;
+  /// Moves a leased message to the space's dead-letter space, if it has one.
+  fn nack(&self, space: &str, consumer: &str, offset: Offset, reason: &str) -> Result<bool, Self::Error>;
+  /// Summarises each space that holds any records, in name order.
+  fn spaces(&self) -> Result<Vec<SpaceStatus>, Self::Error>;
+  /// Receives the name of each space as it's written to, for as long as the
+  /// receiver is kept.
+  fn watch(&self) -> Receiver<String>;
+  /// Makes sure that everything written so far is on disk.
+  fn flush(&self) -> Result<(), Self::Error>;
 }
 
 macro_rules! try_as_any {
@@ -25,7 +92,9 @@ macro_rules! try_as_any {
 pub mod test {
   use super::*;
   use std::thread;
+  use std::time::Duration;
   use std::sync::{Arc, Barrier, Once, ONCE_INIT};
+  use yak_client::{QueueConfig, DeadLetter, KeyFilter, Expected, Conflict, Record, Producer, SpaceStatus};
   use std::any::Any;
   use quickcheck::TestResult;
   use log4rs;
@@ -82,7 +151,7 @@ pub mod test {
       }
 
       debug!("Expected: {:?}", kvs);
-      let actual : Vec<_> = try_as_any!(store.subscribe(&space)).take(kvs.len()).map(|d| (d.key, d.content) ).collect();
+      let actual : Vec<_> = try_as_any!(store.subscribe(&space, &KeyFilter::All, 0)).take(kvs.len()).map(|(_, d)| (d.key, d.content) ).collect();
 
       debug!("Got     : {:?}", actual);
       debug!("Ok?     : {:?}", kvs == actual);
@@ -102,9 +171,9 @@ pub mod test {
 
       let expected : Vec<_> = kvs.iter().filter_map(|x| if x.0 { Some(x.clone()) } else { None }).collect();
 
-      let actual : Vec<_> = try_as_any!(store.subscribe(&format!("{}/{}", space_prefix, true)))
+      let actual : Vec<_> = try_as_any!(store.subscribe(&format!("{}/{}", space_prefix, true), &KeyFilter::All, 0))
         .take(expected.len())
-        .map(|d| (true, d.key, d.content) )
+        .map(|(_, d)| (true, d.key, d.content) )
         .collect();
       debug!("Got     : {:?}", actual);
       debug!("Expected: {:?}", expected);
@@ -112,6 +181,240 @@ pub mod test {
       Ok(expected == actual)
     }
 
+    fn test_put_subscribe_filtered_values_qc(kvs: Vec<(Vec<u8>, Vec<u8>)>, prefix: Vec<u8>) -> Result<bool, BoxedError> {
+      log_init();
+      let store = Self::build();
+
+      let space = "test_put_subscribe_filtered_values_qc";
+      for &(ref key, ref val) in &kvs {
+        try_as_any!(store.write(&space, &key, &val));
+      }
+
+      let expected : Vec<_> = kvs.iter().filter(|&&(ref k, _)| k.starts_with(&prefix)).cloned().collect();
+      let actual : Vec<_> = try_as_any!(store.subscribe(&space, &KeyFilter::Prefix(prefix.clone()), 0))
+        .take(expected.len())
+        .map(|(_, d)| (d.key, d.content) )
+        .collect();
+      debug!("Got     : {:?}", actual);
+      debug!("Expected: {:?}", expected);
+      Ok(expected == actual)
+    }
+
+    fn test_put_subscribe_range_values_qc(kvs: Vec<(Vec<u8>, Vec<u8>)>, start: Vec<u8>, end: Vec<u8>) -> Result<bool, BoxedError> {
+      log_init();
+      let store = Self::build();
+
+      let space = "test_put_subscribe_range_values_qc";
+      for &(ref key, ref val) in &kvs {
+        try_as_any!(store.write(&space, &key, &val));
+      }
+
+      let expected : Vec<_> = kvs.iter().filter(|&&(ref k, _)| k >= &start && k < &end).cloned().collect();
+      let actual : Vec<_> = try_as_any!(store.subscribe(&space, &KeyFilter::Range(start.clone(), end.clone()), 0))
+        .take(expected.len())
+        .map(|(_, d)| (d.key, d.content) )
+        .collect();
+      debug!("Got     : {:?}", actual);
+      debug!("Expected: {:?}", expected);
+      Ok(expected == actual)
+    }
+
+    fn test_put_subscribe_pattern_values_qc(kvs: Vec<(bool, Vec<u8>, Vec<u8>)>) -> Result<bool, BoxedError> {
+      log_init();
+      let store = Self::build();
+
+      let space_of = |first: bool| if first { "orders/first" } else { "orders/second" };
+      for &(first, ref key, ref val) in &kvs {
+        try_as_any!(store.write("audit", &key, &val));
+        try_as_any!(store.write(space_of(first), &key, &val));
+      }
+
+      let expected : Vec<_> = kvs.iter().map(|&(first, ref k, ref v)| (space_of(first).to_string(), k.clone(), v.clone())).collect();
+      let actual : Vec<_> = try_as_any!(store.subscribe_pattern("orders/*", &KeyFilter::All))
+        .take(expected.len())
+        .map(|(space, _, d)| (space, d.key, d.content) )
+        .collect();
+      debug!("Got     : {:?}", actual);
+      debug!("Expected: {:?}", expected);
+      Ok(expected == actual)
+    }
+
+    fn test_subscribe_from_offset_qc(kvs: Vec<(Vec<u8>, Vec<u8>)>, skip: usize) -> Result<bool, BoxedError> {
+      log_init();
+      let store = Self::build();
+
+      let space = "test_subscribe_from_offset_qc";
+      for &(ref key, ref val) in &kvs {
+        try_as_any!(store.write(&space, &key, &val));
+      }
+      let all : Vec<_> = try_as_any!(store.subscribe(&space, &KeyFilter::All, 0)).take(kvs.len()).collect();
+      let skip = if kvs.is_empty() { 0 } else { skip % kvs.len() };
+      let start = all.get(skip).map(|&(offset, _)| offset).unwrap_or(0);
+
+      let expected : Vec<_> = all.into_iter().skip(skip).collect();
+      let actual : Vec<_> = try_as_any!(store.subscribe(&space, &KeyFilter::All, start)).take(expected.len()).collect();
+      debug!("Got     : {:?}", actual);
+      debug!("Expected: {:?}", expected);
+      Ok(expected == actual)
+    }
+
+    fn test_catch_up_ends_at_head_qc(kvs: Vec<(Vec<u8>, Vec<u8>)>, later: Vec<(Vec<u8>, Vec<u8>)>) -> Result<bool, BoxedError> {
+      log_init();
+      let store = Self::build();
+
+      let space = "test_catch_up_ends_at_head_qc";
+      for &(ref key, ref val) in &kvs {
+        try_as_any!(store.write(&space, &key, &val));
+      }
+      let iter = try_as_any!(store.catch_up(&space, &KeyFilter::All, 0));
+      let pattern_iter = try_as_any!(store.catch_up_pattern("test_catch_up_*", &KeyFilter::All));
+      for &(ref key, ref val) in &later {
+        try_as_any!(store.write(&space, &key, &val));
+      }
+
+      let actual : Vec<_> = iter.map(|(_, d)| (d.key, d.content)).collect();
+      let from_pattern : Vec<_> = pattern_iter.map(|(_, _, d)| (d.key, d.content)).collect();
+      debug!("Got     : {:?}", actual);
+      debug!("Expected: {:?}", kvs);
+      Ok(kvs == actual && kvs == from_pattern)
+    }
+
+    fn test_subscribe_gives_up_when_idle_qc(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<bool, BoxedError> {
+      log_init();
+      let store = Self::build();
+
+      let space = "test_subscribe_gives_up_when_idle_qc";
+      for &(ref key, ref val) in &kvs {
+        try_as_any!(store.write(&space, &key, &val));
+      }
+
+      let mut iter = try_as_any!(store.subscribe(&space, &KeyFilter::All, 0));
+      iter.on_idle(1, Box::new(|| false));
+      let actual : Vec<_> = iter.map(|(_, d)| (d.key, d.content)).collect();
+      debug!("Got     : {:?}", actual);
+      debug!("Expected: {:?}", kvs);
+      Ok(kvs == actual)
+    }
+
+    fn test_poll_next_does_not_wait_qc(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<bool, BoxedError> {
+      log_init();
+      let store = Self::build();
+
+      let space = "test_poll_next_does_not_wait_qc";
+      let watch = store.watch();
+      let mut iter = try_as_any!(store.subscribe(&space, &KeyFilter::All, 0));
+      let before = try_as_any!(iter.poll_next().map_err(|e| e.to_string()));
+      for &(ref key, ref val) in &kvs {
+        try_as_any!(store.write(&space, &key, &val));
+      }
+
+      let mut actual = Vec::new();
+      while let Polled::Ready((_, d)) = try_as_any!(iter.poll_next().map_err(|e| e.to_string())) {
+        actual.push((d.key, d.content));
+      }
+      let watched : Vec<String> = watch.try_iter().collect();
+      debug!("Got     : {:?}", actual);
+      debug!("Expected: {:?}", kvs);
+      Ok(before == Polled::NotYet && kvs == actual && watched.len() == kvs.len() && watched.iter().all(|s| s == space))
+    }
+
+    fn test_conditional_write_qc(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<TestResult, BoxedError> {
+      log_init();
+      if kvs.is_empty() {
+        return Ok(TestResult::discard())
+      }
+      let store = Self::build();
+
+      let space = "test_conditional_write_qc";
+      let mut last = None;
+      for &(ref key, ref val) in &kvs {
+        match try_as_any!(store.write_if(&space, &key, &val, &Expected::Space(last))) {
+          Ok(offset) => last = Some(offset),
+          Err(conflict) => {
+            debug!("Unexpected conflict: {:?}", conflict);
+            return Ok(TestResult::failed())
+          },
+        }
+      }
+
+      let (ref key, ref val) = kvs[0];
+      let stale = try_as_any!(store.write_if(&space, &key, &val, &Expected::Space(None)));
+      let expected = Conflict { expected: Expected::Space(None), actual: last };
+      debug!("Got     : {:?}", stale);
+      debug!("Expected: {:?}", expected);
+      let written = try_as_any!(store.read(&space, &key)).len();
+      let expected_written = kvs.iter().filter(|&&(ref k, _)| k == key).count();
+      Ok(TestResult::from_bool(stale == Err(expected) && written == expected_written))
+    }
+
+    fn test_write_all_values_qc(kvs: Vec<(bool, Vec<u8>, Vec<u8>)>) -> Result<bool, BoxedError> {
+      log_init();
+      let store = Self::build();
+
+      let space_of = |first: bool| if first { "tx/first" } else { "tx/second" };
+      let records : Vec<_> = kvs.iter()
+        .map(|&(first, ref k, ref v)| Record { space: space_of(first).to_string(), key: k.clone(), content: v.clone() })
+        .collect();
+      try_as_any!(store.write_all(&records));
+
+      let actual : Vec<_> = try_as_any!(store.catch_up_pattern("tx/*", &KeyFilter::All))
+        .map(|(space, _, d)| Record { space: space, key: d.key, content: d.content })
+        .collect();
+      debug!("Got     : {:?}", actual);
+      debug!("Expected: {:?}", records);
+      Ok(records == actual)
+    }
+
+    fn test_spaces_summarise_writes_qc(kvs: Vec<(bool, Vec<u8>, Vec<u8>)>) -> Result<bool, BoxedError> {
+      log_init();
+      let store = Self::build();
+
+      let space_of = |first: bool| if first { "summary/first" } else { "summary/second" };
+      for &(first, ref key, ref val) in &kvs {
+        try_as_any!(store.write(space_of(first), &key, &val));
+      }
+
+      let expected : Vec<_> = [true, false].iter().filter_map(|&first| {
+        let written : Vec<_> = kvs.iter().filter(|&&(f, _, _)| f == first).collect();
+        if written.is_empty() {
+          return None
+        }
+        Some(SpaceStatus {
+          name: space_of(first).to_string(),
+          first: 0,
+          last: written.len() as u64 - 1,
+          bytes: written.iter().map(|&&(_, ref k, ref v)| (k.len() + v.len()) as u64).sum(),
+        })
+      }).collect();
+      let actual = try_as_any!(store.spaces());
+      debug!("Got     : {:?}", actual);
+      debug!("Expected: {:?}", expected);
+      Ok(expected == actual)
+    }
+
+    fn test_producer_writes_once_qc(kvs: Vec<(Vec<u8>, Vec<u8>)>, producer: u64) -> Result<bool, BoxedError> {
+      log_init();
+      let store = Self::build();
+
+      let space = "test_producer_writes_once_qc";
+      let mut firsts = Vec::new();
+      for (i, &(ref key, ref val)) in kvs.iter().enumerate() {
+        let producer = Producer { id: producer, sequence: i as u64 };
+        firsts.push(try_as_any!(store.write_from(&space, &key, &val, &producer)));
+        // As if the acknowledgement had gone missing.
+        if try_as_any!(store.write_from(&space, &key, &val, &producer)) {
+          return Ok(false)
+        }
+      }
+
+      let actual : Vec<_> = try_as_any!(store.catch_up(&space, &KeyFilter::All, 0))
+        .map(|(_, d)| (d.key, d.content))
+        .collect();
+      debug!("Got     : {:?}", actual);
+      debug!("Expected: {:?}", kvs);
+      Ok(kvs == actual && firsts.iter().all(|&written| written))
+    }
+
     fn test_put_async_subscribe_values_qc(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<bool, BoxedError> {
       use rand::{thread_rng, Rng};
       log_init();
@@ -133,9 +436,9 @@ pub mod test {
         let barrier = barrier.clone();
         let store = store.clone();
         builder.spawn(move || {
-            let sub = store.subscribe(&space).unwrap();
+            let sub = store.subscribe(&space, &KeyFilter::All, 0).unwrap();
             barrier.wait();
-            sub.take(expected_items).map(|d| (d.key, d.content) ).collect()
+            sub.take(expected_items).map(|(_, d)| (d.key, d.content) ).collect()
           }).unwrap()
       };
 
@@ -151,6 +454,117 @@ pub mod test {
       debug!("Ok?     : {:?}", kvs == actual);
       Ok(kvs == actual)
     }
+
+    fn test_queue_consume_acked_values_qc(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<bool, BoxedError> {
+      log_init();
+      let store = Self::build();
+
+      let space = "test_queue_consume_acked_values_qc";
+      let config = QueueConfig { visibility_timeout_ms: 60000, max_deliveries: 1, dead_letter_space: None };
+      try_as_any!(store.configure_queue(&space, &config));
+      for &(ref key, ref val) in &kvs {
+        try_as_any!(store.write(&space, &key, &val));
+      }
+
+      let mut actual = Vec::new();
+      for (offset, d) in try_as_any!(store.consume(&space, "consumer", &KeyFilter::All)).take(kvs.len()) {
+        if !try_as_any!(store.ack(&space, "consumer", offset)) {
+          return Ok(false)
+        }
+        actual.push((d.key, d.content));
+      }
+
+      debug!("Got     : {:?}", actual);
+      debug!("Expected: {:?}", kvs);
+      Ok(kvs == actual)
+    }
+
+    fn test_queue_redelivers_unacked_values_qc(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<TestResult, BoxedError> {
+      log_init();
+      if kvs.is_empty() {
+        return Ok(TestResult::discard())
+      }
+      let store = Self::build();
+
+      let space = "test_queue_redelivers_unacked_values_qc";
+      let config = QueueConfig { visibility_timeout_ms: 1, max_deliveries: 2, dead_letter_space: None };
+      try_as_any!(store.configure_queue(&space, &config));
+      for &(ref key, ref val) in &kvs {
+        try_as_any!(store.write(&space, &key, &val));
+      }
+
+      let first = try_as_any!(store.consume(&space, "first", &KeyFilter::All)).next();
+      thread::sleep(Duration::from_millis(2));
+      let second = try_as_any!(store.consume(&space, "second", &KeyFilter::All)).next();
+      debug!("First   : {:?}", first);
+      debug!("Second  : {:?}", second);
+
+      let stale_ack = match first {
+        Some((offset, _)) => try_as_any!(store.ack(&space, "first", offset)),
+        None => true,
+      };
+      Ok(TestResult::from_bool(first.is_some() && first == second && !stale_ack))
+    }
+
+    fn test_queue_dead_letters_nacked_values_qc(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<bool, BoxedError> {
+      log_init();
+      let store = Self::build();
+
+      let space = "test_queue_dead_letters_nacked_values_qc";
+      let dead_letters = "test_queue_dead_letters_nacked_values_qc/dead";
+      let config = QueueConfig { visibility_timeout_ms: 60000, max_deliveries: 3, dead_letter_space: Some(dead_letters.to_string()) };
+      try_as_any!(store.configure_queue(&space, &config));
+      for &(ref key, ref val) in &kvs {
+        try_as_any!(store.write(&space, &key, &val));
+      }
+
+      for (offset, _) in try_as_any!(store.consume(&space, "consumer", &KeyFilter::All)).take(kvs.len()) {
+        if !try_as_any!(store.nack(&space, "consumer", offset, "rejected")) {
+          return Ok(false)
+        }
+      }
+
+      let expected : Vec<_> = kvs.iter().enumerate()
+        .map(|(i, &(ref key, ref val))| (key.clone(), DeadLetter {
+              space: space.to_string(), offset: i as u64, deliveries: 1,
+              reason: "rejected".to_string(), content: val.clone() }))
+        .collect();
+      let actual : Vec<_> = try_as_any!(store.subscribe(&dead_letters, &KeyFilter::All, 0)).take(kvs.len())
+        .map(|(_, d)| (d.key, DeadLetter::from_bytes(&d.content).unwrap()))
+        .collect();
+      debug!("Got     : {:?}", actual);
+      debug!("Expected: {:?}", expected);
+      Ok(expected == actual)
+    }
+
+    fn test_queue_dead_letters_exhausted_values_qc(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<TestResult, BoxedError> {
+      log_init();
+      if kvs.is_empty() {
+        return Ok(TestResult::discard())
+      }
+      let store = Self::build();
+
+      let space = "test_queue_dead_letters_exhausted_values_qc";
+      let dead_letters = "test_queue_dead_letters_exhausted_values_qc/dead";
+      let config = QueueConfig { visibility_timeout_ms: 1, max_deliveries: 1, dead_letter_space: Some(dead_letters.to_string()) };
+      try_as_any!(store.configure_queue(&space, &config));
+      let (ref key, ref val) = kvs[0];
+      try_as_any!(store.write(&space, &key, &val));
+      try_as_any!(store.write(&space, &key, &val));
+
+      let first = try_as_any!(store.consume(&space, "first", &KeyFilter::All)).next();
+      thread::sleep(Duration::from_millis(2));
+      // The first message has used up its only delivery, so gets skipped.
+      let second = try_as_any!(store.consume(&space, "second", &KeyFilter::All)).next();
+      let dead = try_as_any!(store.subscribe(&dead_letters, &KeyFilter::All, 0)).next()
+        .map(|(_, d)| DeadLetter::from_bytes(&d.content).unwrap());
+
+      debug!("First   : {:?}", first);
+      debug!("Second  : {:?}", second);
+      debug!("Dead    : {:?}", dead);
+      Ok(TestResult::from_bool(first.map(|(o, _)| o) == Some(0) && second.map(|(o, _)| o) == Some(1) &&
+          dead.map(|d| (d.offset, d.deliveries)) == Some((0, 1))))
+    }
   }
 
   macro_rules! build_store_tests {
@@ -160,6 +574,11 @@ pub mod test {
         ::quickcheck::quickcheck($t::test_put_read_values_qc as fn (kvs: Vec<(Vec<u8>, Vec<u8>)>, needle_sel: usize) -> Result<TestResult, Box<::std::any::Any+Send>>)
       }
 
+      #[test]
+      fn test_poll_next_does_not_wait_qc() {
+        ::quickcheck::quickcheck($t::test_poll_next_does_not_wait_qc as fn(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<bool, Box<::std::any::Any+Send>>)
+      }
+
       #[test]
       fn test_put_subscribe_values_qc() {
         ::quickcheck::quickcheck($t::test_put_subscribe_values_qc as fn(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<bool, Box<::std::any::Any+Send>>)
@@ -170,10 +589,80 @@ pub mod test {
         ::quickcheck::quickcheck($t::test_put_subscribe_values_per_space as fn (kvs: Vec<(bool, Vec<u8>, Vec<u8>)>) -> Result<bool, Box<::std::any::Any+Send>>)
       }
 
+      #[test]
+      fn test_put_subscribe_filtered_values_qc() {
+        ::quickcheck::quickcheck($t::test_put_subscribe_filtered_values_qc as fn(kvs: Vec<(Vec<u8>, Vec<u8>)>, prefix: Vec<u8>) -> Result<bool, Box<::std::any::Any+Send>>)
+      }
+
+      #[test]
+      fn test_put_subscribe_range_values_qc() {
+        ::quickcheck::quickcheck($t::test_put_subscribe_range_values_qc as fn(kvs: Vec<(Vec<u8>, Vec<u8>)>, start: Vec<u8>, end: Vec<u8>) -> Result<bool, Box<::std::any::Any+Send>>)
+      }
+
+      #[test]
+      fn test_put_subscribe_pattern_values_qc() {
+        ::quickcheck::quickcheck($t::test_put_subscribe_pattern_values_qc as fn(kvs: Vec<(bool, Vec<u8>, Vec<u8>)>) -> Result<bool, Box<::std::any::Any+Send>>)
+      }
+
+      #[test]
+      fn test_subscribe_from_offset_qc() {
+        ::quickcheck::quickcheck($t::test_subscribe_from_offset_qc as fn(kvs: Vec<(Vec<u8>, Vec<u8>)>, skip: usize) -> Result<bool, Box<::std::any::Any+Send>>)
+      }
+
+      #[test]
+      fn test_catch_up_ends_at_head_qc() {
+        ::quickcheck::quickcheck($t::test_catch_up_ends_at_head_qc as fn(kvs: Vec<(Vec<u8>, Vec<u8>)>, later: Vec<(Vec<u8>, Vec<u8>)>) -> Result<bool, Box<::std::any::Any+Send>>)
+      }
+
+      #[test]
+      fn test_subscribe_gives_up_when_idle_qc() {
+        ::quickcheck::quickcheck($t::test_subscribe_gives_up_when_idle_qc as fn(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<bool, Box<::std::any::Any+Send>>)
+      }
+
+      #[test]
+      fn test_conditional_write_qc() {
+        ::quickcheck::quickcheck($t::test_conditional_write_qc as fn(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<TestResult, Box<::std::any::Any+Send>>)
+      }
+
+      #[test]
+      fn test_write_all_values_qc() {
+        ::quickcheck::quickcheck($t::test_write_all_values_qc as fn(kvs: Vec<(bool, Vec<u8>, Vec<u8>)>) -> Result<bool, Box<::std::any::Any+Send>>)
+      }
+
+      #[test]
+      fn test_producer_writes_once_qc() {
+        ::quickcheck::quickcheck($t::test_producer_writes_once_qc as fn(kvs: Vec<(Vec<u8>, Vec<u8>)>, producer: u64) -> Result<bool, Box<::std::any::Any+Send>>)
+      }
+
+      #[test]
+      fn test_spaces_summarise_writes_qc() {
+        ::quickcheck::quickcheck($t::test_spaces_summarise_writes_qc as fn(kvs: Vec<(bool, Vec<u8>, Vec<u8>)>) -> Result<bool, Box<::std::any::Any+Send>>)
+      }
+
       #[test]
       fn test_put_async_subscribe_values_qc() {
         ::quickcheck::quickcheck($t::test_put_async_subscribe_values_qc as fn(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<bool, Box<::std::any::Any+Send>>)
       }
+
+      #[test]
+      fn test_queue_consume_acked_values_qc() {
+        ::quickcheck::quickcheck($t::test_queue_consume_acked_values_qc as fn(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<bool, Box<::std::any::Any+Send>>)
+      }
+
+      #[test]
+      fn test_queue_redelivers_unacked_values_qc() {
+        ::quickcheck::quickcheck($t::test_queue_redelivers_unacked_values_qc as fn(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<TestResult, Box<::std::any::Any+Send>>)
+      }
+
+      #[test]
+      fn test_queue_dead_letters_nacked_values_qc() {
+        ::quickcheck::quickcheck($t::test_queue_dead_letters_nacked_values_qc as fn(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<bool, Box<::std::any::Any+Send>>)
+      }
+
+      #[test]
+      fn test_queue_dead_letters_exhausted_values_qc() {
+        ::quickcheck::quickcheck($t::test_queue_dead_letters_exhausted_values_qc as fn(kvs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<TestResult, Box<::std::any::Any+Send>>)
+      }
     }
   }
 }
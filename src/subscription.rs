@@ -0,0 +1,125 @@
+use std::sync::{Arc,Mutex};
+
+struct CreditState {
+  available: u64,
+  closed: bool,
+}
+
+type Waker = Box<Fn() + Send>;
+
+/// The number of deliveries a subscriber has said it is willing to accept.
+#[derive(Clone)]
+pub struct Credit {
+  state: Arc<Mutex<CreditState>>,
+  waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl Credit {
+  pub fn new() -> Credit {
+    Credit {
+      state: Arc::new(Mutex::new(CreditState { available: 0, closed: false })),
+      waker: Arc::new(Mutex::new(None)),
+    }
+  }
+
+  /// Has `waker` called whenever credit is granted or closed, so whatever
+  /// feeds the subscription can pick up where it left off.
+  pub fn on_change<F: Fn() + Send + 'static>(&self, waker: F) {
+    *self.waker.lock().unwrap() = Some(Box::new(waker));
+  }
+
+  pub fn grant(&self, amount: u64) {
+    {
+      let mut state = self.state.lock().unwrap();
+      state.available += amount;
+      trace!("Credit granted: +{} → {}", amount, state.available);
+    }
+    self.wake();
+  }
+
+  /// Takes a unit of credit if there is one. Returns false if there is
+  /// none, or once the credit has been closed.
+  pub fn try_take(&self) -> bool {
+    let mut state = self.state.lock().unwrap();
+    if state.closed || state.available == 0 {
+      return false
+    }
+    state.available -= 1;
+    true
+  }
+
+  pub fn available(&self) -> u64 {
+    let state = self.state.lock().unwrap();
+    if state.closed { 0 } else { state.available }
+  }
+
+  /// Returns true if this call was the one that closed the credit; whoever
+  /// does so is responsible for ending the stream.
+  pub fn close(&self) -> bool {
+    let was_open = {
+      let mut state = self.state.lock().unwrap();
+      let was_open = !state.closed;
+      state.closed = true;
+      was_open
+    };
+    if was_open {
+      self.wake();
+    }
+    was_open
+  }
+
+  pub fn is_closed(&self) -> bool {
+    self.state.lock().unwrap().closed
+  }
+
+  // The waker may well take locks of its own, so we never call it with
+  // ours held.
+  fn wake(&self) {
+    if let Some(ref waker) = *self.waker.lock().unwrap() {
+      waker()
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::Credit;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  #[test]
+  fn try_take_needs_a_grant() {
+    let credit = Credit::new();
+    assert_eq!(credit.try_take(), false);
+    credit.grant(2);
+    assert_eq!((credit.try_take(), credit.try_take(), credit.try_take()), (true, true, false));
+  }
+
+  #[test]
+  fn try_take_fails_once_closed() {
+    let credit = Credit::new();
+    credit.grant(1);
+    credit.close();
+    assert_eq!((credit.available(), credit.try_take()), (0, false));
+  }
+
+  #[test]
+  fn grants_and_closing_wake_the_subscription() {
+    let credit = Credit::new();
+    let woken = Arc::new(AtomicUsize::new(0));
+    {
+      let woken = woken.clone();
+      credit.on_change(move || { woken.fetch_add(1, Ordering::SeqCst); });
+    }
+    credit.grant(1);
+    credit.close();
+    credit.close();
+    assert_eq!(woken.load(Ordering::SeqCst), 2);
+  }
+
+  #[test]
+  fn only_first_close_reports_closing() {
+    let credit = Credit::new();
+    assert_eq!((credit.close(), credit.close()), (true, false));
+  }
+}
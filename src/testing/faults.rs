@@ -0,0 +1,219 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{self, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Something that can go wrong with a connection. Apart from `Partition`,
+/// faults only affect what's written to the stream they're applied to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+  /// Writes appear to succeed, but nothing is sent.
+  Drop,
+  /// Each write waits this long before going out.
+  Delay(Duration),
+  /// Lets this many more bytes through, then hangs up: writes fail from
+  /// then on, and reads see the end of the stream.
+  Truncate(usize),
+  /// Inverts every bit written.
+  Corrupt,
+  /// Reads and writes both fail, as if the peer could no longer be reached.
+  Partition,
+}
+
+/// Switches faults on and off for every stream sharing it.
+#[derive(Debug, Clone)]
+pub struct Faults {
+  current: Arc<Mutex<Option<Fault>>>,
+}
+
+impl Faults {
+  pub fn new() -> Faults {
+    Faults { current: Arc::new(Mutex::new(None)) }
+  }
+
+  pub fn set(&self, fault: Fault) {
+    debug!("Injecting fault: {:?}", fault);
+    *self.current.lock().unwrap() = Some(fault);
+  }
+
+  pub fn clear(&self) {
+    *self.current.lock().unwrap() = None;
+  }
+
+  pub fn current(&self) -> Option<Fault> {
+    self.current.lock().unwrap().clone()
+  }
+
+  // How much of a write of `len` bytes a truncated stream lets through.
+  fn allowance(&self, len: usize) -> usize {
+    let mut current = self.current.lock().unwrap();
+    match *current {
+      Some(Fault::Truncate(ref mut remaining)) => {
+        let allowed = cmp::min(*remaining, len);
+        *remaining -= allowed;
+        allowed
+      },
+      _ => len,
+    }
+  }
+}
+
+fn partitioned() -> io::Error {
+  io::Error::new(ErrorKind::ConnectionAborted, "Partitioned by fault injection")
+}
+
+fn truncated() -> io::Error {
+  io::Error::new(ErrorKind::BrokenPipe, "Truncated by fault injection")
+}
+
+/// Wraps a stream, and does to it whatever its `Faults` say.
+#[derive(Debug)]
+pub struct FaultyStream<S> {
+  inner: S,
+  faults: Faults,
+}
+
+impl<S: Read + Write> FaultyStream<S> {
+  pub fn new(inner: S, faults: Faults) -> FaultyStream<S> {
+    FaultyStream { inner: inner, faults: faults }
+  }
+
+  pub fn get_ref(&self) -> &S {
+    &self.inner
+  }
+}
+
+impl<S: Read + Write> Read for FaultyStream<S> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match self.faults.current() {
+      Some(Fault::Partition) => Err(partitioned()),
+      Some(Fault::Truncate(0)) => Ok(0),
+      _ => self.inner.read(buf),
+    }
+  }
+}
+
+impl<S: Read + Write> Write for FaultyStream<S> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    match self.faults.current() {
+      None => self.inner.write(buf),
+      Some(Fault::Drop) => Ok(buf.len()),
+      Some(Fault::Delay(delay)) => {
+        thread::sleep(delay);
+        self.inner.write(buf)
+      },
+      Some(Fault::Truncate(_)) => match self.faults.allowance(buf.len()) {
+        0 => Err(truncated()),
+        allowed => self.inner.write(&buf[..allowed]),
+      },
+      Some(Fault::Corrupt) => {
+        let inverted : Vec<u8> = buf.iter().map(|b| !b).collect();
+        self.inner.write(&inverted)
+      },
+      Some(Fault::Partition) => Err(partitioned()),
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match self.faults.current() {
+      Some(Fault::Partition) => Err(partitioned()),
+      Some(Fault::Truncate(0)) => Err(truncated()),
+      _ => self.inner.flush(),
+    }
+  }
+}
+
+// Both ends of each connection we're forwarding, by the order we accepted
+// them in.
+type Streams = Arc<Mutex<HashMap<usize, (TcpStream, TcpStream)>>>;
+
+/// Forwards connections to `target`, applying its faults to whatever is
+/// sent in either direction. Once a connection fails, it's hung up on at
+/// both ends.
+pub struct Proxy {
+  local_addr: SocketAddr,
+  faults: Faults,
+  stopped: Arc<AtomicBool>,
+  streams: Streams,
+}
+
+impl Proxy {
+  pub fn start(target: SocketAddr) -> io::Result<Proxy> {
+    let listener = try!(TcpListener::bind("127.0.0.1:0"));
+    let local_addr = try!(listener.local_addr());
+    let proxy = Proxy {
+      local_addr: local_addr,
+      faults: Faults::new(),
+      stopped: Arc::new(AtomicBool::new(false)),
+      streams: Arc::new(Mutex::new(HashMap::new())),
+    };
+    let faults = proxy.faults.clone();
+    let stopped = proxy.stopped.clone();
+    let streams = proxy.streams.clone();
+    try!(thread::Builder::new().name(format!("proxy-{}", local_addr)).spawn(move || {
+      for (id, client) in listener.incoming().enumerate() {
+        if stopped.load(Ordering::SeqCst) {
+          break
+        }
+        match client.and_then(|client| connect(id, client, target, &faults, &streams)) {
+          Ok(()) => (),
+          Err(e) => warn!("Proxy {} could not forward connection: {}", local_addr, e),
+        }
+      }
+    }));
+    Ok(proxy)
+  }
+
+  pub fn local_addr(&self) -> SocketAddr {
+    self.local_addr
+  }
+
+  /// The URL for `space`, by way of this proxy.
+  pub fn url(&self, space: &str) -> String {
+    format!("yak://{}/{}", self.local_addr, space)
+  }
+
+  pub fn faults(&self) -> &Faults {
+    &self.faults
+  }
+}
+
+impl Drop for Proxy {
+  fn drop(&mut self) {
+    self.stopped.store(true, Ordering::SeqCst);
+    // Wakes the listener, so it notices.
+    let _ = TcpStream::connect(self.local_addr);
+    for (_, (client, server)) in self.streams.lock().unwrap().drain() {
+      let _ = client.shutdown(net::Shutdown::Both);
+      let _ = server.shutdown(net::Shutdown::Both);
+    }
+  }
+}
+
+fn connect(id: usize, client: TcpStream, target: SocketAddr, faults: &Faults, streams: &Streams) -> io::Result<()> {
+  let server = try!(TcpStream::connect(target));
+  streams.lock().unwrap().insert(id, (try!(client.try_clone()), try!(server.try_clone())));
+  try!(pump(id, try!(client.try_clone()), FaultyStream::new(try!(server.try_clone()), faults.clone()), streams));
+  try!(pump(id, server, FaultyStream::new(client, faults.clone()), streams));
+  Ok(())
+}
+
+// Whichever direction finishes first hangs up on both ends, so there's
+// nothing left for us to shut down once either has.
+fn pump(id: usize, mut from: TcpStream, mut to: FaultyStream<TcpStream>, streams: &Streams) -> io::Result<()> {
+  let name = format!("pump-{}", try!(from.peer_addr()));
+  let streams = streams.clone();
+  try!(thread::Builder::new().name(name).spawn(move || {
+    if let Err(e) = io::copy(&mut from, &mut to) {
+      debug!("Proxied connection failed: {}", e);
+    }
+    let _ = from.shutdown(net::Shutdown::Both);
+    let _ = to.get_ref().shutdown(net::Shutdown::Both);
+    streams.lock().unwrap().remove(&id);
+  }));
+  Ok(())
+}
@@ -0,0 +1,268 @@
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A sequential specification of some part of the system, which a history
+/// of concurrent operations has to be explicable by.
+pub trait Model: Clone + Eq + Hash {
+  type Op: fmt::Debug + Clone;
+  type Ret: fmt::Debug + Clone;
+
+  /// The state after `op` returned `ret` from this one, or None if it could
+  /// not have done. `ret` is None when we never found out how the operation
+  /// went, in which case any outcome will do.
+  fn step(&self, op: &Self::Op, ret: Option<&Self::Ret>) -> Option<Self>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LogOp {
+  Write { key: Vec<u8>, value: Vec<u8> },
+  Read { key: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogRet {
+  Written,
+  Values(Vec<Vec<u8>>),
+}
+
+/// A single space, as the README describes it: writes append to a log, and
+/// reading a key returns every value written to it, oldest first.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Log {
+  values: BTreeMap<Vec<u8>, Vec<Vec<u8>>>,
+}
+
+impl Model for Log {
+  type Op = LogOp;
+  type Ret = LogRet;
+
+  fn step(&self, op: &LogOp, ret: Option<&LogRet>) -> Option<Log> {
+    match (op, ret) {
+      (&LogOp::Write { ref key, ref value }, None) |
+      (&LogOp::Write { ref key, ref value }, Some(&LogRet::Written)) => {
+        let mut next = self.clone();
+        next.values.entry(key.clone()).or_insert_with(Vec::new).push(value.clone());
+        Some(next)
+      },
+      (&LogOp::Read { .. }, None) => Some(self.clone()),
+      (&LogOp::Read { ref key }, Some(&LogRet::Values(ref values))) => {
+        let written = self.values.get(key).map(|values| &values[..]).unwrap_or(&[]);
+        if &values[..] == written { Some(self.clone()) } else { None }
+      },
+      _ => None,
+    }
+  }
+}
+
+/// One operation, and when it happened relative to the start of the history.
+#[derive(Debug, Clone)]
+pub struct Entry<Op, Ret> {
+  pub process: usize,
+  pub op: Op,
+  pub invoked: Duration,
+  /// When the operation returned, and what with; None if we never found
+  /// out, say because it timed out or the connection dropped.
+  pub completed: Option<(Duration, Ret)>,
+}
+
+/// Tells how long it's been since a history started.
+pub type Clock = Arc<Fn() -> Duration + Send + Sync>;
+
+/// Records what concurrent clients asked for, and got, and when.
+pub struct History<M: Model> {
+  clock: Clock,
+  entries: Arc<Mutex<Vec<Entry<M::Op, M::Ret>>>>,
+}
+
+impl<M: Model> Clone for History<M> {
+  fn clone(&self) -> History<M> {
+    History { clock: self.clock.clone(), entries: self.entries.clone() }
+  }
+}
+
+/// An operation that has been started, but not yet completed. Dropping it
+/// without calling `complete` records that we don't know how it went.
+pub struct Invocation<M: Model> {
+  history: History<M>,
+  index: usize,
+}
+
+impl<M: Model> History<M> {
+  /// Times operations by the system clock.
+  pub fn new() -> History<M> {
+    let start = Instant::now();
+    History::with_clock(Arc::new(move || start.elapsed()))
+  }
+
+  /// Times operations by `clock`, say one that's simulated.
+  pub fn with_clock(clock: Clock) -> History<M> {
+    History { clock: clock, entries: Arc::new(Mutex::new(Vec::new())) }
+  }
+
+  /// Call immediately before sending the request for `op`.
+  pub fn invoke(&self, process: usize, op: M::Op) -> Invocation<M> {
+    let invoked = (self.clock)();
+    let mut entries = self.entries.lock().unwrap();
+    entries.push(Entry { process: process, op: op, invoked: invoked, completed: None });
+    Invocation { history: self.clone(), index: entries.len() - 1 }
+  }
+
+  pub fn entries(&self) -> Vec<Entry<M::Op, M::Ret>> {
+    self.entries.lock().unwrap().clone()
+  }
+
+  pub fn check(&self, initial: M) -> Result<(), NotLinearizable> {
+    check(initial, &self.entries())
+  }
+}
+
+impl<M: Model> Invocation<M> {
+  /// Where this is in the history.
+  pub fn index(&self) -> usize {
+    self.index
+  }
+
+  /// Call as soon as the response for the operation arrives.
+  pub fn complete(self, ret: M::Ret) {
+    let completed = (self.history.clock)();
+    self.history.entries.lock().unwrap()[self.index].completed = Some((completed, ret));
+  }
+}
+
+/// No order of the operations both respected real time and made sense to
+/// the model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotLinearizable {
+  /// The longest valid ordering we found, as indices into the history.
+  pub longest: Vec<usize>,
+  /// Completed operations that it could not be extended with.
+  pub unplaced: Vec<usize>,
+}
+
+impl fmt::Display for NotLinearizable {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    write!(f, "History is not linearizable: at best ordered {:?}, leaving {:?}", self.longest, self.unplaced)
+  }
+}
+
+impl Error for NotLinearizable {
+  fn description(&self) -> &str {
+    "history is not linearizable"
+  }
+}
+
+/// Searches for an order of `entries` that respects real time (an operation
+/// that completed before another was invoked comes first) and in which each
+/// returns what `initial` says it should. Operations that never completed may
+/// be placed anywhere after their invocation, or left out altogether.
+///
+/// This is the search from Wing & Gong, remembering which combinations of
+/// operations placed and model state we've already explored, as per Lowe.
+pub fn check<M: Model>(initial: M, entries: &[Entry<M::Op, M::Ret>]) -> Result<(), NotLinearizable> {
+  let required = entries.iter().filter(|e| e.completed.is_some()).count();
+  let mut seen : HashSet<(Vec<bool>, M)> = HashSet::new();
+  let mut stack = vec![(vec![false; entries.len()], initial, Vec::new())];
+  let mut longest = Vec::new();
+
+  while let Some((placed, state, order)) = stack.pop() {
+    let placed_required = order.iter().filter(|&&i| entries[i].completed.is_some()).count();
+    if placed_required == required {
+      return Ok(())
+    }
+    if order.len() > longest.len() {
+      longest = order.clone();
+    }
+
+    // Nothing can come after an operation that's yet to be placed, if it
+    // was invoked after that operation completed.
+    let horizon = entries.iter().enumerate()
+      .filter(|&(i, _)| !placed[i])
+      .filter_map(|(_, e)| e.completed.as_ref().map(|&(at, _)| at))
+      .min();
+    for (i, entry) in entries.iter().enumerate() {
+      if placed[i] || horizon.map(|h| entry.invoked > h).unwrap_or(false) {
+        continue
+      }
+      let next = match state.step(&entry.op, entry.completed.as_ref().map(|&(_, ref ret)| ret)) {
+        Some(next) => next,
+        None => continue,
+      };
+      let mut now_placed = placed.clone();
+      now_placed[i] = true;
+      if seen.insert((now_placed.clone(), next.clone())) {
+        let mut order = order.clone();
+        order.push(i);
+        stack.push((now_placed, next, order));
+      }
+    }
+  }
+
+  let unplaced = (0..entries.len()).filter(|i| entries[*i].completed.is_some() && !longest.contains(i)).collect();
+  Err(NotLinearizable { longest: longest, unplaced: unplaced })
+}
+
+#[cfg(test)]
+mod test {
+  use super::{check, Entry, Log, LogOp, LogRet};
+  use std::time::Duration;
+
+  fn write(process: usize, value: &str, invoked: u64, completed: Option<u64>) -> Entry<LogOp, LogRet> {
+    Entry {
+      process: process,
+      op: LogOp::Write { key: b"k".to_vec(), value: value.as_bytes().to_vec() },
+      invoked: Duration::from_millis(invoked),
+      completed: completed.map(|at| (Duration::from_millis(at), LogRet::Written)),
+    }
+  }
+
+  fn read(process: usize, values: &[&str], invoked: u64, completed: u64) -> Entry<LogOp, LogRet> {
+    Entry {
+      process: process,
+      op: LogOp::Read { key: b"k".to_vec() },
+      invoked: Duration::from_millis(invoked),
+      completed: Some((Duration::from_millis(completed),
+        LogRet::Values(values.iter().map(|v| v.as_bytes().to_vec()).collect()))),
+    }
+  }
+
+  #[test]
+  fn sequential_history_is_linearizable() {
+    let history = vec![write(0, "a", 0, Some(1)), read(1, &["a"], 2, 3), write(0, "b", 4, Some(5)), read(1, &["a", "b"], 6, 7)];
+    assert_eq!(check(Log::default(), &history), Ok(()));
+  }
+
+  #[test]
+  fn read_after_completed_write_must_see_it() {
+    let history = vec![write(0, "a", 0, Some(1)), read(1, &[], 2, 3)];
+    let err = check(Log::default(), &history).unwrap_err();
+    assert_eq!(err.unplaced, vec![1]);
+  }
+
+  #[test]
+  fn concurrent_read_may_see_either_state() {
+    let before = vec![write(0, "a", 0, Some(10)), read(1, &[], 1, 2)];
+    let after = vec![write(0, "a", 0, Some(10)), read(1, &["a"], 1, 2)];
+    assert_eq!((check(Log::default(), &before), check(Log::default(), &after)), (Ok(()), Ok(())));
+  }
+
+  #[test]
+  fn incomplete_write_may_or_may_not_have_happened() {
+    let missing = vec![write(0, "a", 0, None), read(1, &[], 5, 6)];
+    let present = vec![write(0, "a", 0, None), read(1, &["a"], 5, 6)];
+    let vanished = vec![write(0, "a", 0, None), read(1, &["a"], 5, 6), read(1, &[], 7, 8)];
+    assert_eq!(check(Log::default(), &missing), Ok(()));
+    assert_eq!(check(Log::default(), &present), Ok(()));
+    assert!(check(Log::default(), &vanished).is_err());
+  }
+
+  #[test]
+  fn readers_must_agree_on_order() {
+    let history = vec![write(0, "a", 0, Some(10)), write(1, "b", 0, Some(10)),
+      read(2, &["a", "b"], 1, 2), read(3, &["b", "a"], 3, 4)];
+    assert!(check(Log::default(), &history).is_err());
+  }
+}
@@ -0,0 +1,115 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use rand::{self, Rng};
+
+use server::{Server, RunningServer, ServerError};
+
+pub mod history;
+pub mod faults;
+pub mod simulation;
+
+use self::faults::{Faults, Proxy};
+
+// Tests only ever have a handful of clients, so there's no call for the
+// usual number of threads per node.
+const TEST_SESSION_WORKERS : usize = 4;
+const TEST_SUBSCRIPTION_WORKERS : usize = 2;
+
+/// A chain of servers listening on ephemeral ports on localhost, each with a
+/// store in a fresh temporary directory. Dropping it shuts every node down
+/// and removes their stores.
+pub struct Chain {
+  // Head first.
+  nodes: Vec<RunningServer>,
+  // Between each node and the next, when faults can be injected.
+  links: Vec<Proxy>,
+  dirs: Vec<PathBuf>,
+}
+
+impl Chain {
+  /// Starts `length` nodes, from the tail back, so each one's successor is
+  /// already listening when it connects.
+  pub fn start(length: usize) -> Result<Chain, ServerError> {
+    Chain::start_with(length, false)
+  }
+
+  /// As `start`, but with each node talking to the next through a `Proxy`,
+  /// so that faults can be injected with `link`.
+  pub fn start_faulty(length: usize) -> Result<Chain, ServerError> {
+    Chain::start_with(length, true)
+  }
+
+  fn start_with(length: usize, faulty: bool) -> Result<Chain, ServerError> {
+    assert!(length > 0, "A chain needs at least one node");
+    let name = rand::thread_rng().gen_ascii_chars().take(12).collect::<String>();
+    let mut chain = Chain { nodes: Vec::new(), links: Vec::new(), dirs: Vec::new() };
+    for n in (0..length).rev() {
+      let dir = env::temp_dir().join(format!("yak-chain-{}-{}", name, n));
+      try!(fs::create_dir_all(&dir));
+      chain.dirs.push(dir.clone());
+      let mut server = Server::new(&dir)
+        .session_workers(TEST_SESSION_WORKERS)
+        .subscription_workers(TEST_SUBSCRIPTION_WORKERS);
+      let next = chain.nodes.first().map(|next| next.local_addr());
+      if let Some(next) = next {
+        let next = if faulty {
+          let link = try!(Proxy::start(next));
+          let addr = link.local_addr();
+          chain.links.insert(0, link);
+          addr
+        } else {
+          next
+        };
+        server = server.next(&next.to_string());
+      }
+      let node = try!(server.start());
+      chain.nodes.insert(0, node);
+    }
+    Ok(chain)
+  }
+
+  pub fn head(&self) -> &RunningServer {
+    &self.nodes[0]
+  }
+
+  pub fn tail(&self) -> &RunningServer {
+    &self.nodes[self.nodes.len() - 1]
+  }
+
+  pub fn nodes(&self) -> &[RunningServer] {
+    &self.nodes
+  }
+
+  pub fn head_url(&self, space: &str) -> String {
+    self.head().url(space)
+  }
+
+  pub fn tail_url(&self, space: &str) -> String {
+    self.tail().url(space)
+  }
+
+  /// The faults on the link from node `n` to the one after it; only for
+  /// chains started with `start_faulty`.
+  pub fn link(&self, n: usize) -> &Faults {
+    self.links[n].faults()
+  }
+}
+
+impl Drop for Chain {
+  fn drop(&mut self) {
+    // From the head down, so nothing is forwarded to a node that's gone.
+    for node in self.nodes.drain(..) {
+      let addr = node.local_addr();
+      if let Err(e) = node.shutdown() {
+        warn!("Node {} did not shut down cleanly: {}", addr, e);
+      }
+    }
+    for dir in &self.dirs {
+      if let Err(e) = fs::remove_dir_all(dir) {
+        warn!("Could not remove store {}: {}", dir.display(), e);
+      }
+    }
+  }
+}
@@ -0,0 +1,438 @@
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::env;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use rand::{self, Rng, SeedableRng, XorShiftRng};
+use yak_client::{self, WireProtocol, Request, Response, Operation, SeqNo};
+
+use dispatch::Dispatcher;
+use metrics::Metrics;
+use server::{Session, DownStream, Peers, ServerError};
+use sqlite_store::{self, SqliteStore};
+use store::Store;
+use super::history::{self, History, Invocation, Log, LogOp, LogRet};
+
+const SPACE : &'static str = "simulated";
+// Each packet takes between one and this many simulated milliseconds to
+// arrive.
+const MAX_LATENCY_MS : u64 = 10;
+const NANOS_PER_MS : usize = 1000000;
+
+// Which end of a connection: the one that connected (a client, or the node
+// before), or the node that accepted it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Side {
+  Connector,
+  Acceptor,
+}
+
+type End = (usize, Side);
+
+fn other(end: End) -> End {
+  match end {
+    (conn, Side::Connector) => (conn, Side::Acceptor),
+    (conn, Side::Acceptor) => (conn, Side::Connector),
+  }
+}
+
+struct Packet {
+  to: End,
+  arrives: u64,
+  bytes: Vec<u8>,
+}
+
+struct Network {
+  now: u64,
+  // Simulated time in nanoseconds, for the stores and the history: `now`,
+  // plus a nanosecond for each thing that's happened since it moved on, so
+  // that anything ordered in the simulation is ordered by the clock too.
+  clock: Arc<AtomicUsize>,
+  rng: XorShiftRng,
+  // In the order they were sent.
+  in_flight: Vec<Packet>,
+  inboxes: BTreeMap<End, Vec<u8>>,
+  // When the last packet to each end arrives; like TCP, we never reorder.
+  last_arrival: BTreeMap<End, u64>,
+  // The node that accepted each connection.
+  acceptors: Vec<usize>,
+}
+
+impl Network {
+  fn connect(&mut self, node: usize) -> usize {
+    self.acceptors.push(node);
+    self.acceptors.len() - 1
+  }
+
+  fn transmit(&mut self, to: End, bytes: Vec<u8>) {
+    let latency = self.rng.gen_range(1, MAX_LATENCY_MS + 1);
+    let arrives = cmp::max(self.now + latency, self.last_arrival.get(&to).cloned().unwrap_or(0));
+    self.last_arrival.insert(to, arrives);
+    self.in_flight.push(Packet { to: to, arrives: arrives, bytes: bytes });
+  }
+
+  fn take_inbox(&mut self, end: End) -> Vec<u8> {
+    self.inboxes.get_mut(&end).map(|inbox| mem::replace(inbox, Vec::new())).unwrap_or_else(Vec::new)
+  }
+
+  fn advance_to(&mut self, at: u64) {
+    self.now = at;
+    self.clock.store(at as usize * NANOS_PER_MS, Ordering::SeqCst);
+  }
+}
+
+/// One end of a simulated connection. Reading from it runs the rest of the
+/// simulation until something arrives.
+struct SimStream {
+  world: Weak<World>,
+  end: End,
+}
+
+impl Read for SimStream {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let world = match self.world.upgrade() {
+      Some(world) => world,
+      None => return Ok(0),
+    };
+    loop {
+      {
+        let mut network = world.network.lock().unwrap();
+        if let Some(inbox) = network.inboxes.get_mut(&self.end) {
+          if !inbox.is_empty() {
+            let n = cmp::min(inbox.len(), buf.len());
+            buf[..n].copy_from_slice(&inbox[..n]);
+            inbox.drain(..n);
+            return Ok(n)
+          }
+        }
+      }
+      if !step(&world) {
+        return Err(io::Error::new(ErrorKind::TimedOut, "Simulation ran out of things to do"))
+      }
+    }
+  }
+}
+
+impl Write for SimStream {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if let Some(world) = self.world.upgrade() {
+      world.network.lock().unwrap().transmit(other(self.end), buf.to_vec());
+    }
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+struct SimNode {
+  index: usize,
+  store: SqliteStore,
+  dispatcher: Dispatcher,
+  metrics: Metrics,
+  next: Option<DownStream<SimStream>>,
+  peers: Peers,
+  sessions: BTreeMap<usize, Session<String, SimStream, SqliteStore>>,
+  // Connections we've hung up on.
+  closed: BTreeSet<usize>,
+}
+
+impl SimNode {
+  fn process(&mut self, world: &Arc<World>, conn: usize, bytes: &[u8]) {
+    if self.closed.contains(&conn) {
+      return
+    }
+    if !self.sessions.contains_key(&conn) {
+      let writer = SimStream { world: Arc::downgrade(world), end: (conn, Side::Acceptor) };
+      let session = Session::new(format!("node{}/{}", self.index, conn), &format!("node{}", self.index), writer,
+        self.store.clone(), self.next.clone(), self.dispatcher.clone(), self.peers.clone(), self.metrics.clone());
+      self.sessions.insert(conn, session);
+    }
+    let failed = match self.sessions.get_mut(&conn).unwrap().feed(bytes) {
+      Ok(()) => false,
+      Err(e) => {
+        debug!("node{}/{}: session failed: {}", self.index, conn, e);
+        true
+      },
+    };
+    if failed {
+      if let Some(mut session) = self.sessions.remove(&conn) {
+        session.close();
+      }
+      self.closed.insert(conn);
+    }
+  }
+}
+
+struct SimClient {
+  index: usize,
+  // Writes go to the head, and reads to the tail.
+  head: usize,
+  tail: usize,
+  script: VecDeque<LogOp>,
+  next_seq: SeqNo,
+  // Clients wait for each response before sending the next request.
+  outstanding: Option<(SeqNo, usize, Invocation<Log>)>,
+  input: Vec<u8>,
+}
+
+impl SimClient {
+  fn send(&mut self, network: &mut Network, history: &History<Log>) {
+    let op = match self.script.pop_front() {
+      Some(op) => op,
+      None => return,
+    };
+    let seq = self.next_seq;
+    self.next_seq += 1;
+    let (conn, operation) = match op {
+      LogOp::Write { ref key, ref value } =>
+        (self.head, Operation::Write { key: key.clone(), value: value.clone(), expected: None, producer: None }),
+      LogOp::Read { ref key } => (self.tail, Operation::Read { key: key.clone() }),
+    };
+    let req = Request { sequence: seq, space: SPACE.to_string(), deadline: None, operation: operation };
+    let bytes = yak_client::encode_packed(&req).expect("encode request");
+    self.outstanding = Some((seq, conn, history.invoke(self.index, op)));
+    network.transmit((conn, Side::Acceptor), bytes);
+  }
+
+  fn receive(&mut self, network: &mut Network) {
+    let conn = match self.outstanding {
+      Some((_, conn, _)) => conn,
+      None => return,
+    };
+    self.input.extend(network.take_inbox((conn, Side::Connector)));
+    loop {
+      let (resp, used) = match yak_client::decode_partial::<Response>(&self.input) {
+        Ok(Some(decoded)) => decoded,
+        Ok(None) => return,
+        Err(e) => {
+          warn!("client{}: could not decode response: {}", self.index, e);
+          self.input.clear();
+          return
+        },
+      };
+      self.input.drain(..used);
+      // Anything but the answer we were waiting for leaves the outcome
+      // unknown.
+      if let Some((seq, _, invocation)) = self.outstanding.take() {
+        match outcome(seq, resp) {
+          Some(ret) => invocation.complete(ret),
+          None => warn!("client{}: no usable response to request {}", self.index, seq),
+        }
+      }
+    }
+  }
+}
+
+fn outcome(seq: SeqNo, resp: Response) -> Option<LogRet> {
+  match resp {
+    Response::Okay(s) => if s == seq { Some(LogRet::Written) } else { None },
+    Response::OkayData(s, data) =>
+      if s == seq { Some(LogRet::Values(data.into_iter().map(|d| d.content).collect())) } else { None },
+    _ => None,
+  }
+}
+
+struct World {
+  network: Mutex<Network>,
+  nodes: Mutex<Vec<Arc<Mutex<SimNode>>>>,
+  clients: Mutex<Vec<SimClient>>,
+  history: History<Log>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Event {
+  Deliver(usize),
+  Process(usize, usize),
+  Send(usize),
+  Receive(usize),
+}
+
+// Everything that could happen next. A node that's busy handling a request
+// further up the stack can't take another until it's done, which is as if
+// each node had a single worker.
+fn possible(world: &World, clients: &[SimClient], network: &Network) -> Vec<Event> {
+  let nodes = world.nodes.lock().unwrap();
+  let mut events = Vec::new();
+  for (i, packet) in network.in_flight.iter().enumerate() {
+    if packet.arrives <= network.now && !network.in_flight[..i].iter().any(|p| p.to == packet.to) {
+      events.push(Event::Deliver(i));
+    }
+  }
+  for (&(conn, side), inbox) in &network.inboxes {
+    let node = network.acceptors[conn];
+    if side == Side::Acceptor && !inbox.is_empty() && nodes[node].try_lock().is_ok() {
+      events.push(Event::Process(node, conn));
+    }
+  }
+  for client in clients {
+    match client.outstanding {
+      None if !client.script.is_empty() => events.push(Event::Send(client.index)),
+      Some((_, conn, _)) if network.inboxes.get(&(conn, Side::Connector)).map(|i| !i.is_empty()).unwrap_or(false) =>
+        events.push(Event::Receive(client.index)),
+      _ => (),
+    }
+  }
+  events
+}
+
+// Does one thing, chosen at random from everything that could happen now,
+// moving the clock on to the next arrival if need be. Returns false once
+// there's nothing left that could happen.
+fn step(world: &Arc<World>) -> bool {
+  loop {
+    let event = {
+      let mut clients = world.clients.lock().unwrap();
+      let mut network = world.network.lock().unwrap();
+      let events = possible(world, &clients, &network);
+      if events.is_empty() {
+        match network.in_flight.iter().map(|p| p.arrives).filter(|&at| at > network.now).min() {
+          Some(at) => {
+            network.advance_to(at);
+            continue
+          },
+          None => return false,
+        }
+      }
+      let event = events[network.rng.gen_range(0, events.len())];
+      network.clock.fetch_add(1, Ordering::SeqCst);
+      trace!("At {}ms: {:?}", network.now, event);
+      match event {
+        Event::Deliver(i) => {
+          let packet = network.in_flight.remove(i);
+          network.inboxes.entry(packet.to).or_insert_with(Vec::new).extend(packet.bytes);
+          return true
+        },
+        Event::Send(client) => {
+          clients[client].send(&mut network, &world.history);
+          return true
+        },
+        Event::Receive(client) => {
+          clients[client].receive(&mut network);
+          return true
+        },
+        Event::Process(..) => event,
+      }
+    };
+    // Nodes may well need to step the simulation themselves while they
+    // wait on the next node, so we mustn't hold anything while they work.
+    if let Event::Process(node, conn) = event {
+      let bytes = world.network.lock().unwrap().take_inbox((conn, Side::Acceptor));
+      let node = world.nodes.lock().unwrap()[node].clone();
+      node.lock().unwrap().process(world, conn, &bytes);
+    }
+    return true
+  }
+}
+
+/// A chain of nodes, and clients working through scripts against it, all
+/// on one thread. Every choice of what happens next, and every network
+/// delay, is drawn from a generator seeded with `seed`, so a run can be
+/// repeated exactly.
+///
+/// The stores' leases and the history's timings go by simulated time.
+/// Subscriptions aren't simulated, though: each node's dispatcher still runs
+/// on threads of its own, by the real clock, so scripts only read and
+/// write. Nor are deadlines, as clients never set any.
+pub struct Simulation {
+  world: Arc<World>,
+  dirs: Vec<PathBuf>,
+}
+
+impl Simulation {
+  /// Clients write to the head and read from the tail, each waiting for a
+  /// response before moving on to the next operation in its script.
+  pub fn new(seed: u64, length: usize, scripts: Vec<Vec<LogOp>>) -> Result<Simulation, ServerError> {
+    assert!(length > 0, "A chain needs at least one node");
+    let rng = XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, 0x9e3779b9, 0x7f4a7c15]);
+    let clock = Arc::new(AtomicUsize::new(0));
+    let history_clock : history::Clock = {
+      let clock = clock.clone();
+      Arc::new(move || {
+        let nanos = clock.load(Ordering::SeqCst);
+        Duration::new((nanos / 1000000000) as u64, (nanos % 1000000000) as u32)
+      })
+    };
+    let world = Arc::new(World {
+      network: Mutex::new(Network { now: 0, clock: clock.clone(), rng: rng, in_flight: Vec::new(),
+        inboxes: BTreeMap::new(), last_arrival: BTreeMap::new(), acceptors: Vec::new() }),
+      nodes: Mutex::new(Vec::new()),
+      clients: Mutex::new(Vec::new()),
+      history: History::with_clock(history_clock),
+    });
+    let mut sim = Simulation { world: world, dirs: Vec::new() };
+
+    let name = rand::thread_rng().gen_ascii_chars().take(12).collect::<String>();
+    for index in 0..length {
+      let dir = env::temp_dir().join(format!("yak-sim-{}-{}", name, index));
+      try!(fs::create_dir_all(&dir));
+      sim.dirs.push(dir.clone());
+      let metrics = Metrics::new();
+      let store_clock : sqlite_store::Clock = {
+        let clock = clock.clone();
+        Arc::new(move || (clock.load(Ordering::SeqCst) / NANOS_PER_MS) as i64)
+      };
+      let store = try_box!(SqliteStore::new(&dir)).with_metrics(metrics.clone()).with_clock(store_clock);
+      let dispatcher = try!(Dispatcher::new(1, store.watch()));
+      let next = if index + 1 < length {
+        let conn = sim.world.network.lock().unwrap().connect(index + 1);
+        let stream = SimStream { world: Arc::downgrade(&sim.world), end: (conn, Side::Connector) };
+        Some(DownStream::over(&format!("node{}", index + 1), WireProtocol::new(stream), metrics.clone()))
+      } else {
+        None
+      };
+      let node = SimNode { index: index, store: store, dispatcher: dispatcher, metrics: metrics, next: next,
+        peers: Peers::new(), sessions: BTreeMap::new(), closed: BTreeSet::new() };
+      sim.world.nodes.lock().unwrap().push(Arc::new(Mutex::new(node)));
+    }
+
+    for (index, script) in scripts.into_iter().enumerate() {
+      let (head, tail) = {
+        let mut network = sim.world.network.lock().unwrap();
+        (network.connect(0), network.connect(length - 1))
+      };
+      sim.world.clients.lock().unwrap().push(SimClient { index: index, head: head, tail: tail,
+        script: script.into_iter().collect(), next_seq: 1, outstanding: None, input: Vec::new() });
+    }
+    Ok(sim)
+  }
+
+  /// Runs until nothing more can happen, and returns how many steps that
+  /// took.
+  pub fn run(&self) -> usize {
+    let mut steps = 0;
+    while step(&self.world) {
+      steps += 1;
+    }
+    steps
+  }
+
+  /// The simulated time, in milliseconds.
+  pub fn now(&self) -> u64 {
+    self.world.network.lock().unwrap().now
+  }
+
+  pub fn history(&self) -> &History<Log> {
+    &self.world.history
+  }
+}
+
+impl Drop for Simulation {
+  fn drop(&mut self) {
+    for node in self.world.nodes.lock().unwrap().drain(..) {
+      node.lock().unwrap().dispatcher.stop();
+    }
+    for dir in &self.dirs {
+      if let Err(e) = fs::remove_dir_all(dir) {
+        warn!("Could not remove store {}: {}", dir.display(), e);
+      }
+    }
+  }
+}
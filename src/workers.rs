@@ -0,0 +1,64 @@
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+// How often idle workers check whether they've been stopped.
+const STOP_CHECK_MS : u64 = 100;
+
+/// A fixed number of threads sharing a queue of jobs. Workers may submit
+/// further jobs to the pool that is running them.
+pub struct WorkerPool<T> {
+  jobs: Sender<T>,
+  stopped: Arc<AtomicBool>,
+}
+
+impl<T> Clone for WorkerPool<T> {
+  fn clone(&self) -> WorkerPool<T> {
+    WorkerPool { jobs: self.jobs.clone(), stopped: self.stopped.clone() }
+  }
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+  pub fn new<F>(name: &str, size: usize, work: F) -> io::Result<WorkerPool<T>>
+      where F: Fn(&WorkerPool<T>, T) + Send + Sync + 'static {
+    let (tx, rx) = channel();
+    let pool = WorkerPool { jobs: tx, stopped: Arc::new(AtomicBool::new(false)) };
+    let queue : Arc<Mutex<Receiver<T>>> = Arc::new(Mutex::new(rx));
+    let work = Arc::new(work);
+    for n in 0..size {
+      let queue = queue.clone();
+      let work = work.clone();
+      let pool = pool.clone();
+      try!(thread::Builder::new().name(format!("{}-{}", name, n)).spawn(move || {
+        while !pool.stopped.load(Ordering::SeqCst) {
+          let job = match queue.lock().unwrap().recv_timeout(Duration::from_millis(STOP_CHECK_MS)) {
+            Ok(job) => job,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+          };
+          // One bad job shouldn't take a worker, and everything queued
+          // behind it, down with it.
+          if let Err(_) = panic::catch_unwind(AssertUnwindSafe(|| work(&pool, job))) {
+            error!("Worker job panicked");
+          }
+        }
+      }));
+    }
+    Ok(pool)
+  }
+
+  /// Returns false if the workers have all gone.
+  pub fn submit(&self, job: T) -> bool {
+    self.jobs.send(job).is_ok()
+  }
+
+  /// Has the workers exit once they've finished what they're doing, leaving
+  /// any jobs still queued undone.
+  pub fn stop(&self) {
+    self.stopped.store(true, Ordering::SeqCst);
+  }
+}
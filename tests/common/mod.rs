@@ -1,13 +1,45 @@
+use std::cell::RefCell;
 use std::env;
 extern crate rand;
+extern crate yak_mq;
 use self::rand::Rng;
+use self::yak_mq::testing::Chain;
 
-use yak_client::Client;
+use yak_client::{Client,Cluster};
+
+const LOCAL_CHAIN_LENGTH : usize = 3;
+
+thread_local! {
+  // Each test runs on a thread of its own, so it gets a chain of its own the
+  // first time it asks for one, which is shut down and removed once the test
+  // is over.
+  static CHAIN: RefCell<Option<Chain>> = RefCell::new(None);
+}
+
+fn local_chain_url<F: Fn(&Chain) -> String>(url: F) -> String {
+  CHAIN.with(|chain| {
+    let mut chain = chain.borrow_mut();
+    if chain.is_none() {
+      *chain = Some(Chain::start(LOCAL_CHAIN_LENGTH).expect("start local chain"));
+    }
+    url(chain.as_ref().unwrap())
+  })
+}
+
+/// Uses the node named by `env_var` if it's set, or else one end of a chain
+/// running in this process.
+pub fn url_from_env(env_var: &str, name: &str, test_id: u64) -> String {
+  let yak_url = match env::var(env_var) {
+    Ok(url) => url,
+    Err(_) if env_var == "YAK_HEAD" => local_chain_url(|chain| chain.head_url("test")),
+    Err(_) if env_var == "YAK_TAIL" => local_chain_url(|chain| chain.tail_url("test")),
+    Err(e) => panic!("env var {}: {}", env_var, e),
+  };
+  format!("{}-{}-{:x}", yak_url, name, test_id)
+}
 
 pub fn open_from_env(env_var: &str, name: &str, test_id: u64) -> Client {
-  let yak_url = env::var(env_var).ok()
-    .expect(&format!("env var {} not found", env_var));
-  let full_url = format!("{}-{}-{:x}", yak_url, name, test_id);
+  let full_url = url_from_env(env_var, name, test_id);
   info!("Connecting to:{:?}", full_url);
   Client::connect(&full_url).unwrap()
 }
@@ -18,3 +50,22 @@ pub fn open_client(name: &str) -> (Client, Client) {
   let tail = open_from_env("YAK_TAIL", name, test_id);
   (head, tail)
 }
+
+pub fn new_test_id() -> u64 {
+  rand::thread_rng().next_u64()
+}
+
+pub fn open_clients(name: &str, tails: usize) -> (Client, Vec<Client>) {
+  let test_id = new_test_id();
+  let head = open_from_env("YAK_HEAD", name, test_id);
+  let tails = (0..tails).map(|_| open_from_env("YAK_TAIL", name, test_id)).collect();
+  (head, tails)
+}
+
+pub fn open_cluster(name: &str) -> Cluster {
+  let test_id = new_test_id();
+  let urls : Vec<String> = ["YAK_TAIL", "YAK_HEAD"].iter().map(|env_var| url_from_env(env_var, name, test_id)).collect();
+  info!("Connecting to cluster via:{:?}", urls);
+  let urls : Vec<&str> = urls.iter().map(|url| &url[..]).collect();
+  Cluster::connect(&urls).unwrap()
+}
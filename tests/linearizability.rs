@@ -0,0 +1,72 @@
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+extern crate yak_client;
+extern crate yak_mq;
+
+use std::thread;
+use yak_client::{Client, RetryPolicy};
+use yak_mq::testing::history::{History, Log, LogOp, LogRet};
+
+mod common;
+use common::*;
+
+const WRITERS : usize = 3;
+const READERS : usize = 3;
+const OPS_PER_CLIENT : usize = 20;
+const KEYS : &'static [&'static [u8]] = &[b"a", b"b"];
+
+// A retried write may be applied twice, which is a different thing to test.
+fn connect(env_var: &str, name: &str, test_id: u64) -> Client {
+  let mut client = open_from_env(env_var, name, test_id);
+  client.set_retry_policy(RetryPolicy::never());
+  client
+}
+
+#[test]
+fn test_concurrent_writes_and_reads_are_linearizable() {
+  static TEST_NAME: &'static str = "test_concurrent_writes_and_reads_are_linearizable";
+  env_logger::init().unwrap_or(());
+  let test_id = new_test_id();
+  let history : History<Log> = History::new();
+
+  let writers = (0..WRITERS).map(|process| {
+    let history = history.clone();
+    let mut head = connect("YAK_HEAD", TEST_NAME, test_id);
+    thread::spawn(move || for n in 0..OPS_PER_CLIENT {
+      let key = KEYS[n % KEYS.len()];
+      let value = format!("{}-{}", process, n).into_bytes();
+      let invocation = history.invoke(process, LogOp::Write { key: key.to_vec(), value: value.clone() });
+      match head.write(key, &value) {
+        Ok(()) => invocation.complete(LogRet::Written),
+        Err(e) => warn!("Write {:?} failed: {}", invocation.index(), e),
+      }
+    })
+  });
+  let readers = (0..READERS).map(|reader| {
+    let process = WRITERS + reader;
+    let history = history.clone();
+    let mut tail = connect("YAK_TAIL", TEST_NAME, test_id);
+    thread::spawn(move || for n in 0..OPS_PER_CLIENT {
+      let key = KEYS[n % KEYS.len()];
+      let invocation = history.invoke(process, LogOp::Read { key: key.to_vec() });
+      match tail.read(key) {
+        Ok(data) => invocation.complete(LogRet::Values(data.into_iter().map(|d| d.content).collect())),
+        Err(e) => warn!("Read {:?} failed: {}", invocation.index(), e),
+      }
+    })
+  });
+  let threads : Vec<_> = writers.chain(readers).collect();
+  for thread in threads {
+    thread.join().unwrap();
+  }
+
+  info!("Checking {} operations", history.entries().len());
+  if let Err(e) = history.check(Log::default()) {
+    let entries = history.entries();
+    for i in e.longest.iter().chain(e.unplaced.iter()) {
+      error!("{}: {:?}", i, entries[*i]);
+    }
+    panic!("{}", e);
+  }
+}
@@ -0,0 +1,64 @@
+extern crate rand;
+extern crate yak_client;
+extern crate yak_mq;
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use rand::Rng;
+use yak_client::Client;
+use yak_mq::Server;
+
+fn scrape(addr: SocketAddr) -> String {
+  let mut stream = TcpStream::connect(addr).unwrap();
+  stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+  let mut response = String::new();
+  stream.read_to_string(&mut response).unwrap();
+  assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "Unexpected response: {:?}", response);
+  response
+}
+
+#[test]
+fn test_metrics_count_requests_and_offsets() {
+  let dir = env::temp_dir().join(format!("yak-metrics-{}", rand::thread_rng().gen_ascii_chars().take(12).collect::<String>()));
+  fs::create_dir_all(&dir).unwrap();
+  let server = Server::new(&dir).metrics_listen("127.0.0.1:0").start().unwrap();
+  {
+    let mut client = Client::connect(&server.url("metered")).unwrap();
+    client.write(b"key", b"one").unwrap();
+    client.write(b"key", b"two").unwrap();
+    client.read(b"key").unwrap();
+  }
+
+  let text = scrape(server.metrics_addr().unwrap());
+  assert!(text.contains("yak_requests_total{operation=\"write\"} 2\n"), "{}", text);
+  assert!(text.contains("yak_requests_total{operation=\"read\"} 1\n"), "{}", text);
+  assert!(text.contains("yak_space_last_offset{space=\"metered\"} 1\n"), "{}", text);
+  assert!(text.contains("yak_write_duration_seconds_count 2\n"), "{}", text);
+
+  server.shutdown().unwrap();
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_metrics_report_offsets_written_before_starting() {
+  let dir = env::temp_dir().join(format!("yak-metrics-{}", rand::thread_rng().gen_ascii_chars().take(12).collect::<String>()));
+  fs::create_dir_all(&dir).unwrap();
+  {
+    let server = Server::new(&dir).start().unwrap();
+    let mut client = Client::connect(&server.url("restarted")).unwrap();
+    for value in &[b"one", b"two", b"six"] {
+      client.write(b"key", *value).unwrap();
+    }
+    drop(client);
+    server.shutdown().unwrap();
+  }
+
+  let server = Server::new(&dir).metrics_listen("127.0.0.1:0").start().unwrap();
+  let text = scrape(server.metrics_addr().unwrap());
+  assert!(text.contains("yak_space_last_offset{space=\"restarted\"} 2\n"), "{}", text);
+
+  server.shutdown().unwrap();
+  fs::remove_dir_all(&dir).unwrap();
+}
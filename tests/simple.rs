@@ -2,8 +2,13 @@
 extern crate log;
 extern crate log4rs;
 extern crate yak_client;
+extern crate futures;
+extern crate tokio_core;
 
 use std::thread;
+use std::time::Duration;
+use futures::{future, Future, Stream};
+use tokio_core::reactor::Core;
 
 mod common;
 use common::*;
@@ -106,3 +111,418 @@ fn test_subscribe_async_deliveries() {
 
   assert_eq!(maybe_message.map(|message| (message.key, message.content)), Some((key.to_vec(), val.to_vec())))
 }
+
+#[test]
+fn test_queue_subscription_delivers_after_ack() {
+  static TEST_NAME: &'static str = "test_queue_subscription_delivers_after_ack";
+  log_init();
+  let (mut head, tail) = open_client(TEST_NAME);
+  head.configure_queue(yak_client::QueueConfig { visibility_timeout_ms: 60000, max_deliveries: 3, dead_letter_space: None }).unwrap();
+  head.write(b"key", b"a").unwrap();
+  head.write(b"key", b"b").unwrap();
+
+  let mut subscription = tail.subscribe().unwrap();
+  let first = subscription.fetch_next().unwrap();
+  subscription.ack().unwrap();
+  let second = subscription.fetch_next().unwrap();
+
+  assert_eq!(first.map(|message| message.content), Some(b"a".to_vec()));
+  assert_eq!(second.map(|message| message.content), Some(b"b".to_vec()));
+}
+
+#[test]
+fn test_queue_subscription_redelivers_unacked() {
+  static TEST_NAME: &'static str = "test_queue_subscription_redelivers_unacked";
+  log_init();
+  let (mut head, mut tails) = open_clients(TEST_NAME, 2);
+  head.configure_queue(yak_client::QueueConfig { visibility_timeout_ms: 100, max_deliveries: 3, dead_letter_space: None }).unwrap();
+  head.write(b"key", b"value").unwrap();
+
+  let mut crashed = tails.remove(0).subscribe().unwrap();
+  let first = crashed.fetch_next().unwrap();
+  drop(crashed);
+
+  let mut subscription = tails.remove(0).subscribe().unwrap();
+  let second = subscription.fetch_next().unwrap();
+
+  assert_eq!(first, second);
+  assert_eq!(subscription.last_offset(), Some(0));
+}
+
+#[test]
+fn test_queue_subscription_dead_letters_nacked() {
+  static TEST_NAME: &'static str = "test_queue_subscription_dead_letters_nacked";
+  log_init();
+  let (mut head, mut tails) = open_clients(TEST_NAME, 1);
+  let mut dead_tail = open_from_env("YAK_TAIL", &format!("{}-dead", TEST_NAME), new_test_id());
+  let dead_space = dead_tail.space().to_string();
+  head.configure_queue(yak_client::QueueConfig { visibility_timeout_ms: 60000, max_deliveries: 3, dead_letter_space: Some(dead_space) }).unwrap();
+  head.write(b"key", b"poison").unwrap();
+  head.write(b"key", b"fine").unwrap();
+
+  let mut subscription = tails.remove(0).subscribe().unwrap();
+  subscription.fetch_next().unwrap();
+  subscription.nack("cannot parse").unwrap();
+  let next = subscription.fetch_next().unwrap();
+  assert_eq!(next.map(|message| message.content), Some(b"fine".to_vec()));
+
+  let dead = dead_tail.read(b"key").unwrap();
+  let letters : Vec<_> = dead.iter().map(|d| yak_client::DeadLetter::from_bytes(&d.content).unwrap()).collect();
+  assert_eq!(letters.len(), 1);
+  assert_eq!(letters[0].content, b"poison".to_vec());
+  assert_eq!(letters[0].reason, "cannot parse");
+  assert_eq!(letters[0].offset, 0);
+}
+
+#[test]
+fn test_queue_is_consumed_and_dead_lettered_on_the_tail() {
+  static TEST_NAME: &'static str = "test_queue_is_consumed_and_dead_lettered_on_the_tail";
+  log_init();
+  let (mut head, mut tails) = open_clients(TEST_NAME, 1);
+  let dead_id = new_test_id();
+  let mut dead_head = open_from_env("YAK_HEAD", &format!("{}-dead", TEST_NAME), dead_id);
+  let dead_space = dead_head.space().to_string();
+  head.configure_queue(yak_client::QueueConfig { visibility_timeout_ms: 60000, max_deliveries: 3, dead_letter_space: Some(dead_space) }).unwrap();
+  head.write(b"key", b"poison").unwrap();
+  head.write(b"key", b"fine").unwrap();
+
+  match head.subscribe() {
+    Err(yak_client::YakError::Refused(_)) => (),
+    other => panic!("Expected the head to refuse to lease messages, got: {:?}", other.map(|s| s.id())),
+  }
+  match dead_head.write(b"key", b"forged") {
+    Err(yak_client::YakError::Refused(_)) => (),
+    other => panic!("Expected a write to a dead-letter space to be refused, got: {:?}", other),
+  }
+
+  let mut subscription = tails.remove(0).subscribe().unwrap();
+  subscription.fetch_next().unwrap();
+  subscription.nack("cannot parse").unwrap();
+  subscription.fetch_next().unwrap();
+
+  let dead = dead_head.read(b"key").unwrap();
+  let letters : Vec<_> = dead.iter().map(|d| yak_client::DeadLetter::from_bytes(&d.content).unwrap()).collect();
+  assert_eq!(letters.len(), 1);
+  assert_eq!(letters[0].content, b"poison".to_vec());
+}
+
+#[test]
+fn test_subscribe_with_small_credit_window() {
+  static TEST_NAME: &'static str = "test_subscribe_with_small_credit_window";
+  log_init();
+  let (mut head, tail) = open_client(TEST_NAME);
+  let vals : Vec<Vec<u8>> = (0..5).map(|i| format!("value-{}", i).into_bytes()).collect();
+  for val in &vals {
+    head.write(b"key", &val).unwrap();
+  }
+
+  let mut subscription = tail.subscribe_with_credit(1).unwrap();
+  let received : Vec<Vec<u8>> = vals.iter()
+    .map(|_| subscription.fetch_next().unwrap().unwrap().content)
+    .collect();
+
+  assert_eq!(received, vals);
+}
+
+#[test]
+fn test_multiple_subscriptions_share_connection() {
+  static TEST_NAME: &'static str = "test_multiple_subscriptions_share_connection";
+  log_init();
+  let (mut head, mut tail) = open_client(TEST_NAME);
+  let key = b"key";
+  let val = b"value";
+  head.write(key, val).unwrap();
+
+  let mut first = tail.subscribe().unwrap();
+  let mut second = tail.subscribe().unwrap();
+  assert!(first.id() != second.id());
+
+  assert_eq!(first.fetch_next().unwrap().map(|message| message.content), Some(val.to_vec()));
+  assert_eq!(tail.read(key).unwrap().len(), 1);
+  assert_eq!(second.fetch_next().unwrap().map(|message| message.content), Some(val.to_vec()));
+}
+
+#[test]
+fn test_unsubscribe_ends_stream() {
+  static TEST_NAME: &'static str = "test_unsubscribe_ends_stream";
+  log_init();
+  let (mut head, tail) = open_client(TEST_NAME);
+  head.write(b"key", b"value").unwrap();
+
+  let mut subscription = tail.subscribe_with_credit(1).unwrap();
+  assert!(subscription.fetch_next().unwrap().is_some());
+  subscription.unsubscribe().unwrap();
+
+  assert_eq!(subscription.fetch_next().unwrap(), None);
+}
+
+#[test]
+fn test_subscribe_with_key_prefix() {
+  static TEST_NAME: &'static str = "test_subscribe_with_key_prefix";
+  log_init();
+  let (mut head, tail) = open_client(TEST_NAME);
+  head.write(b"orders/1", b"a").unwrap();
+  head.write(b"audit/1", b"b").unwrap();
+  head.write(b"orders/2", b"c").unwrap();
+
+  let options = yak_client::SubscriptionOptions { filter: yak_client::KeyFilter::Prefix(b"orders/".to_vec()), ..Default::default() };
+  let mut subscription = tail.subscribe_with(options).unwrap();
+  let first = subscription.fetch_next().unwrap().map(|message| message.key);
+  let second = subscription.fetch_next().unwrap().map(|message| message.key);
+
+  assert_eq!((first, second), (Some(b"orders/1".to_vec()), Some(b"orders/2".to_vec())));
+}
+
+#[test]
+fn test_subscribe_to_space_pattern() {
+  static TEST_NAME: &'static str = "test_subscribe_to_space_pattern";
+  log_init();
+  let test_id = new_test_id();
+  let mut first = open_from_env("YAK_HEAD", &format!("{}/first", TEST_NAME), test_id);
+  let mut unrelated = open_from_env("YAK_HEAD", &format!("{}-unrelated", TEST_NAME), test_id);
+  let tail = open_from_env("YAK_TAIL", &format!("{}/watch", TEST_NAME), test_id);
+  let pattern = tail.space().replace("/watch-", "/*-");
+
+  let options = yak_client::SubscriptionOptions { space_pattern: Some(pattern), ..Default::default() };
+  let mut subscription = tail.subscribe_with(options).unwrap();
+
+  first.write(b"key", b"a").unwrap();
+  unrelated.write(b"key", b"b").unwrap();
+  // Created after the subscription started.
+  let mut second = open_from_env("YAK_HEAD", &format!("{}/second", TEST_NAME), test_id);
+  second.write(b"key", b"c").unwrap();
+
+  let a = subscription.fetch_next().unwrap().unwrap();
+  let c = subscription.fetch_next().unwrap().unwrap();
+
+  assert_eq!((a.space, a.content), (first.space().to_string(), b"a".to_vec()));
+  assert_eq!((c.space, c.content), (second.space().to_string(), b"c".to_vec()));
+}
+
+#[test]
+fn test_subscribe_to_space_pattern_with_character_class() {
+  static TEST_NAME: &'static str = "test_subscribe_to_space_pattern_with_character_class";
+  log_init();
+  let test_id = new_test_id();
+  let mut alpha = open_from_env("YAK_HEAD", &format!("{}/alpha", TEST_NAME), test_id);
+  let mut gamma = open_from_env("YAK_HEAD", &format!("{}/gamma", TEST_NAME), test_id);
+  let mut beta = open_from_env("YAK_HEAD", &format!("{}/beta", TEST_NAME), test_id);
+  let tail = open_from_env("YAK_TAIL", &format!("{}/watch", TEST_NAME), test_id);
+  let pattern = tail.space().replace("/watch-", "/[ab]*-");
+
+  let options = yak_client::SubscriptionOptions { space_pattern: Some(pattern), ..Default::default() };
+  let mut subscription = tail.subscribe_with(options).unwrap();
+
+  alpha.write(b"key", b"a").unwrap();
+  gamma.write(b"key", b"g").unwrap();
+  beta.write(b"key", b"b").unwrap();
+
+  let first = subscription.fetch_next().unwrap().unwrap();
+  let second = subscription.fetch_next().unwrap().unwrap();
+
+  assert_eq!((first.space, first.content), (alpha.space().to_string(), b"a".to_vec()));
+  assert_eq!((second.space, second.content), (beta.space().to_string(), b"b".to_vec()));
+}
+
+#[test]
+fn test_catch_up_subscription_ends() {
+  static TEST_NAME: &'static str = "test_catch_up_subscription_ends";
+  log_init();
+  let (mut head, tail) = open_client(TEST_NAME);
+  head.write(b"key", b"a").unwrap();
+  head.write(b"key", b"b").unwrap();
+
+  let options = yak_client::SubscriptionOptions { catch_up: true, ..Default::default() };
+  let mut subscription = tail.subscribe_with(options).unwrap();
+  head.write(b"key", b"c").unwrap();
+
+  let mut seen = Vec::new();
+  while let Some(message) = subscription.fetch_next().unwrap() {
+    seen.push(message.content);
+  }
+
+  assert_eq!(seen, vec![b"a".to_vec(), b"b".to_vec()]);
+}
+
+#[test]
+fn test_idle_subscription_survives_heartbeat_timeout() {
+  static TEST_NAME: &'static str = "test_idle_subscription_survives_heartbeat_timeout";
+  log_init();
+  let (mut head, tail) = open_client(TEST_NAME);
+  tail.set_heartbeat_timeout(2 * yak_client::HEARTBEAT_INTERVAL_MS).unwrap();
+  let mut subscription = tail.subscribe().unwrap();
+
+  thread::sleep(Duration::from_millis(5 * yak_client::HEARTBEAT_INTERVAL_MS));
+  head.write(b"key", b"late").unwrap();
+
+  assert_eq!(subscription.fetch_next().unwrap().map(|message| message.content), Some(b"late".to_vec()));
+}
+
+#[test]
+fn test_conditional_write_conflicts() {
+  static TEST_NAME: &'static str = "test_conditional_write_conflicts";
+  log_init();
+  let (mut head, _tail) = open_client(TEST_NAME);
+  let first = head.write_expecting(b"key", b"a", yak_client::Expected::Space(None)).unwrap();
+  head.write_expecting(b"key", b"b", yak_client::Expected::Key(Some(first))).unwrap();
+
+  match head.write_expecting(b"key", b"c", yak_client::Expected::Space(Some(first))) {
+    Err(yak_client::YakError::Conflict(conflict)) => assert_eq!(conflict.actual, Some(first + 1)),
+    other => panic!("Expected a conflict, got: {:?}", other),
+  }
+  assert_eq!(head.read(b"key").unwrap().len(), 2);
+}
+
+#[test]
+fn test_transaction_writes_to_several_spaces() {
+  static TEST_NAME: &'static str = "test_transaction_writes_to_several_spaces";
+  log_init();
+  let test_id = new_test_id();
+  let mut orders = open_from_env("YAK_HEAD", &format!("{}/orders", TEST_NAME), test_id);
+  let audit = open_from_env("YAK_TAIL", &format!("{}/audit", TEST_NAME), test_id);
+  let records = vec![
+    yak_client::Record { space: orders.space().to_string(), key: b"order".to_vec(), content: b"placed".to_vec() },
+    yak_client::Record { space: audit.space().to_string(), key: b"order".to_vec(), content: b"logged".to_vec() },
+  ];
+  orders.transaction(records).unwrap();
+
+  let mut subscription = audit.subscribe().unwrap();
+  assert_eq!(subscription.fetch_next().unwrap().map(|message| message.content), Some(b"logged".to_vec()));
+  assert_eq!(orders.read(b"order").unwrap().len(), 1);
+}
+
+#[test]
+fn test_retried_producer_write_is_not_duplicated() {
+  static TEST_NAME: &'static str = "test_retried_producer_write_is_not_duplicated";
+  log_init();
+  let test_id = new_test_id();
+  let mut head = open_from_env("YAK_HEAD", TEST_NAME, test_id);
+  let mut tail = open_from_env("YAK_TAIL", TEST_NAME, test_id);
+  head.set_producer(test_id, 0);
+  head.write(b"key", b"once").unwrap();
+
+  // A new connection retrying the same write, as if the first never got its
+  // acknowledgement.
+  let mut retry = open_from_env("YAK_HEAD", TEST_NAME, test_id);
+  retry.set_producer(test_id, 0);
+  retry.write(b"key", b"once").unwrap();
+
+  assert_eq!(retry.producer().map(|p| p.sequence), Some(1));
+  assert_eq!(tail.read(b"key").unwrap().len(), 1);
+}
+
+#[test]
+fn test_cluster_writes_to_head_and_reads_from_tail() {
+  static TEST_NAME: &'static str = "test_cluster_writes_to_head_and_reads_from_tail";
+  log_init();
+  let mut cluster = open_cluster(TEST_NAME);
+  assert!(cluster.layout().len() > 1);
+
+  let mut subscription = cluster.subscribe().unwrap();
+  cluster.write(b"key", b"value").unwrap();
+
+  assert_eq!(cluster.read(b"key").unwrap().len(), 1);
+  assert_eq!(subscription.fetch_next().unwrap().map(|message| message.content), Some(b"value".to_vec()));
+}
+
+#[test]
+fn test_cluster_finds_the_head_from_the_tail() {
+  static TEST_NAME: &'static str = "test_cluster_finds_the_head_from_the_tail";
+  log_init();
+  let test_id = new_test_id();
+  let from_both = open_cluster(TEST_NAME);
+  let tail_url = url_from_env("YAK_TAIL", TEST_NAME, test_id);
+  let mut cluster = yak_client::Cluster::connect(&[&tail_url]).unwrap();
+  assert_eq!(cluster.layout(), from_both.layout());
+
+  cluster.write(b"key", b"value").unwrap();
+  let mut head = open_from_env("YAK_HEAD", TEST_NAME, test_id);
+  assert_eq!(head.read(b"key").unwrap().len(), 1);
+}
+
+#[test]
+fn test_status_reports_roles_and_spaces() {
+  static TEST_NAME: &'static str = "test_status_reports_roles_and_spaces";
+  log_init();
+  let (mut head, mut tail) = open_client(TEST_NAME);
+  head.write(b"key", b"value").unwrap();
+
+  let head_status = head.status().unwrap();
+  assert_eq!(head_status.role, yak_client::Role::Head);
+  assert_eq!(head_status.downstream.map(|d| d.healthy), Some(Some(true)));
+  let tail_status = tail.status().unwrap();
+  assert_eq!(tail_status.role, yak_client::Role::Tail);
+  assert_eq!(tail_status.downstream, None);
+  assert!(!tail_status.sessions.is_empty());
+
+  let space = tail_status.spaces.into_iter().find(|s| s.name == tail.space()).expect("space in status");
+  assert_eq!((space.first, space.last, space.bytes), (0, 0, 8));
+}
+
+#[test]
+fn test_subscribe_from_start_offset() {
+  static TEST_NAME: &'static str = "test_subscribe_from_start_offset";
+  log_init();
+  let (mut head, tail) = open_client(TEST_NAME);
+  for value in &[b"a", b"b", b"c"] {
+    head.write(b"key", &value[..]).unwrap();
+  }
+  let offsets : Vec<_> = {
+    let mut subscription = tail.subscribe().unwrap();
+    (0..3).map(|_| subscription.fetch_next().unwrap().unwrap().offset).collect()
+  };
+
+  let options = yak_client::SubscriptionOptions { start_offset: offsets[1], ..Default::default() };
+  let mut subscription = tail.subscribe_with(options).unwrap();
+  let next = subscription.fetch_next().unwrap().unwrap();
+  assert_eq!((next.offset, next.content), (offsets[1], b"b".to_vec()));
+}
+
+#[test]
+fn test_fetch_times_out_when_idle() {
+  static TEST_NAME: &'static str = "test_fetch_times_out_when_idle";
+  log_init();
+  let (mut head, tail) = open_client(TEST_NAME);
+  let mut subscription = tail.subscribe().unwrap();
+
+  match subscription.fetch_next_within(100) {
+    Err(yak_client::YakError::Timeout) => (),
+    other => panic!("Expected a timeout, got: {:?}", other),
+  }
+  head.write_within(b"key", b"value", 5000).unwrap();
+  assert_eq!(subscription.fetch_next().unwrap().map(|message| message.content), Some(b"value".to_vec()));
+}
+
+#[test]
+fn test_read_past_deadline_times_out() {
+  static TEST_NAME: &'static str = "test_read_past_deadline_times_out";
+  log_init();
+  let (mut head, mut tail) = open_client(TEST_NAME);
+  head.write(b"key", b"value").unwrap();
+
+  match tail.read_within(b"key", 0) {
+    Err(yak_client::YakError::Timeout) => (),
+    other => panic!("Expected a timeout, got: {:?}", other),
+  }
+  assert_eq!(tail.read_within(b"key", 5000).unwrap().len(), 1);
+}
+
+#[test]
+fn test_async_client_pipelines_writes() {
+  static TEST_NAME: &'static str = "test_async_client_pipelines_writes";
+  log_init();
+  let test_id = new_test_id();
+  let mut core = Core::new().unwrap();
+  let head = core.run(yak_client::AsyncClient::connect(&url_from_env("YAK_HEAD", TEST_NAME, test_id), &core.handle())).unwrap();
+  let tail = core.run(yak_client::AsyncClient::connect(&url_from_env("YAK_TAIL", TEST_NAME, test_id), &core.handle())).unwrap();
+
+  let values : Vec<Vec<u8>> = (0..10).map(|i| format!("value-{}", i).into_bytes()).collect();
+  let writes : Vec<_> = values.iter().map(|value| head.write(b"key", value)).collect();
+  core.run(future::join_all(writes)).unwrap();
+
+  let subscription = core.run(tail.subscribe()).unwrap();
+  // The writes share a connection, so they're applied in the order sent.
+  let delivered = core.run(subscription.take(values.len() as u64).map(|message| message.content).collect()).unwrap();
+  assert_eq!(delivered, values);
+  assert_eq!(core.run(tail.read(b"key")).unwrap().len(), values.len());
+}
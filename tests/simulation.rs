@@ -0,0 +1,63 @@
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+extern crate quickcheck;
+extern crate yak_mq;
+
+use yak_mq::testing::history::{Log, LogOp};
+use yak_mq::testing::simulation::Simulation;
+
+const CHAIN_LENGTH : usize = 3;
+const MAX_CLIENTS : usize = 3;
+const MAX_OPS_PER_CLIENT : usize = 8;
+
+// Each client's ops are writes (true) or reads (false) of one of two keys.
+fn scripts(ops: Vec<Vec<(bool, bool)>>) -> Vec<Vec<LogOp>> {
+  ops.into_iter().take(MAX_CLIENTS).enumerate().map(|(client, ops)| {
+    ops.into_iter().take(MAX_OPS_PER_CLIENT).enumerate().map(|(n, (write, which))| {
+      let key = if which { b"a".to_vec() } else { b"b".to_vec() };
+      if write {
+        LogOp::Write { key: key, value: format!("{}-{}", client, n).into_bytes() }
+      } else {
+        LogOp::Read { key: key }
+      }
+    }).collect()
+  }).collect()
+}
+
+#[test]
+fn test_simulated_chain_is_linearizable_qc() {
+  fn test_simulated_chain_is_linearizable_qc(seed: u64, ops: Vec<Vec<(bool, bool)>>) -> bool {
+    env_logger::init().unwrap_or(());
+    let sim = Simulation::new(seed, CHAIN_LENGTH, scripts(ops)).unwrap();
+    let steps = sim.run();
+    debug!("Seed {} ran for {} steps, to {}ms", seed, steps, sim.now());
+    match sim.history().check(Log::default()) {
+      Ok(()) => true,
+      Err(e) => {
+        error!("Seed {}: {}", seed, e);
+        false
+      },
+    }
+  }
+  quickcheck::quickcheck(test_simulated_chain_is_linearizable_qc as fn(u64, Vec<Vec<(bool, bool)>>) -> bool)
+}
+
+#[test]
+fn test_simulation_repeats_exactly_for_a_seed() {
+  env_logger::init().unwrap_or(());
+  let ops = vec![
+    vec![(true, true), (false, true), (true, false)],
+    vec![(false, true), (true, true), (false, false)],
+    vec![(true, false), (true, true), (false, true)],
+  ];
+  let run = |seed| {
+    let sim = Simulation::new(seed, CHAIN_LENGTH, scripts(ops.clone())).unwrap();
+    let steps = sim.run();
+    let outcomes : Vec<_> = sim.history().entries().into_iter()
+      .map(|e| (e.process, e.op, e.completed.map(|(_, ret)| ret)))
+      .collect();
+    (steps, sim.now(), outcomes)
+  };
+  assert_eq!(run(42), run(42));
+}
@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::BytesMut;
+use futures::{future, Future, Stream, Poll, Async};
+use futures::sync::{mpsc, oneshot};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::AsyncRead;
+use tokio_io::codec::{Decoder, Encoder};
+
+use super::{Request,Response,Datum,Delivery,Record,SeqNo,Offset,SubscriptionId,QueueConfig,Expected,NodeStatus,YakError};
+use super::encode_packed;
+use super::Decoder as MessageDecoder;
+use client::{SubscriptionOptions,parse_url};
+
+/// A future that resolves to the outcome of a request.
+pub type YakFuture<T> = Box<Future<Item=T, Error=YakError>>;
+
+// Requests go out and responses come back in the same packed form that
+// `WireProtocol` uses. Responses are picked out as they arrive, so that one
+// that arrives in many pieces is still only read through once.
+struct ClientCodec {
+  input: MessageDecoder,
+}
+
+impl Decoder for ClientCodec {
+  type Item = Response;
+  type Error = YakError;
+
+  fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Response>, YakError> {
+    let len = buf.len();
+    self.input.feed(&buf.split_to(len));
+    let resp = try!(self.input.next::<Response>());
+    if let Some(ref resp) = resp {
+      trace!("Read: {:?}", resp);
+    }
+    Ok(resp)
+  }
+}
+
+impl Encoder for ClientCodec {
+  type Item = Request;
+  type Error = YakError;
+
+  fn encode(&mut self, req: Request, buf: &mut BytesMut) -> Result<(), YakError> {
+    trace!("Send: {:?}", req);
+    buf.extend_from_slice(&try!(encode_packed(&req)));
+    Ok(())
+  }
+}
+
+// Like the blocking client's router, but completing futures and feeding
+// streams rather than waking threads.
+struct Router {
+  pending: HashMap<SeqNo, oneshot::Sender<Response>>,
+  subscriptions: HashMap<SubscriptionId, mpsc::UnboundedSender<Response>>,
+  closed: bool,
+}
+
+impl Router {
+  fn new() -> Router {
+    Router { pending: HashMap::new(), subscriptions: HashMap::new(), closed: false }
+  }
+
+  fn dispatch(&mut self, resp: Response) {
+    match resp {
+      Response::Heartbeat(subscription) => trace!("Heartbeat for: {:?}", subscription),
+      Response::Delivery(subscription, _) | Response::EndOfStream(subscription) => {
+        match self.subscriptions.get(&subscription) {
+          Some(tx) => { let _ = tx.unbounded_send(resp); },
+          None => trace!("Nobody waiting for: {:?}", resp),
+        }
+      },
+      Response::Okay(seq) | Response::OkayData(seq, _) | Response::Written(seq, _) | Response::Conflict(seq, _) |
+          Response::Layout(seq, _) | Response::Status(seq, _) | Response::TimedOut(seq) |
+          Response::Refused(seq, _) => {
+        match self.pending.remove(&seq) {
+          Some(tx) => { let _ = tx.send(resp); },
+          None => trace!("Nobody waiting for: {:?}", resp),
+        }
+      },
+    }
+  }
+
+  // Dropping the senders fails anyone waiting with `Disconnected`.
+  fn close(&mut self) {
+    self.closed = true;
+    self.pending.clear();
+    self.subscriptions.clear();
+  }
+}
+
+// The tasks reading and writing the socket only hold on to the router, so
+// that dropping the last handle on the connection lets them finish.
+struct Connection {
+  sequence: AtomicUsize,
+  router: Arc<Mutex<Router>>,
+  requests: mpsc::UnboundedSender<Request>,
+}
+
+impl Connection {
+  fn next_seq(&self) -> SeqNo {
+    self.sequence.fetch_add(1, Ordering::Relaxed) as SeqNo
+  }
+
+  fn send(&self, req: Request) -> Result<(), YakError> {
+    self.requests.unbounded_send(req).map_err(|_| YakError::Disconnected)
+  }
+
+  // Nothing waits for the response but the future we hand back, so any
+  // number of calls may be in flight at once.
+  fn call(&self, req: Request) -> YakFuture<Response> {
+    let (tx, rx) = oneshot::channel();
+    {
+      let mut router = self.router.lock().unwrap();
+      if router.closed {
+        return Box::new(future::err(YakError::Disconnected))
+      }
+      router.pending.insert(req.sequence, tx);
+    }
+    if let Err(e) = self.send(req) {
+      return Box::new(future::err(e))
+    }
+    Box::new(rx.map_err(|_| YakError::Disconnected))
+  }
+}
+
+impl fmt::Debug for Connection {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmt, "Connection{{ sequence: {:?} }}", self.sequence.load(Ordering::Relaxed))
+  }
+}
+
+/// A client for a single space that runs on a Tokio event loop. Requests
+/// don't wait for each other: each returns a future for its own response.
+/// Clones share the connection.
+#[derive(Debug,Clone)]
+pub struct AsyncClient {
+  connection: Arc<Connection>,
+  space: String,
+}
+
+/// Deliveries from a subscription, as a stream. The stream ends when the
+/// subscription does.
+pub struct AsyncSubscription {
+  connection: Arc<Connection>,
+  id: SubscriptionId,
+  space: String,
+  deliveries: mpsc::UnboundedReceiver<Response>,
+  last_offset: Option<Offset>,
+  window: u64,
+  outstanding: u64,
+  finished: bool,
+}
+
+impl AsyncClient {
+  pub fn connect(loc: &str, handle: &Handle) -> YakFuture<AsyncClient> {
+    let (host, port, space) = match parse_url(loc) {
+      Ok(parts) => parts,
+      Err(e) => return Box::new(future::err(e)),
+    };
+    let addr = match (&host as &str, port).to_socket_addrs().map(|mut addrs| addrs.next()) {
+      Ok(Some(addr)) => addr,
+      Ok(None) => return Box::new(future::err(YakError::IoError(io::Error::new(io::ErrorKind::NotFound, host)))),
+      Err(e) => return Box::new(future::err(From::from(e))),
+    };
+
+    let handle = handle.clone();
+    Box::new(TcpStream::connect(&addr, &handle).map_err(From::from).map(move |sock| {
+      debug!("connected:{:?}", sock);
+      let (writer, reader) = sock.framed(ClientCodec { input: MessageDecoder::new() }).split();
+      let (requests, outgoing) = mpsc::unbounded();
+      let router = Arc::new(Mutex::new(Router::new()));
+      let connection = Arc::new(Connection { sequence: AtomicUsize::new(0), router: router.clone(), requests: requests });
+
+      // Once nobody can send anything more, we stop reading too, which
+      // drops the socket.
+      let (done_tx, done_rx) = oneshot::channel::<()>();
+      let sending = outgoing.map_err(|()| YakError::Disconnected).forward(writer).then(move |result| -> Result<(), ()> {
+        if let Err(e) = result {
+          warn!("Connection failed: {}", e);
+        }
+        let _ = done_tx.send(());
+        Ok(())
+      });
+
+      let routing = router.clone();
+      let receiving = reader.for_each(move |resp| {
+        routing.lock().unwrap().dispatch(resp);
+        Ok(())
+      });
+      let receiving = receiving.select(done_rx.map_err(|_| YakError::Disconnected)).then(move |result| -> Result<(), ()> {
+        if let Err((e, _)) = result {
+          warn!("Connection failed: {}", e);
+        }
+        debug!("Connection closed");
+        router.lock().unwrap().close();
+        Ok(())
+      });
+
+      handle.spawn(sending);
+      handle.spawn(receiving);
+      AsyncClient { connection: connection, space: space }
+    }))
+  }
+
+  pub fn space(&self) -> &str {
+    &self.space
+  }
+
+  pub fn write(&self, key: &[u8], val: &[u8]) -> YakFuture<()> {
+    let req = Request::write(self.connection.next_seq(), &self.space, key, val, None);
+    Box::new(self.connection.call(req).and_then(|resp| resp.expect_ok().map(|_| ())))
+  }
+
+  /// See `Client::write_expecting`.
+  pub fn write_expecting(&self, key: &[u8], val: &[u8], expected: Expected) -> YakFuture<Offset> {
+    let req = Request::write_expecting(self.connection.next_seq(), &self.space, key, val, expected);
+    Box::new(self.connection.call(req).and_then(|resp| resp.expect_written().map(|(_seq, offset)| offset)))
+  }
+
+  /// See `Client::transaction`.
+  pub fn transaction(&self, records: Vec<Record>) -> YakFuture<()> {
+    let req = Request::transaction(self.connection.next_seq(), &self.space, records);
+    Box::new(self.connection.call(req).and_then(|resp| resp.expect_ok().map(|_| ())))
+  }
+
+  pub fn read(&self, key: &[u8]) -> YakFuture<Vec<Datum>> {
+    let req = Request::read(self.connection.next_seq(), &self.space, key);
+    Box::new(self.connection.call(req).and_then(|resp| resp.expect_datum_list().map(|(_seq, data)| data)))
+  }
+
+  pub fn layout(&self) -> YakFuture<Vec<String>> {
+    let req = Request::layout(self.connection.next_seq(), &self.space);
+    Box::new(self.connection.call(req).and_then(|resp| resp.expect_layout().map(|(_seq, nodes)| nodes)))
+  }
+
+  pub fn status(&self) -> YakFuture<NodeStatus> {
+    let req = Request::status(self.connection.next_seq(), &self.space);
+    Box::new(self.connection.call(req).and_then(|resp| resp.expect_status().map(|(_seq, status)| status)))
+  }
+
+  pub fn configure_queue(&self, config: QueueConfig) -> YakFuture<()> {
+    let req = Request::configure_queue(self.connection.next_seq(), &self.space, config);
+    Box::new(self.connection.call(req).and_then(|resp| resp.expect_ok().map(|_| ())))
+  }
+
+  pub fn subscribe(&self) -> YakFuture<AsyncSubscription> {
+    self.subscribe_with(SubscriptionOptions::default())
+  }
+
+  pub fn subscribe_with(&self, options: SubscriptionOptions) -> YakFuture<AsyncSubscription> {
+    let id = self.connection.next_seq();
+    let (tx, rx) = mpsc::unbounded();
+    {
+      let mut router = self.connection.router.lock().unwrap();
+      if router.closed {
+        return Box::new(future::err(YakError::Disconnected))
+      }
+      router.subscriptions.insert(id, tx);
+    }
+
+    // The connection is never reopened, so the session will do as the consumer.
+    let req = Request::subscribe(id, &self.space, options.filter, options.space_pattern, options.catch_up,
+      options.start_offset, None);
+    let subscription = AsyncSubscription { connection: self.connection.clone(), id: id, space: self.space.clone(),
+      deliveries: rx, last_offset: None, window: options.credit_window, outstanding: 0, finished: false };
+    // If the server refuses, dropping the subscription forgets about it.
+    Box::new(self.connection.call(req).and_then(move |resp| {
+      try!(resp.expect_ok());
+      trace!("Subscribed: {:?}", id);
+      Ok(subscription)
+    }))
+  }
+}
+
+impl AsyncSubscription {
+  pub fn id(&self) -> SubscriptionId {
+    self.id
+  }
+
+  /// The offset of the most recently delivered record, if any.
+  pub fn last_offset(&self) -> Option<Offset> {
+    self.last_offset
+  }
+
+  /// See `Subscription::ack`.
+  pub fn ack(&mut self) -> Result<(), YakError> {
+    let offset = try!(self.last_offset.ok_or(YakError::ProtocolError));
+    let req = Request::ack(self.connection.next_seq(), &self.space, offset, None);
+    self.connection.send(req)
+  }
+
+  /// See `Subscription::nack`.
+  pub fn nack(&mut self, reason: &str) -> Result<(), YakError> {
+    let offset = try!(self.last_offset.ok_or(YakError::ProtocolError));
+    let req = Request::nack(self.connection.next_seq(), &self.space, offset, reason, None);
+    self.connection.send(req)
+  }
+
+  // As with the blocking client, we only ask for more once the stream has
+  // handed over half of what it was allowed.
+  fn replenish_credit(&mut self) -> Result<(), YakError> {
+    if self.outstanding > self.window / 2 {
+      return Ok(())
+    }
+    let amount = self.window - self.outstanding;
+    let req = Request::credit(self.connection.next_seq(), &self.space, self.id, amount);
+    try!(self.connection.send(req));
+    self.outstanding += amount;
+    Ok(())
+  }
+}
+
+impl Stream for AsyncSubscription {
+  type Item = Delivery;
+  type Error = YakError;
+
+  fn poll(&mut self) -> Poll<Option<Delivery>, YakError> {
+    if self.finished {
+      return Ok(Async::Ready(None))
+    }
+    try!(self.replenish_credit());
+    match self.deliveries.poll() {
+      Ok(Async::Ready(Some(Response::Delivery(_, d)))) => {
+        self.last_offset = Some(d.offset);
+        self.outstanding = self.outstanding.saturating_sub(1);
+        Ok(Async::Ready(Some(d)))
+      },
+      Ok(Async::Ready(Some(Response::EndOfStream(_)))) => {
+        self.finished = true;
+        self.connection.router.lock().unwrap().subscriptions.remove(&self.id);
+        Ok(Async::Ready(None))
+      },
+      Ok(Async::Ready(Some(_))) => Err(YakError::ProtocolError),
+      Ok(Async::Ready(None)) | Err(()) => Err(YakError::Disconnected),
+      Ok(Async::NotReady) => Ok(Async::NotReady),
+    }
+  }
+}
+
+impl Drop for AsyncSubscription {
+  fn drop(&mut self) {
+    self.connection.router.lock().unwrap().subscriptions.remove(&self.id);
+    if !self.finished {
+      let req = Request::unsubscribe(self.connection.next_seq(), &self.space, self.id);
+      let _ = self.connection.send(req);
+    }
+  }
+}
@@ -0,0 +1,781 @@
+use std::net::{TcpStream,ToSocketAddrs,Shutdown,SocketAddr};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::cmp::min;
+use std::thread;
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc::{channel,Sender,Receiver,RecvTimeoutError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use url::{SchemeType,UrlParser};
+
+use super::{WireProtocol,Request,Response,Datum,Delivery,Record,Producer,SeqNo,Offset,SubscriptionId,QueueConfig,KeyFilter,Expected,NodeStatus,YakError};
+use super::{DEFAULT_CREDIT_WINDOW,DEFAULT_HEARTBEAT_TIMEOUT_MS,millis_since_epoch};
+
+fn yak_url_scheme(_scheme: &str) -> SchemeType {
+  SchemeType::Relative(0)
+}
+
+/// Splits a `yak://host:port/space` URL into its parts.
+pub fn parse_url(loc: &str) -> Result<(String, u16, String), YakError> {
+  let mut p = UrlParser::new();
+  p.scheme_type_mapper(yak_url_scheme);
+  let url = try!(p.parse(loc));
+  debug!("yak:url: {:?}", url);
+  match (url.domain(), url.port(), url.serialize_path()) {
+    (Some(host), Some(port), Some(path)) => Ok((host.to_string(), port, path)),
+    _ => Err(YakError::InvalidUrl(url.clone()))
+  }
+}
+
+struct SeqCtr(AtomicUsize);
+
+impl SeqCtr {
+  fn new() -> SeqCtr {
+    SeqCtr(AtomicUsize::new(0))
+  }
+  fn next(&self) -> u64 {
+    self.0.fetch_add(1, Ordering::Relaxed) as u64
+  }
+}
+
+impl fmt::Debug for SeqCtr {
+
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmt, "SeqCtr{{ approx: {:?} }}", self.0.load(Ordering::Relaxed))
+  }
+}
+
+// Responses to requests are routed back to the caller by their sequence
+// number, and deliveries to their subscription by the subscription id.
+//
+// While there are subscriptions, the server sends heartbeats on any that are
+// idle, so going without hearing from it for long means the connection is
+// dead. We catch that with a read timeout on the socket, which we keep here
+// so that it's updated along with the set of subscriptions.
+struct Router {
+  pending: HashMap<SeqNo, Sender<Response>>,
+  subscriptions: HashMap<SubscriptionId, Sender<Response>>,
+  closed: bool,
+  socket: TcpStream,
+  heartbeat_timeout_ms: u64,
+}
+
+impl Router {
+  fn new(socket: TcpStream) -> Router {
+    Router { pending: HashMap::new(), subscriptions: HashMap::new(), closed: false, socket: socket,
+      heartbeat_timeout_ms: DEFAULT_HEARTBEAT_TIMEOUT_MS }
+  }
+
+  fn update_read_timeout(&self) -> Result<(), YakError> {
+    let timeout = if self.subscriptions.is_empty() {
+      None
+    } else {
+      Some(Duration::from_millis(self.heartbeat_timeout_ms))
+    };
+    try!(self.socket.set_read_timeout(timeout));
+    Ok(())
+  }
+
+  fn set_heartbeat_timeout(&mut self, timeout_ms: u64) -> Result<(), YakError> {
+    self.heartbeat_timeout_ms = timeout_ms;
+    self.update_read_timeout()
+  }
+
+  fn expect_response(&mut self, seq: SeqNo, tx: Sender<Response>) -> Result<(), YakError> {
+    if self.closed {
+      return Err(YakError::Disconnected)
+    }
+    self.pending.insert(seq, tx);
+    Ok(())
+  }
+
+  fn subscribe(&mut self, id: SubscriptionId, tx: Sender<Response>) -> Result<(), YakError> {
+    if self.closed {
+      return Err(YakError::Disconnected)
+    }
+    self.subscriptions.insert(id, tx);
+    self.update_read_timeout()
+  }
+
+  fn forget(&mut self, id: SubscriptionId) {
+    self.subscriptions.remove(&id);
+  }
+
+  // Any response that turns up later is dropped.
+  fn abandon(&mut self, seq: SeqNo) {
+    self.pending.remove(&seq);
+  }
+
+  fn dispatch(&mut self, resp: Response) {
+    let (is_delivery, id) = match &resp {
+      &Response::Heartbeat(subscription) => {
+        // Having read it is all that matters.
+        trace!("Heartbeat for: {:?}", subscription);
+        return
+      },
+      &Response::Delivery(subscription, _) => (true, subscription),
+      &Response::EndOfStream(subscription) => (true, subscription),
+      &Response::Okay(seq) => (false, seq),
+      &Response::OkayData(seq, _) => (false, seq),
+      &Response::Written(seq, _) => (false, seq),
+      &Response::Conflict(seq, _) => (false, seq),
+      &Response::Layout(seq, _) => (false, seq),
+      &Response::Status(seq, _) => (false, seq),
+      &Response::TimedOut(seq) => (false, seq),
+      &Response::Refused(seq, _) => (false, seq),
+    };
+
+    let target = if is_delivery {
+      self.subscriptions.get(&id).cloned()
+    } else {
+      self.pending.remove(&id)
+    };
+
+    match target {
+      Some(tx) => { let _ = tx.send(resp); },
+      None => trace!("Nobody waiting for: {:?}", resp),
+    }
+  }
+
+  // Dropping the senders wakes up anyone waiting with an error.
+  fn close(&mut self) {
+    self.closed = true;
+    self.pending.clear();
+    self.subscriptions.clear();
+  }
+}
+
+struct Connection {
+  writer: Mutex<WireProtocol<TcpStream>>,
+  sequence: SeqCtr,
+  router: Arc<Mutex<Router>>,
+}
+
+impl Connection {
+  fn open<A: ToSocketAddrs>(addr: A, timeout_ms: Option<u64>) -> Result<Connection, YakError> {
+    let sock = try!(match timeout_ms {
+      Some(timeout_ms) => connect_within(addr, timeout_ms),
+      None => TcpStream::connect(addr).map_err(From::from),
+    });
+    debug!("connected:{:?}", sock);
+    let reader = WireProtocol::new(try!(sock.try_clone()));
+    let router = Arc::new(Mutex::new(Router::new(try!(sock.try_clone()))));
+    {
+      let router = router.clone();
+      let name = format!("yak-client:{}", try!(sock.peer_addr()));
+      let _ = try!(thread::Builder::new().name(name).spawn(move || Connection::route(reader, router)));
+    }
+    Ok(Connection { writer: Mutex::new(WireProtocol::new(sock)), sequence: SeqCtr::new(), router: router })
+  }
+
+  fn route(mut protocol: WireProtocol<TcpStream>, router: Arc<Mutex<Router>>) {
+    loop {
+      if let Err(e) = router.lock().unwrap().update_read_timeout() {
+        warn!("Could not set read timeout: {}", e);
+        break
+      }
+      match protocol.read::<Response>() {
+        Ok(Some(resp)) => router.lock().unwrap().dispatch(resp),
+        Ok(None) => {
+          debug!("Connection closed");
+          break
+        },
+        Err(e) => {
+          // Includes going without a heartbeat for too long.
+          warn!("Connection failed: {}", e);
+          break
+        },
+      }
+    }
+    router.lock().unwrap().close();
+  }
+
+  fn next_seq(&self) -> SeqNo {
+    self.sequence.next()
+  }
+
+  fn local_addr(&self) -> Result<SocketAddr, YakError> {
+    Ok(try!(self.writer.lock().unwrap().get_ref().local_addr()))
+  }
+
+  fn is_closed(&self) -> bool {
+    self.router.lock().unwrap().closed
+  }
+
+  // Fails anything waiting on this connection, rather than leaving it to
+  // notice for itself.
+  fn close(&self) {
+    self.router.lock().unwrap().close();
+    if let Ok(writer) = self.writer.lock() {
+      let _ = writer.get_ref().shutdown(Shutdown::Both);
+    }
+  }
+
+  fn send(&self, req: &Request) -> Result<(), YakError> {
+    self.writer.lock().unwrap().send(req)
+  }
+
+  fn call(&self, req: Request) -> Result<Response, YakError> {
+    let (tx, rx) = channel();
+    try!(self.router.lock().unwrap().expect_response(req.sequence, tx));
+    try!(self.send(&req));
+    trace!("Waiting for response: {:?}", req);
+    match req.deadline {
+      Some(deadline) => {
+        let result = receive_within(&rx, deadline.saturating_sub(millis_since_epoch()));
+        if let Err(YakError::Timeout) = result {
+          self.router.lock().unwrap().abandon(req.sequence);
+        }
+        result
+      },
+      None => rx.recv().map_err(|_| YakError::Disconnected),
+    }
+  }
+}
+
+fn connect_within<A: ToSocketAddrs>(addr: A, timeout_ms: u64) -> Result<TcpStream, YakError> {
+  let mut last_error = None;
+  for addr in try!(addr.to_socket_addrs()) {
+    match TcpStream::connect_timeout(&addr, Duration::from_millis(timeout_ms)) {
+      Ok(sock) => return Ok(sock),
+      Err(e) => last_error = Some(e),
+    }
+  }
+  // Timing out is left as an `IoError`, as it tells us no more about the
+  // server than being refused does.
+  match last_error {
+    Some(e) => Err(From::from(e)),
+    None => Err(YakError::IoError(io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to"))),
+  }
+}
+
+fn receive_within(rx: &Receiver<Response>, timeout_ms: u64) -> Result<Response, YakError> {
+  match rx.recv_timeout(Duration::from_millis(timeout_ms)) {
+    Ok(resp) => Ok(resp),
+    Err(RecvTimeoutError::Timeout) => Err(YakError::Timeout),
+    Err(RecvTimeoutError::Disconnected) => Err(YakError::Disconnected),
+  }
+}
+
+impl Drop for Connection {
+  fn drop(&mut self) {
+    if let Ok(writer) = self.writer.lock() {
+      let _ = writer.get_ref().shutdown(Shutdown::Both);
+    }
+  }
+}
+
+impl fmt::Debug for Connection {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+    write!(fmt, "Connection{{ sequence: {:?} }}", self.sequence)
+  }
+}
+
+#[derive(Debug,Clone)]
+pub struct SubscriptionOptions {
+  /// Only records whose keys match are delivered.
+  pub filter: KeyFilter,
+  /// Subscribe to every space matching this pattern, instead of the
+  /// client's own space. Work-queue mode does not apply to these.
+  pub space_pattern: Option<String>,
+  /// Only deliver what had been written when the subscription started, then
+  /// end the stream. Work-queue spaces are read without taking leases.
+  pub catch_up: bool,
+  /// How many deliveries may be in flight before the application fetches
+  /// them. For work-queue spaces, this bounds the number of leases held at
+  /// once.
+  pub credit_window: u64,
+  /// Start from this offset rather than the beginning of the space. Ignored
+  /// for pattern subscriptions and work-queue spaces.
+  pub start_offset: Offset,
+}
+
+impl Default for SubscriptionOptions {
+  fn default() -> SubscriptionOptions {
+    SubscriptionOptions { filter: KeyFilter::All, space_pattern: None, catch_up: false,
+      credit_window: DEFAULT_CREDIT_WINDOW, start_offset: 0 }
+  }
+}
+
+/// How a client gets its connection back once it has been lost. Each
+/// attempt to reconnect waits twice as long as the one before, from
+/// `initial_backoff_ms` up to `max_backoff_ms`. Requests that are safe to
+/// repeat are retried on the new connection up to `max_attempts` times.
+#[derive(Debug,Clone)]
+pub struct RetryPolicy {
+  pub max_attempts: u32,
+  pub initial_backoff_ms: u64,
+  pub max_backoff_ms: u64,
+}
+
+impl RetryPolicy {
+  /// Never reconnects, so the first connection failure is final.
+  pub fn never() -> RetryPolicy {
+    RetryPolicy { max_attempts: 0, initial_backoff_ms: 0, max_backoff_ms: 0 }
+  }
+
+  fn backoff_ms(&self, attempt: u32) -> u64 {
+    let factor = 1u64.checked_shl(attempt).unwrap_or(u64::max_value());
+    min(self.initial_backoff_ms.saturating_mul(factor), self.max_backoff_ms)
+  }
+}
+
+impl Default for RetryPolicy {
+  fn default() -> RetryPolicy {
+    RetryPolicy { max_attempts: 5, initial_backoff_ms: 100, max_backoff_ms: 5000 }
+  }
+}
+
+/// How long a client waits before giving up with `YakError::Timeout`. Each
+/// is unlimited when `None`, which is the default.
+#[derive(Debug,Clone,Default)]
+pub struct Timeouts {
+  /// For each attempt to connect to the server. Running out of time fails
+  /// with an `IoError`, as with any other failure to connect.
+  pub connect_ms: Option<u64>,
+  /// For the response to a request. The server is told when we'll stop
+  /// waiting, and doesn't start on anything that reaches it after that.
+  pub request_ms: Option<u64>,
+  /// For the next delivery on a subscription. The subscription carries on
+  /// after a timeout, so the application may simply try again.
+  pub idle_ms: Option<u64>,
+}
+
+// Everything needed to open a connection like the one we had.
+#[derive(Debug,Clone)]
+struct Endpoint {
+  host: String,
+  port: u16,
+  retry: RetryPolicy,
+  timeouts: Timeouts,
+  // Shared with the client's subscriptions, so that it can be changed
+  // through a shared reference.
+  heartbeat_timeout_ms: Arc<AtomicUsize>,
+}
+
+impl Endpoint {
+  fn open(&self) -> Result<Arc<Connection>, YakError> {
+    let connection = try!(Connection::open((&self.host as &str, self.port), self.timeouts.connect_ms));
+    try!(connection.router.lock().unwrap().set_heartbeat_timeout(self.heartbeat_timeout_ms.load(Ordering::Relaxed) as u64));
+    Ok(Arc::new(connection))
+  }
+
+  fn reconnect(&self) -> Result<Arc<Connection>, YakError> {
+    if self.retry.max_attempts == 0 {
+      return Err(YakError::Disconnected)
+    }
+    let mut attempt = 0;
+    loop {
+      thread::sleep(Duration::from_millis(self.retry.backoff_ms(attempt)));
+      match self.open() {
+        Ok(connection) => {
+          info!("Reconnected to {}:{}", self.host, self.port);
+          return Ok(connection)
+        },
+        Err(ref e) if e.is_connection_error() && attempt + 1 < self.retry.max_attempts => {
+          warn!("Could not reconnect to {}:{}: {}", self.host, self.port, e);
+          attempt += 1;
+        },
+        Err(e) => return Err(e),
+      }
+    }
+  }
+}
+
+/// A client for a single space. Any number of subscriptions may share the
+/// client's connection with ordinary reads and writes.
+///
+/// If the connection is lost, the next request reconnects according to the
+/// client's `RetryPolicy`. Requests that are safe to repeat are retried
+/// straight away; those that aren't fail, as we can't tell whether the
+/// server saw them.
+#[derive(Debug)]
+pub struct Client {
+  endpoint: Endpoint,
+  connection: Mutex<Arc<Connection>>,
+  space: String,
+  producer: Option<Producer>,
+}
+
+/// A stream of deliveries. When its connection is lost, a subscription
+/// reconnects by itself and carries on from after the last delivery it
+/// returned.
+pub struct Subscription {
+  endpoint: Endpoint,
+  connection: Arc<Connection>,
+  id: SubscriptionId,
+  space: String,
+  options: SubscriptionOptions,
+  deliveries: Receiver<Response>,
+  last_offset: Option<Offset>,
+  positions: HashMap<String, Offset>,
+  outstanding: u64,
+  finished: bool,
+  // Who the server leases work-queue messages to, for as long as the
+  // subscription lasts, however many times it reconnects.
+  consumer: String,
+}
+
+// Registers for deliveries before asking, so that we can't miss any.
+fn open_subscription(connection: &Connection, space: &str, options: &SubscriptionOptions, start: Offset, consumer: &str)
+    -> Result<(SubscriptionId, Receiver<Response>), YakError> {
+  let id = connection.next_seq();
+  let (tx, rx) = channel();
+  try!(connection.router.lock().unwrap().subscribe(id, tx));
+
+  let req = Request::subscribe(id, space, options.filter.clone(), options.space_pattern.clone(), options.catch_up, start,
+    Some(consumer.to_string()));
+  if let Err(e) = connection.call(req).and_then(|resp| resp.expect_ok()) {
+    connection.router.lock().unwrap().forget(id);
+    return Err(e)
+  }
+  trace!("Subscribed: {:?}", id);
+  Ok((id, rx))
+}
+
+impl Client {
+  pub fn connect(loc: &str) -> Result<Client, YakError> {
+    Client::connect_with(loc, Timeouts::default())
+  }
+
+  pub fn connect_with(loc: &str, timeouts: Timeouts) -> Result<Client, YakError> {
+    let (host, port, space) = try!(parse_url(loc));
+    let endpoint = Endpoint { host: host, port: port, retry: RetryPolicy::default(), timeouts: timeouts,
+      heartbeat_timeout_ms: Arc::new(AtomicUsize::new(DEFAULT_HEARTBEAT_TIMEOUT_MS as usize)) };
+    let connection = try!(endpoint.open());
+    Ok(Client { endpoint: endpoint, connection: Mutex::new(connection), space: space, producer: None })
+  }
+
+  /// Sets how this client, and any subscriptions it starts from now on,
+  /// reconnect after losing their connection.
+  pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+    self.endpoint.retry = policy;
+  }
+
+  /// Sets the timeouts for this client, and any subscriptions it starts
+  /// from now on.
+  pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+    self.endpoint.timeouts = timeouts;
+  }
+
+  // Reconnects if the last connection has gone.
+  fn connection(&self) -> Result<Arc<Connection>, YakError> {
+    let mut connection = self.connection.lock().unwrap();
+    if connection.is_closed() {
+      *connection = try!(self.endpoint.reconnect());
+    }
+    Ok(connection.clone())
+  }
+
+  // Retries share the one deadline.
+  fn call<F: Fn(SeqNo) -> Request>(&self, safe: bool, timeout_ms: Option<u64>, request: F) -> Result<Response, YakError> {
+    let deadline = timeout_ms.map(|timeout_ms| millis_since_epoch() + timeout_ms);
+    let mut attempt = 0;
+    loop {
+      if deadline.map(|deadline| deadline <= millis_since_epoch()).unwrap_or(false) {
+        return Err(YakError::Timeout)
+      }
+      let connection = try!(self.connection());
+      let mut req = request(connection.next_seq());
+      req.deadline = deadline;
+      match connection.call(req) {
+        Err(e) => {
+          if !e.is_connection_error() {
+            return Err(e)
+          }
+          // Make sure the next request doesn't go down the same hole.
+          connection.close();
+          if !safe || attempt >= self.endpoint.retry.max_attempts {
+            return Err(e)
+          }
+          warn!("Request failed: {}; retrying", e);
+          attempt += 1;
+        },
+        Ok(resp) => return Ok(resp),
+      }
+    }
+  }
+
+  /// Makes this client's writes idempotent: each is tagged with `id` and a
+  /// sequence number, starting from `next_sequence`, and the server ignores
+  /// any it has already seen. The sequence number only moves on once a write
+  /// succeeds, so a write that failed can be retried as is, even from a new
+  /// client given the same id and next sequence.
+  ///
+  /// This also means that writes are retried after reconnecting.
+  pub fn set_producer(&mut self, id: u64, next_sequence: u64) {
+    self.producer = Some(Producer { id: id, sequence: next_sequence });
+  }
+
+  /// The producer id and sequence number that the next write will use.
+  pub fn producer(&self) -> Option<Producer> {
+    self.producer
+  }
+
+  pub fn write(&mut self, key: &[u8], val: &[u8]) -> Result<(), YakError> {
+    let timeout_ms = self.endpoint.timeouts.request_ms;
+    self.write_timeout(key, val, timeout_ms)
+  }
+
+  /// Like `write`, but waits at most `timeout_ms` for the server, rather
+  /// than the client's request timeout.
+  pub fn write_within(&mut self, key: &[u8], val: &[u8], timeout_ms: u64) -> Result<(), YakError> {
+    self.write_timeout(key, val, Some(timeout_ms))
+  }
+
+  fn write_timeout(&mut self, key: &[u8], val: &[u8], timeout_ms: Option<u64>) -> Result<(), YakError> {
+    let producer = self.producer;
+    let resp = try!(self.call(producer.is_some(), timeout_ms, |seq| Request::write(seq, &self.space, key, val, producer)));
+    try!(resp.expect_ok());
+    if let Some(ref mut producer) = self.producer {
+      producer.sequence += 1;
+    }
+    Ok(())
+  }
+
+  /// Appends only if the space, or the key, is still at the expected
+  /// offset, and returns the new record's offset. Otherwise fails with
+  /// `YakError::Conflict`, saying where the log actually is.
+  pub fn write_expecting(&mut self, key: &[u8], val: &[u8], expected: Expected) -> Result<Offset, YakError> {
+    let resp = try!(self.call(false, self.endpoint.timeouts.request_ms, |seq| Request::write_expecting(seq, &self.space, key, val, expected.clone())));
+    resp.expect_written().map(|(_seq, offset)| offset)
+  }
+
+  /// Appends each record to its own space, all at once. Nobody ever sees
+  /// some of the records without the rest.
+  pub fn transaction(&mut self, records: Vec<Record>) -> Result<(), YakError> {
+    let resp = try!(self.call(false, self.endpoint.timeouts.request_ms, |seq| Request::transaction(seq, &self.space, records.clone())));
+    resp.expect_ok().map(|_| ())
+  }
+
+  pub fn read(&mut self, key: &[u8]) -> Result<Vec<Datum>, YakError> {
+    let timeout_ms = self.endpoint.timeouts.request_ms;
+    self.read_timeout(key, timeout_ms)
+  }
+
+  /// Like `read`, but waits at most `timeout_ms` for the server, rather
+  /// than the client's request timeout.
+  pub fn read_within(&mut self, key: &[u8], timeout_ms: u64) -> Result<Vec<Datum>, YakError> {
+    self.read_timeout(key, Some(timeout_ms))
+  }
+
+  fn read_timeout(&mut self, key: &[u8], timeout_ms: Option<u64>) -> Result<Vec<Datum>, YakError> {
+    let resp = try!(self.call(true, timeout_ms, |seq| Request::read(seq, &self.space, key)));
+    resp.expect_datum_list().map(|(_seq, data)| data)
+  }
+
+  pub fn space(&self) -> &str {
+    &self.space
+  }
+
+  /// Returns the addresses of the chain's nodes, from the one this client
+  /// is connected to down to the tail.
+  pub fn layout(&mut self) -> Result<Vec<String>, YakError> {
+    let resp = try!(self.call(true, self.endpoint.timeouts.request_ms, |seq| Request::layout(seq, &self.space)));
+    resp.expect_layout().map(|(_seq, nodes)| nodes)
+  }
+
+  /// Asks the node this client is connected to what it is up to.
+  pub fn status(&mut self) -> Result<NodeStatus, YakError> {
+    let resp = try!(self.call(true, self.endpoint.timeouts.request_ms, |seq| Request::status(seq, &self.space)));
+    resp.expect_status().map(|(_seq, status)| status)
+  }
+
+  /// Sets how long to wait without hearing from the server while there are
+  /// subscriptions on this client's connection, after which the connection
+  /// is assumed dead and the subscriptions fail with `Disconnected`, or
+  /// reconnect if the retry policy allows.
+  pub fn set_heartbeat_timeout(&self, timeout_ms: u64) -> Result<(), YakError> {
+    self.endpoint.heartbeat_timeout_ms.store(timeout_ms as usize, Ordering::Relaxed);
+    let connection = try!(self.connection());
+    let result = connection.router.lock().unwrap().set_heartbeat_timeout(timeout_ms);
+    result
+  }
+
+  pub fn configure_queue(&mut self, config: QueueConfig) -> Result<(), YakError> {
+    let resp = try!(self.call(true, self.endpoint.timeouts.request_ms, |seq| Request::configure_queue(seq, &self.space, config.clone())));
+    resp.expect_ok().map(|_| ())
+  }
+
+  pub fn subscribe(&self) -> Result<Subscription, YakError> {
+    self.subscribe_with(SubscriptionOptions::default())
+  }
+
+  pub fn subscribe_with_credit(&self, window: u64) -> Result<Subscription, YakError> {
+    self.subscribe_with(SubscriptionOptions { credit_window: window, ..SubscriptionOptions::default() })
+  }
+
+  pub fn subscribe_with(&self, options: SubscriptionOptions) -> Result<Subscription, YakError> {
+    let connection = try!(self.connection());
+    // No other client can have the same local address while we're connected,
+    // and leases don't outlive us by much once we're gone.
+    let consumer = format!("{}/{}", try!(connection.local_addr()), connection.next_seq());
+    let (id, rx) = try!(open_subscription(&connection, &self.space, &options, options.start_offset, &consumer));
+    Ok(Subscription { endpoint: self.endpoint.clone(), connection: connection, id: id, space: self.space.clone(),
+        options: options, deliveries: rx, last_offset: None, positions: HashMap::new(), outstanding: 0,
+        finished: false, consumer: consumer })
+  }
+}
+
+impl Subscription {
+  pub fn id(&self) -> SubscriptionId {
+    self.id
+  }
+
+  /// Returns the next delivery, or `None` once the subscription has ended.
+  pub fn fetch_next(&mut self) -> Result<Option<Delivery>, YakError> {
+    let timeout_ms = self.endpoint.timeouts.idle_ms;
+    self.fetch(timeout_ms)
+  }
+
+  /// Like `fetch_next`, but waits at most `timeout_ms` for a delivery,
+  /// rather than the client's idle timeout.
+  pub fn fetch_next_within(&mut self, timeout_ms: u64) -> Result<Option<Delivery>, YakError> {
+    self.fetch(Some(timeout_ms))
+  }
+
+  fn fetch(&mut self, timeout_ms: Option<u64>) -> Result<Option<Delivery>, YakError> {
+    let deadline = timeout_ms.map(|timeout_ms| millis_since_epoch() + timeout_ms);
+    loop {
+      if self.finished {
+        return Ok(None)
+      }
+      let next = self.replenish_credit().and_then(|()| {
+        debug!("Waiting for next delivery");
+        match deadline {
+          Some(deadline) => receive_within(&self.deliveries, deadline.saturating_sub(millis_since_epoch())),
+          None => self.deliveries.recv().map_err(|_| YakError::Disconnected),
+        }
+      });
+      match next {
+        Ok(Response::Delivery(_, d)) => {
+          self.outstanding = self.outstanding.saturating_sub(1);
+          if self.already_seen(&d) {
+            trace!("Skipping redelivery of {}@{}", d.space, d.offset);
+            continue
+          }
+          self.last_offset = Some(d.offset);
+          self.positions.insert(d.space.clone(), d.offset);
+          return Ok(Some(d))
+        },
+        Ok(Response::EndOfStream(_)) => {
+          self.finished = true;
+          self.connection.router.lock().unwrap().forget(self.id);
+          return Ok(None)
+        },
+        Ok(_) => return Err(YakError::ProtocolError),
+        Err(ref e) if e.is_connection_error() && self.endpoint.retry.max_attempts > 0 => {
+          warn!("Subscription {} lost its connection: {}", self.id, e);
+          try!(self.resume());
+        },
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  // A pattern subscription can't be told where to start in each space, so
+  // it starts over after reconnecting, and we skip what we've already seen.
+  fn already_seen(&self, d: &Delivery) -> bool {
+    self.options.space_pattern.is_some() &&
+      self.positions.get(&d.space).map(|&seen| d.offset <= seen).unwrap_or(false)
+  }
+
+  fn resume(&mut self) -> Result<(), YakError> {
+    self.connection.close();
+    let connection = try!(self.endpoint.reconnect());
+    let start = match (&self.options.space_pattern, self.last_offset) {
+      (&None, Some(offset)) => offset + 1,
+      _ => self.options.start_offset,
+    };
+    let (id, deliveries) = try!(open_subscription(&connection, &self.space, &self.options, start, &self.consumer));
+    debug!("Resumed subscription {} as {} from {}", self.id, id, start);
+    self.connection = connection;
+    self.id = id;
+    self.deliveries = deliveries;
+    self.outstanding = 0;
+    Ok(())
+  }
+
+  /// Asks the server to stop the subscription. Deliveries already in flight
+  /// may still be fetched before `fetch_next` reports the end of the stream.
+  pub fn unsubscribe(&mut self) -> Result<(), YakError> {
+    if self.finished {
+      return Ok(())
+    }
+    let req = Request::unsubscribe(self.connection.next_seq(), &self.space, self.id);
+    try!(self.connection.call(req)).expect_ok().map(|_| ())
+  }
+
+  // We top the window back up once it's half empty, rather than after
+  // every delivery. This happens on fetch, so that we only ask for more
+  // once the application has finished with what it's been given.
+  fn replenish_credit(&mut self) -> Result<(), YakError> {
+    let window = self.options.credit_window;
+    if self.outstanding > window / 2 {
+      return Ok(())
+    }
+    let amount = window - self.outstanding;
+    let req = Request::credit(self.connection.next_seq(), &self.space, self.id, amount);
+    try!(self.connection.send(&req));
+    self.outstanding += amount;
+    Ok(())
+  }
+
+  /// The offset of the most recently fetched delivery, if any.
+  pub fn last_offset(&self) -> Option<Offset> {
+    self.last_offset
+  }
+
+  /// Acknowledges the most recently fetched delivery on a work-queue space,
+  /// releasing its lease so that it will not be redelivered.
+  pub fn ack(&mut self) -> Result<(), YakError> {
+    let offset = try!(self.last_offset.ok_or(YakError::ProtocolError));
+    let req = Request::ack(self.connection.next_seq(), &self.space, offset, Some(self.consumer.clone()));
+    self.connection.send(&req)
+  }
+
+  /// Rejects the most recently fetched delivery on a work-queue space, which
+  /// moves it straight to the space's dead-letter space.
+  pub fn nack(&mut self, reason: &str) -> Result<(), YakError> {
+    let offset = try!(self.last_offset.ok_or(YakError::ProtocolError));
+    let req = Request::nack(self.connection.next_seq(), &self.space, offset, reason, Some(self.consumer.clone()));
+    self.connection.send(&req)
+  }
+}
+
+// We don't wait for the server to confirm, as the connection may well be
+// going away too.
+impl Drop for Subscription {
+  fn drop(&mut self) {
+    self.connection.router.lock().unwrap().forget(self.id);
+    if !self.finished {
+      let req = Request::unsubscribe(self.connection.next_seq(), &self.space, self.id);
+      let _ = self.connection.send(&req);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{RetryPolicy, connect_within};
+
+  #[test]
+  fn backoff_doubles_up_to_the_limit() {
+    let policy = RetryPolicy { max_attempts: 10, initial_backoff_ms: 100, max_backoff_ms: 1000 };
+    let backoffs : Vec<u64> = (0..6).map(|n| policy.backoff_ms(n)).collect();
+    assert_eq!(backoffs, vec![100, 200, 400, 800, 1000, 1000]);
+    assert_eq!(policy.backoff_ms(200), 1000);
+  }
+
+  #[test]
+  fn failing_to_connect_in_time_is_a_connection_error() {
+    // Reserved for documentation, so nothing should ever answer.
+    match connect_within("192.0.2.1:7", 10) {
+      Err(e) => assert!(e.is_connection_error(), "Not a connection error: {:?}", e),
+      Ok(sock) => panic!("Connected to {:?}", sock),
+    }
+  }
+}
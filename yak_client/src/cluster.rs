@@ -0,0 +1,159 @@
+use super::{Client,Subscription,SubscriptionOptions,Datum,Record,Producer,Offset,QueueConfig,Expected,YakError};
+
+// A mid-chain node can only tell us about the node before it, itself and
+// the nodes after it, so we start from whichever answer covers the most of
+// the chain, and keep asking the first node it names until the answer stops
+// growing. That's the head, or as near to it as anyone knows.
+fn discover(bootstrap: &[String]) -> Result<(String, Vec<String>), YakError> {
+  let (space, mut layout) = try!(ask_bootstrap(bootstrap));
+  loop {
+    let (_, further) = try!(Client::connect(&node_url(&layout[0], &space)).and_then(|mut client| client.layout()));
+    debug!("Layout from {}: {:?}", layout[0], further);
+    if further.len() <= layout.len() {
+      return Ok((space, layout))
+    }
+    layout = further;
+  }
+}
+
+fn ask_bootstrap(bootstrap: &[String]) -> Result<(String, Vec<String>), YakError> {
+  let mut best : Option<(String, Vec<String>)> = None;
+  let mut last_error = None;
+  for url in bootstrap {
+    let found = Client::connect(url).and_then(|mut client| {
+      let layout = try!(client.layout());
+      Ok((client.space().to_string(), layout))
+    });
+    match found {
+      Ok((space, layout)) => {
+        debug!("Layout from {}: {:?}", url, layout);
+        if best.as_ref().map(|&(_, ref best)| layout.len() > best.len()).unwrap_or(true) {
+          best = Some((space, layout))
+        }
+      },
+      Err(e) => {
+        warn!("Could not get layout from {}: {}", url, e);
+        last_error = Some(e)
+      },
+    }
+  }
+  match (best, last_error) {
+    (Some((space, layout)), _) => if layout.is_empty() { Err(YakError::ProtocolError) } else { Ok((space, layout)) },
+    (None, Some(e)) => Err(e),
+    (None, None) => Err(YakError::ProtocolError),
+  }
+}
+
+fn node_url(node: &str, space: &str) -> String {
+  format!("yak://{}{}", node, space)
+}
+
+/// A client for a space that finds its own way around the chain: writes go
+/// to the head, and reads and subscriptions to the tail. The chain's layout
+/// is found by asking the bootstrap nodes, and is looked up again whenever
+/// we lose touch with the head or tail.
+#[derive(Debug)]
+pub struct Cluster {
+  bootstrap: Vec<String>,
+  space: String,
+  layout: Vec<String>,
+  head: Client,
+  tail: Client,
+}
+
+impl Cluster {
+  /// Connects given `yak://host:port/space` URLs for any of the chain's
+  /// nodes. They should all name the same space.
+  pub fn connect(bootstrap: &[&str]) -> Result<Cluster, YakError> {
+    let bootstrap : Vec<String> = bootstrap.iter().map(|url| url.to_string()).collect();
+    let (space, layout) = try!(discover(&bootstrap));
+    let head = try!(Client::connect(&node_url(&layout[0], &space)));
+    let tail = try!(Client::connect(&node_url(&layout[layout.len() - 1], &space)));
+    Ok(Cluster { bootstrap: bootstrap, space: space, layout: layout, head: head, tail: tail })
+  }
+
+  pub fn space(&self) -> &str {
+    &self.space
+  }
+
+  /// The addresses of the chain's nodes, head first, as last discovered.
+  pub fn layout(&self) -> &[String] {
+    &self.layout
+  }
+
+  /// Looks up the chain's layout again, and reconnects to the head and tail
+  /// if they have moved. Existing subscriptions stay with the old tail.
+  pub fn refresh(&mut self) -> Result<(), YakError> {
+    let (_, layout) = try!(discover(&self.bootstrap));
+    info!("Chain layout: {:?}", layout);
+    let (head, tail) = (layout[0].clone(), layout[layout.len() - 1].clone());
+    if self.layout.first() != Some(&head) || self.layout.last() != Some(&tail) {
+      let producer = self.head.producer();
+      let mut new_head = try!(Client::connect(&node_url(&head, &self.space)));
+      if let Some(Producer { id, sequence }) = producer {
+        new_head.set_producer(id, sequence);
+      }
+      self.tail = try!(Client::connect(&node_url(&tail, &self.space)));
+      self.head = new_head;
+    }
+    self.layout = layout;
+    Ok(())
+  }
+
+  // We still report the original failure, but the next call will find the
+  // chain as it is now.
+  fn refresh_after<T>(&mut self, result: Result<T, YakError>) -> Result<T, YakError> {
+    if let Err(ref e) = result {
+      // Only errors that suggest the node has gone are worth looking
+      // around for.
+      if e.is_connection_error() {
+        if let Err(refresh_error) = self.refresh() {
+          warn!("Could not rediscover chain after {}: {}", e, refresh_error);
+        }
+      }
+    }
+    result
+  }
+
+  pub fn set_producer(&mut self, id: u64, next_sequence: u64) {
+    self.head.set_producer(id, next_sequence)
+  }
+
+  pub fn producer(&self) -> Option<Producer> {
+    self.head.producer()
+  }
+
+  pub fn write(&mut self, key: &[u8], val: &[u8]) -> Result<(), YakError> {
+    let result = self.head.write(key, val);
+    self.refresh_after(result)
+  }
+
+  pub fn write_expecting(&mut self, key: &[u8], val: &[u8], expected: Expected) -> Result<Offset, YakError> {
+    let result = self.head.write_expecting(key, val, expected);
+    self.refresh_after(result)
+  }
+
+  pub fn transaction(&mut self, records: Vec<Record>) -> Result<(), YakError> {
+    let result = self.head.transaction(records);
+    self.refresh_after(result)
+  }
+
+  pub fn configure_queue(&mut self, config: QueueConfig) -> Result<(), YakError> {
+    let result = self.head.configure_queue(config);
+    self.refresh_after(result)
+  }
+
+  pub fn read(&mut self, key: &[u8]) -> Result<Vec<Datum>, YakError> {
+    let result = self.tail.read(key);
+    self.refresh_after(result)
+  }
+
+  pub fn subscribe(&mut self) -> Result<Subscription, YakError> {
+    self.subscribe_with(SubscriptionOptions::default())
+  }
+
+  pub fn subscribe_with(&mut self, options: SubscriptionOptions) -> Result<Subscription, YakError> {
+    let result = self.tail.subscribe_with(options);
+    self.refresh_after(result)
+  }
+}
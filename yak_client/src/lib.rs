@@ -3,27 +3,57 @@ extern crate log;
 extern crate url;
 extern crate bufstream;
 extern crate capnp;
+extern crate bytes;
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_io;
 
 mod yak_capnp;
+mod client;
+mod cluster;
+mod async_client;
+
+pub use client::{Client, Subscription, SubscriptionOptions, RetryPolicy, Timeouts};
+pub use cluster::Cluster;
+pub use async_client::{AsyncClient, AsyncSubscription, YakFuture};
 
 use std::net::{TcpStream,ToSocketAddrs};
 use bufstream::BufStream;
 use std::io::{self,BufRead,Write};
 use std::fmt;
+use std::cmp;
 use std::error::Error;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use capnp::serialize_packed;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use capnp::{serialize, serialize_packed};
 use capnp::message::{Builder, Allocator, Reader, ReaderSegments, ReaderOptions};
 
-use url::{Url,SchemeType,UrlParser};
+use url::Url;
 
 use yak_capnp::*;
 
-fn yak_url_scheme(_scheme: &str) -> SchemeType {
-  SchemeType::Relative(0)
-}
-
 pub type SeqNo = u64;
+pub type Offset = u64;
+/// Subscriptions are identified by the sequence number of the request that
+/// created them.
+pub type SubscriptionId = SeqNo;
+
+/// How many deliveries a subscription lets the server send ahead of the
+/// application fetching them, unless told otherwise.
+pub const DEFAULT_CREDIT_WINDOW : u64 = 64;
+
+/// How often the server sends a heartbeat on a subscription that has
+/// nothing else to send.
+pub const HEARTBEAT_INTERVAL_MS : u64 = 1000;
+/// How long a client with active subscriptions waits to hear anything from
+/// the server before deciding the connection is dead, unless told otherwise.
+pub const DEFAULT_HEARTBEAT_TIMEOUT_MS : u64 = 5 * HEARTBEAT_INTERVAL_MS;
+
+/// The wall-clock time that request deadlines are given in. Deadlines are
+/// checked by every node in the chain, so their clocks should roughly agree.
+pub fn millis_since_epoch() -> u64 {
+  let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+  now.as_secs() * 1000 + (now.subsec_nanos() / 1000000) as u64
+}
 
 #[derive(Debug)]
 pub enum YakError {
@@ -32,7 +62,25 @@ pub enum YakError {
   IoError(io::Error),
   CapnpError(capnp::Error),
   CapnpNotInSchema(capnp::NotInSchema),
-  ProtocolError
+  ProtocolError,
+  Disconnected,
+  Conflict(Conflict),
+  /// Gave up waiting for a response or for a delivery. A write that times
+  /// out may or may not have been applied.
+  Timeout,
+  /// The server won't do that, for the reason given.
+  Refused(String),
+}
+
+impl YakError {
+  /// Whether the error means we've lost touch with the server, rather than
+  /// that it turned down the request.
+  pub fn is_connection_error(&self) -> bool {
+    match self {
+      &YakError::IoError(_) | &YakError::Disconnected => true,
+      _ => false,
+    }
+  }
 }
 
 impl fmt::Display for YakError {
@@ -43,7 +91,11 @@ impl fmt::Display for YakError {
       &YakError::IoError(ref e) => e.fmt(f),
       &YakError::CapnpError(ref e) => e.fmt(f),
       &YakError::CapnpNotInSchema(ref e) => e.fmt(f),
-      &YakError::ProtocolError => "Protocol Error".fmt(f)
+      &YakError::ProtocolError => "Protocol Error".fmt(f),
+      &YakError::Disconnected => "Disconnected".fmt(f),
+      &YakError::Timeout => "Timed out".fmt(f),
+      &YakError::Refused(ref reason) => f.write_fmt(format_args!("Refused: {}", reason)),
+      &YakError::Conflict(ref c) => f.write_fmt(format_args!("Conflict: expected {:?}, but was at {:?}", c.expected, c.actual)),
     }
   }
 }
@@ -53,6 +105,10 @@ impl Error for YakError {
     match self {
       &YakError::InvalidUrl(_) => "Invalid URL",
       &YakError::ProtocolError => "Protocol Error",
+      &YakError::Disconnected => "Disconnected",
+      &YakError::Timeout => "Timed out",
+      &YakError::Refused(_) => "Refused",
+      &YakError::Conflict(_) => "Conflicting write",
       &YakError::IoError(ref e) => e.description(),
       &YakError::CapnpError(ref e) => e.description(),
       &YakError::CapnpNotInSchema(ref e) => e.description(),
@@ -67,40 +123,277 @@ pub struct Datum {
   pub content: Vec<u8>
 }
 
-#[derive(Debug)]
+/// A record delivered to a subscription, along with where it came from.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct Delivery {
+  pub space: String,
+  pub offset: Offset,
+  pub key: Vec<u8>,
+  pub content: Vec<u8>,
+}
+
+/// Tags a write with who made it, and how many writes they'd made before, so
+/// that a retried write can be told apart from a new one.
+#[derive(PartialEq,Eq,Debug,Clone,Copy)]
+pub struct Producer {
+  pub id: u64,
+  pub sequence: u64,
+}
+
+/// One of the records written by a transaction.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct Record {
+  pub space: String,
+  pub key: Vec<u8>,
+  pub content: Vec<u8>,
+}
+
+/// Where a conditional write expects the log to be: the offset of the last
+/// record in the space, or of the last record with the write's key. `None`
+/// means that there should be no such record yet.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub enum Expected {
+  Space(Option<Offset>),
+  Key(Option<Offset>),
+}
+
+/// Why a conditional write was refused.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct Conflict {
+  pub expected: Expected,
+  pub actual: Option<Offset>,
+}
+
+/// Puts a space into work-queue mode: each message is leased to a single
+/// consumer, and is redelivered if it is not acknowledged within
+/// `visibility_timeout_ms`, up to `max_deliveries` times. Messages that
+/// exhaust their deliveries or are rejected by a consumer are appended to
+/// `dead_letter_space`, if one is given.
+///
+/// Leases and dead letters are kept by the tail alone, so work queues must be
+/// consumed from the tail. Other nodes refuse work-queue subscriptions, and
+/// pass reads of a dead-letter space on to the tail. A dead-letter space is
+/// only written to by the queue; clients' writes to it are refused.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct QueueConfig {
+  pub visibility_timeout_ms: u64,
+  pub max_deliveries: u32,
+  pub dead_letter_space: Option<String>,
+}
+
+/// The content of a record in a dead-letter space; the record's key is the
+/// key of the original message.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct DeadLetter {
+  pub space: String,
+  pub offset: Offset,
+  pub deliveries: u32,
+  pub reason: String,
+  pub content: Vec<u8>,
+}
+
+/// Selects records from a space by their key. Ranges include `start` and
+/// exclude `end`; keys are compared bytewise.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub enum KeyFilter {
+  All,
+  Exact(Vec<u8>),
+  Prefix(Vec<u8>),
+  Range(Vec<u8>, Vec<u8>),
+}
+
+impl Default for KeyFilter {
+  fn default() -> KeyFilter {
+    KeyFilter::All
+  }
+}
+
+/// What a node is up to, as reported by `Client::status`.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct NodeStatus {
+  /// The address the node gives clients.
+  pub address: String,
+  pub role: Role,
+  pub downstream: Option<DownstreamStatus>,
+  /// Only spaces that hold records, in name order.
+  pub spaces: Vec<SpaceStatus>,
+  /// Connections from clients, including the node upstream, if any, in
+  /// peer order.
+  pub sessions: Vec<SessionStatus>,
+}
+
+/// Where a node is in its chain, as far as it can tell: a node only knows
+/// there's one before it while that one is connected. A chain of one node
+/// has it as its tail.
+#[derive(PartialEq,Eq,Debug,Clone,Copy)]
+pub enum Role {
+  Head,
+  Middle,
+  Tail,
+}
+
+/// The next node along, and whether the last exchange with it succeeded;
+/// `healthy` is `None` until there's been one.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct DownstreamStatus {
+  pub address: String,
+  pub healthy: Option<bool>,
+}
+
+/// A connection to a node, and the subscriptions open on it.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct SessionStatus {
+  pub peer: String,
+  /// In id order.
+  pub subscriptions: Vec<SubscriptionStatus>,
+}
+
+/// For subscriptions to a pattern of spaces, `space` is the pattern.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct SubscriptionStatus {
+  pub id: SubscriptionId,
+  pub space: String,
+}
+
+/// The offsets of the first and last records a node holds for a space, and
+/// the bytes their keys and values take up.
+#[derive(PartialEq,Eq,Debug,Clone)]
+pub struct SpaceStatus {
+  pub name: String,
+  pub first: Offset,
+  pub last: Offset,
+  pub bytes: u64,
+}
+
+#[derive(Debug,Clone)]
 pub struct Request {
   pub sequence: SeqNo,
   pub space: String,
+  /// When the client stops waiting for a response, as returned by
+  /// `millis_since_epoch`. Work that gets to a node any later is abandoned.
+  /// Nodes only pass the deadline on with requests that they haven't acted
+  /// on themselves, such as reads; once a node has applied a write, the rest
+  /// of the chain must apply it too, so it goes on without one.
+  pub deadline: Option<u64>,
   pub operation: Operation,
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub enum Operation {
   Read { key: Vec<u8> },
-  Write { key: Vec<u8>, value: Vec<u8> },
-  Subscribe,
+  /// With `expected`, the write only happens if the log is still where the
+  /// writer expects it to be. With `producer`, the write is ignored if that
+  /// producer has already written as many times to the space. A write can't
+  /// have both; retrying a conditional write simply conflicts.
+  Write { key: Vec<u8>, value: Vec<u8>, expected: Option<Expected>, producer: Option<Producer> },
+  /// When `space_pattern` is given, the subscription covers every space
+  /// whose name matches it, rather than just the request's space. Patterns
+  /// use `*` for any run of characters and `?` for any single character.
+  /// A `catch_up` subscription ends once it has delivered everything
+  /// written before it started. Single-space subscriptions that aren't
+  /// work queues start from the `start` offset. Leases on a work queue are
+  /// taken in the name of `consumer`, or else of the session, so that they
+  /// can still be acknowledged after reconnecting.
+  Subscribe { filter: KeyFilter, space_pattern: Option<String>, catch_up: bool, start: Offset, consumer: Option<String> },
+  Ack { offset: Offset, consumer: Option<String> },
+  Nack { offset: Offset, reason: String, consumer: Option<String> },
+  Credit { subscription: SubscriptionId, amount: u64 },
+  Unsubscribe { subscription: SubscriptionId },
+  ConfigureQueue(QueueConfig),
+  /// Appends all of the records, each to its own space, or none of them.
+  Transaction { records: Vec<Record> },
+  /// Asks for the addresses of the nodes in the chain, from the one asked
+  /// down to the tail. A node that has heard from the node before it puts
+  /// that first, so that whoever asked can carry on towards the head.
+  Layout,
+  /// Asks the node for its `NodeStatus`; it isn't passed on.
+  Status,
+  /// Sent by a node to the next one along as soon as it has connected, with
+  /// the address that it advertises, so that the next node knows that it
+  /// isn't the head.
+  Upstream { address: String },
 }
 
 impl Request {
   fn read(seq: SeqNo, space: &str, key: &[u8]) -> Request {
-    Request { sequence: seq, space: space.to_string(), operation: Operation::Read { key: key.to_vec() } }
+    Request { sequence: seq, space: space.to_string(), deadline: None, operation: Operation::Read { key: key.to_vec() } }
+  }
+
+  fn write(seq: SeqNo, space: &str, key: &[u8], value: &[u8], producer: Option<Producer>) -> Request {
+    Request { sequence: seq, space: space.to_string(), deadline: None,
+      operation: Operation::Write { key: key.to_owned(), value: value.to_owned(), expected: None, producer: producer } }
+  }
+
+  fn write_expecting(seq: SeqNo, space: &str, key: &[u8], value: &[u8], expected: Expected) -> Request {
+    Request { sequence: seq, space: space.to_string(), deadline: None,
+      operation: Operation::Write { key: key.to_owned(), value: value.to_owned(), expected: Some(expected), producer: None } }
+  }
+
+  fn subscribe(seq: SeqNo, space: &str, filter: KeyFilter, space_pattern: Option<String>, catch_up: bool,
+      start: Offset, consumer: Option<String>) -> Request {
+    Request { sequence: seq, space: space.to_string(), deadline: None,
+      operation: Operation::Subscribe { filter: filter, space_pattern: space_pattern, catch_up: catch_up, start: start,
+        consumer: consumer } }
+  }
+
+  fn ack(seq: SeqNo, space: &str, offset: Offset, consumer: Option<String>) -> Request {
+    Request { sequence: seq, space: space.to_string(), deadline: None, operation: Operation::Ack { offset: offset, consumer: consumer } }
+  }
+
+  fn nack(seq: SeqNo, space: &str, offset: Offset, reason: &str, consumer: Option<String>) -> Request {
+    Request { sequence: seq, space: space.to_string(), deadline: None,
+      operation: Operation::Nack { offset: offset, reason: reason.to_string(), consumer: consumer } }
+  }
+
+  fn credit(seq: SeqNo, space: &str, subscription: SubscriptionId, amount: u64) -> Request {
+    Request { sequence: seq, space: space.to_string(), deadline: None, operation: Operation::Credit { subscription: subscription, amount: amount } }
   }
 
-  fn write(seq: SeqNo, space: &str, key: &[u8], value: &[u8]) -> Request {
-    Request { sequence: seq, space: space.to_string(), operation: Operation::Write { key: key.to_owned(), value: value.to_owned() } }
+  fn unsubscribe(seq: SeqNo, space: &str, subscription: SubscriptionId) -> Request {
+    Request { sequence: seq, space: space.to_string(), deadline: None, operation: Operation::Unsubscribe { subscription: subscription } }
   }
 
-  fn subscribe(seq: SeqNo, space: &str) -> Request {
-    Request { sequence: seq, space: space.to_string(), operation: Operation::Subscribe }
+  fn configure_queue(seq: SeqNo, space: &str, config: QueueConfig) -> Request {
+    Request { sequence: seq, space: space.to_string(), deadline: None, operation: Operation::ConfigureQueue(config) }
   }
 
-  fn encode_write<A: Allocator>(message: &mut Builder<A>, seq: SeqNo, space: &str, key: &[u8], val: &[u8]) {
+  fn layout(seq: SeqNo, space: &str) -> Request {
+    Request { sequence: seq, space: space.to_string(), deadline: None, operation: Operation::Layout }
+  }
+
+  fn status(seq: SeqNo, space: &str) -> Request {
+    Request { sequence: seq, space: space.to_string(), deadline: None, operation: Operation::Status }
+  }
+
+  /// Introduces a node, at `address`, to the next one along.
+  pub fn upstream(seq: SeqNo, address: &str) -> Request {
+    Request { sequence: seq, space: String::new(), deadline: None, operation: Operation::Upstream { address: address.to_string() } }
+  }
+
+  fn transaction(seq: SeqNo, space: &str, records: Vec<Record>) -> Request {
+    Request { sequence: seq, space: space.to_string(), deadline: None, operation: Operation::Transaction { records: records } }
+  }
+
+  fn encode_write<A: Allocator>(message: &mut Builder<A>, seq: SeqNo, space: &str, key: &[u8], val: &[u8],
+      expected: &Option<Expected>, producer: &Option<Producer>) {
     let mut rec = message.init_root::<client_request::Builder>();
     rec.set_sequence(seq);
     rec.set_space(space);
     let mut req = rec.init_operation().init_write();
     req.set_key(key);
     req.set_value(val);
+    match expected {
+      &Some(ref expected) => encode_expected(req.borrow().init_condition().init_expected(), expected),
+      &None => req.borrow().init_condition().set_always(()),
+    }
+    match producer {
+      &Some(ref producer) => {
+        let mut p = req.init_origin().init_producer();
+        p.set_id(producer.id);
+        p.set_sequence(producer.sequence);
+      },
+      &None => req.init_origin().set_anonymous(()),
+    }
   }
 
   fn encode_read<A: Allocator>(message: &mut Builder<A>, seq: SeqNo, space: &str, key: &[u8]) {
@@ -111,11 +404,136 @@ impl Request {
     req.set_key(key)
   }
 
-  fn encode_subscribe<A: Allocator>(message: &mut Builder<A>, seq: SeqNo, space: &str) {
+  fn encode_subscribe<A: Allocator>(message: &mut Builder<A>, seq: SeqNo, space: &str, filter: &KeyFilter, space_pattern: &Option<String>,
+      catch_up: bool, start: Offset, consumer: &Option<String>) {
     let mut rec = message.init_root::<client_request::Builder>();
     rec.set_sequence(seq);
     rec.set_space(space);
-    rec.init_operation().set_subscribe(())
+    let mut req = rec.init_operation().init_subscribe();
+    if let &Some(ref pattern) = space_pattern {
+      req.set_space_pattern(pattern);
+    }
+    req.set_catch_up(catch_up);
+    req.set_start_offset(start);
+    if let &Some(ref consumer) = consumer {
+      req.set_consumer(consumer);
+    }
+    let mut f = req.init_filter();
+    match filter {
+      &KeyFilter::All => f.set_all(()),
+      &KeyFilter::Exact(ref key) => f.set_exact(key),
+      &KeyFilter::Prefix(ref prefix) => f.set_prefix(prefix),
+      &KeyFilter::Range(ref start, ref end) => {
+        let mut range = f.init_range();
+        range.set_start(start);
+        range.set_end(end)
+      },
+    }
+  }
+
+  fn decode_filter(filter: key_filter::Reader) -> Result<KeyFilter, YakError> {
+    match try!(filter.which()) {
+      key_filter::All(()) => Ok(KeyFilter::All),
+      key_filter::Exact(key) => Ok(KeyFilter::Exact(try!(key).into())),
+      key_filter::Prefix(prefix) => Ok(KeyFilter::Prefix(try!(prefix).into())),
+      key_filter::Range(range) => {
+        let range = try!(range);
+        Ok(KeyFilter::Range(try!(range.get_start()).into(), try!(range.get_end()).into()))
+      },
+    }
+  }
+
+  // Left empty when the session is the consumer.
+  fn decode_consumer(consumer: &str) -> Option<String> {
+    if consumer.is_empty() { None } else { Some(consumer.to_string()) }
+  }
+
+  fn encode_ack<A: Allocator>(message: &mut Builder<A>, seq: SeqNo, space: &str, offset: Offset, consumer: &Option<String>) {
+    let mut rec = message.init_root::<client_request::Builder>();
+    rec.set_sequence(seq);
+    rec.set_space(space);
+    let mut req = rec.init_operation().init_ack();
+    req.set_offset(offset);
+    if let &Some(ref consumer) = consumer {
+      req.set_consumer(consumer);
+    }
+  }
+
+  fn encode_nack<A: Allocator>(message: &mut Builder<A>, seq: SeqNo, space: &str, offset: Offset, reason: &str,
+      consumer: &Option<String>) {
+    let mut rec = message.init_root::<client_request::Builder>();
+    rec.set_sequence(seq);
+    rec.set_space(space);
+    let mut req = rec.init_operation().init_nack();
+    req.set_offset(offset);
+    req.set_reason(reason);
+    if let &Some(ref consumer) = consumer {
+      req.set_consumer(consumer);
+    }
+  }
+
+  fn encode_credit<A: Allocator>(message: &mut Builder<A>, seq: SeqNo, space: &str, subscription: SubscriptionId, amount: u64) {
+    let mut rec = message.init_root::<client_request::Builder>();
+    rec.set_sequence(seq);
+    rec.set_space(space);
+    let mut req = rec.init_operation().init_credit();
+    req.set_subscription(subscription);
+    req.set_amount(amount)
+  }
+
+  fn encode_unsubscribe<A: Allocator>(message: &mut Builder<A>, seq: SeqNo, space: &str, subscription: SubscriptionId) {
+    let mut rec = message.init_root::<client_request::Builder>();
+    rec.set_sequence(seq);
+    rec.set_space(space);
+    let mut req = rec.init_operation().init_unsubscribe();
+    req.set_subscription(subscription)
+  }
+
+  fn encode_configure_queue<A: Allocator>(message: &mut Builder<A>, seq: SeqNo, space: &str, config: &QueueConfig) {
+    let mut rec = message.init_root::<client_request::Builder>();
+    rec.set_sequence(seq);
+    rec.set_space(space);
+    let mut req = rec.init_operation().init_configure_queue();
+    req.set_visibility_timeout_ms(config.visibility_timeout_ms);
+    req.set_max_deliveries(config.max_deliveries);
+    if let Some(ref dead_letter_space) = config.dead_letter_space {
+      req.set_dead_letter_space(dead_letter_space)
+    }
+  }
+
+  fn encode_layout<A: Allocator>(message: &mut Builder<A>, seq: SeqNo, space: &str) {
+    let mut rec = message.init_root::<client_request::Builder>();
+    rec.set_sequence(seq);
+    rec.set_space(space);
+    rec.init_operation().set_layout(());
+  }
+
+  fn encode_status<A: Allocator>(message: &mut Builder<A>, seq: SeqNo, space: &str) {
+    let mut rec = message.init_root::<client_request::Builder>();
+    rec.set_sequence(seq);
+    rec.set_space(space);
+    rec.init_operation().set_status(());
+  }
+
+  fn encode_upstream<A: Allocator>(message: &mut Builder<A>, seq: SeqNo, space: &str, address: &str) {
+    let mut rec = message.init_root::<client_request::Builder>();
+    rec.set_sequence(seq);
+    rec.set_space(space);
+    rec.init_operation().set_upstream(address);
+  }
+
+  fn encode_transaction<A: Allocator>(message: &mut Builder<A>, seq: SeqNo, space: &str, records: &[Record]) {
+    let mut rec = message.init_root::<client_request::Builder>();
+    rec.set_sequence(seq);
+    rec.set_space(space);
+    let req = rec.init_operation().init_transaction();
+    let mut list = req.init_records(records.len() as u32);
+    for i in 0..records.len() {
+      let mut r = list.borrow().get(i as u32);
+      r.set_space(&records[i].space);
+      r.set_key(&records[i].key);
+      r.set_value(&records[i].content);
+    }
   }
 }
 
@@ -123,8 +541,24 @@ impl WireMessage for Request {
   fn encode<A: Allocator>(&self, message: &mut Builder<A>) {
     match &self.operation {
       &Operation::Read { ref key } => Self::encode_read(message, self.sequence, &self.space, &key),
-      &Operation::Write { ref key, ref value } => Self::encode_write(message, self.sequence, &self.space, &key, &value),
-      &Operation::Subscribe => Self::encode_subscribe(message, self.sequence, &self.space),
+      &Operation::Write { ref key, ref value, ref expected, ref producer } =>
+        Self::encode_write(message, self.sequence, &self.space, &key, &value, expected, producer),
+      &Operation::Subscribe { ref filter, ref space_pattern, catch_up, start, ref consumer } =>
+        Self::encode_subscribe(message, self.sequence, &self.space, filter, space_pattern, catch_up, start, consumer),
+      &Operation::Ack { offset, ref consumer } => Self::encode_ack(message, self.sequence, &self.space, offset, consumer),
+      &Operation::Nack { offset, ref reason, ref consumer } =>
+        Self::encode_nack(message, self.sequence, &self.space, offset, &reason, consumer),
+      &Operation::Credit { subscription, amount } => Self::encode_credit(message, self.sequence, &self.space, subscription, amount),
+      &Operation::Unsubscribe { subscription } => Self::encode_unsubscribe(message, self.sequence, &self.space, subscription),
+      &Operation::ConfigureQueue(ref config) => Self::encode_configure_queue(message, self.sequence, &self.space, config),
+      &Operation::Transaction { ref records } => Self::encode_transaction(message, self.sequence, &self.space, records),
+      &Operation::Layout => Self::encode_layout(message, self.sequence, &self.space),
+      &Operation::Status => Self::encode_status(message, self.sequence, &self.space),
+      &Operation::Upstream { ref address } => Self::encode_upstream(message, self.sequence, &self.space, address),
+    }
+    if let Some(deadline) = self.deadline {
+      // Each of the encoders above has just set up the root for us.
+      message.get_root::<client_request::Builder>().expect("request root").set_deadline(deadline);
     }
   }
 
@@ -132,6 +566,10 @@ impl WireMessage for Request {
     let msg = try!(message.get_root::<client_request::Reader>());
     let space = try!(msg.get_space()).into();
     let seq = msg.get_sequence();
+    let deadline = match msg.get_deadline() {
+      0 => None,
+      deadline => Some(deadline),
+    };
     let op = try!(msg.get_operation());
     match try!(op.which()) {
       operation::Read(v) => {
@@ -139,6 +577,7 @@ impl WireMessage for Request {
         Ok(Request {
           sequence: seq,
           space: space,
+          deadline: deadline,
           operation: Operation::Read {
             key: try!(v.get_key()).into(),
           }
@@ -146,22 +585,139 @@ impl WireMessage for Request {
       },
       operation::Write(v) => {
         let v = try!(v);
+        let expected = match try!(v.get_condition().which()) {
+          write_request::condition::Always(()) => None,
+          write_request::condition::Expected(e) => Some(try!(decode_expected(try!(e)))),
+        };
+        let producer = match try!(v.get_origin().which()) {
+          write_request::origin::Anonymous(()) => None,
+          write_request::origin::Producer(p) => {
+            let p = try!(p);
+            Some(Producer { id: p.get_id(), sequence: p.get_sequence() })
+          },
+        };
+        if expected.is_some() && producer.is_some() {
+          return Err(From::from(capnp::Error::new_decode_error("A conditional write can't come from a producer.", None)))
+        }
         Ok(Request {
           sequence: seq,
           space: space,
+          deadline: deadline,
           operation: Operation::Write {
             key: try!(v.get_key()).into(),
             value: try!(v.get_value()).into(),
+            expected: expected,
+            producer: producer,
           }
         })
       },
-      operation::Subscribe(()) => {
+      operation::ObsoleteSubscribe(()) => {
+        Ok(Request {
+          sequence: seq,
+          space: space,
+          deadline: deadline,
+          operation: Operation::Subscribe { filter: KeyFilter::All, space_pattern: None, catch_up: false, start: 0, consumer: None },
+        })
+      },
+      operation::Subscribe(v) => {
+        let v = try!(v);
+        let space_pattern : String = try!(v.get_space_pattern()).into();
         Ok(Request {
           sequence: seq,
           space: space,
-          operation: Operation::Subscribe,
+          deadline: deadline,
+          operation: Operation::Subscribe {
+            filter: try!(Self::decode_filter(try!(v.get_filter()))),
+            space_pattern: if space_pattern.is_empty() { None } else { Some(space_pattern) },
+            catch_up: v.get_catch_up(),
+            start: v.get_start_offset(),
+            consumer: Self::decode_consumer(try!(v.get_consumer())),
+          }
         })
       },
+      operation::Ack(v) => {
+        let v = try!(v);
+        Ok(Request {
+          sequence: seq,
+          space: space,
+          deadline: deadline,
+          operation: Operation::Ack {
+            offset: v.get_offset(),
+            consumer: Self::decode_consumer(try!(v.get_consumer())),
+          }
+        })
+      },
+      operation::Nack(v) => {
+        let v = try!(v);
+        Ok(Request {
+          sequence: seq,
+          space: space,
+          deadline: deadline,
+          operation: Operation::Nack {
+            offset: v.get_offset(),
+            reason: try!(v.get_reason()).into(),
+            consumer: Self::decode_consumer(try!(v.get_consumer())),
+          }
+        })
+      },
+      operation::Credit(v) => {
+        let v = try!(v);
+        Ok(Request {
+          sequence: seq,
+          space: space,
+          deadline: deadline,
+          operation: Operation::Credit {
+            subscription: v.get_subscription(),
+            amount: v.get_amount(),
+          }
+        })
+      },
+      operation::Unsubscribe(v) => {
+        let v = try!(v);
+        Ok(Request {
+          sequence: seq,
+          space: space,
+          deadline: deadline,
+          operation: Operation::Unsubscribe {
+            subscription: v.get_subscription(),
+          }
+        })
+      },
+      operation::ConfigureQueue(v) => {
+        let v = try!(v);
+        let dead_letter_space : String = try!(v.get_dead_letter_space()).into();
+        Ok(Request {
+          sequence: seq,
+          space: space,
+          deadline: deadline,
+          operation: Operation::ConfigureQueue(QueueConfig {
+            visibility_timeout_ms: v.get_visibility_timeout_ms(),
+            max_deliveries: v.get_max_deliveries(),
+            dead_letter_space: if dead_letter_space.is_empty() { None } else { Some(dead_letter_space) },
+          })
+        })
+      },
+      operation::Transaction(v) => {
+        let v = try!(v);
+        let mut records = Vec::new();
+        for r in try!(v.get_records()).iter() {
+          records.push(Record {
+            space: try!(r.get_space()).into(),
+            key: try!(r.get_key()).into(),
+            content: try!(r.get_value()).into(),
+          });
+        }
+        Ok(Request {
+          sequence: seq,
+          space: space,
+          deadline: deadline,
+          operation: Operation::Transaction { records: records },
+        })
+      },
+      operation::Layout(()) => Ok(Request { sequence: seq, space: space, deadline: deadline, operation: Operation::Layout }),
+      operation::Status(()) => Ok(Request { sequence: seq, space: space, deadline: deadline, operation: Operation::Status }),
+      operation::Upstream(address) =>
+        Ok(Request { sequence: seq, space: space, deadline: deadline, operation: Operation::Upstream { address: try!(address).into() } }),
     }
   }
 }
@@ -170,28 +726,71 @@ impl WireMessage for Request {
 pub enum Response {
   Okay(SeqNo),
   OkayData(SeqNo, Vec<Datum>),
-  Delivery(Datum),
+  Delivery(SubscriptionId, Delivery),
+  EndOfStream(SubscriptionId),
+  Heartbeat(SubscriptionId),
+  /// A conditional write succeeded, and appended the record at this offset.
+  Written(SeqNo, Offset),
+  Conflict(SeqNo, Conflict),
+  Layout(SeqNo, Vec<String>),
+  Status(SeqNo, NodeStatus),
+  /// The request's deadline passed before the server could deal with it.
+  TimedOut(SeqNo),
+  /// The server won't carry out the request, for the reason given.
+  Refused(SeqNo, String),
 }
 
 impl Response {
+  fn unexpected(&self) -> YakError {
+    match self {
+      &Response::TimedOut(_) => YakError::Timeout,
+      &Response::Refused(_, ref reason) => YakError::Refused(reason.clone()),
+      _ => YakError::ProtocolError,
+    }
+  }
+
   pub fn expect_ok(&self) -> Result<SeqNo, YakError> {
     match self {
       &Response::Okay(seq) => Ok(seq),
-      &_ => Err(YakError::ProtocolError)
+      other => Err(other.unexpected())
     }
   }
 
   pub fn expect_datum_list(&self) -> Result<(SeqNo, Vec<Datum>), YakError> {
     match self {
       &Response::OkayData(seq, ref result) => Ok((seq, result.clone())),
-      &_ => Err(YakError::ProtocolError)
+      other => Err(other.unexpected())
+    }
+  }
+
+  /// Conflicts are reported as errors, as there's nothing else to do about
+  /// them here.
+  pub fn expect_written(&self) -> Result<(SeqNo, Offset), YakError> {
+    match self {
+      &Response::Written(seq, offset) => Ok((seq, offset)),
+      &Response::Conflict(_, ref conflict) => Err(YakError::Conflict(conflict.clone())),
+      other => Err(other.unexpected())
+    }
+  }
+
+  pub fn expect_layout(&self) -> Result<(SeqNo, Vec<String>), YakError> {
+    match self {
+      &Response::Layout(seq, ref nodes) => Ok((seq, nodes.clone())),
+      other => Err(other.unexpected())
     }
   }
 
-  pub fn expect_delivery(&self) -> Result<Datum, YakError> {
+  pub fn expect_status(&self) -> Result<(SeqNo, NodeStatus), YakError> {
     match self {
-      &Response::Delivery(ref result) => Ok(result.clone()),
-      &_ => Err(YakError::ProtocolError)
+      &Response::Status(seq, ref status) => Ok((seq, status.clone())),
+      other => Err(other.unexpected())
+    }
+  }
+
+  pub fn expect_delivery(&self) -> Result<Delivery, YakError> {
+    match self {
+      &Response::Delivery(_, ref result) => Ok(result.clone()),
+      other => Err(other.unexpected())
     }
   }
 }
@@ -209,11 +808,36 @@ impl WireMessage for Response {
           datum.set_value(&val[i].content)
         }
       },
-      &Response::Delivery(ref val) => {
+      &Response::Delivery(subscription, ref val) => {
+        response.set_sequence(subscription);
         let mut datum = response.init_delivery();
+        datum.set_space(&val.space);
+        datum.set_offset(val.offset);
         datum.set_key(&val.key);
         datum.set_value(&val.content)
-      }
+      },
+      &Response::EndOfStream(subscription) => { response.set_sequence(subscription); response.set_end(()) },
+      &Response::Heartbeat(subscription) => { response.set_sequence(subscription); response.set_heartbeat(()) },
+      &Response::Written(seq, offset) => { response.set_sequence(seq); response.set_written(offset) },
+      &Response::TimedOut(seq) => { response.set_sequence(seq); response.set_timed_out(()) },
+      &Response::Refused(seq, ref reason) => { response.set_sequence(seq); response.set_refused(reason) },
+      &Response::Layout(seq, ref nodes) => {
+        response.set_sequence(seq);
+        let mut list = response.init_layout(nodes.len() as u32);
+        for i in 0..nodes.len() {
+          list.set(i as u32, &nodes[i]);
+        }
+      },
+      &Response::Status(seq, ref status) => {
+        response.set_sequence(seq);
+        encode_status(response.init_status(), status);
+      },
+      &Response::Conflict(seq, ref conflict) => {
+        response.set_sequence(seq);
+        let mut c = response.init_conflict();
+        encode_expected(c.borrow().init_expected(), &conflict.expected);
+        encode_maybe_offset(c.init_actual(), conflict.actual);
+      },
     }
   }
 
@@ -232,15 +856,193 @@ impl WireMessage for Response {
       },
       client_response::Delivery(d) => {
         let d = try!(d);
-        let key = try!(d.get_key()).into();
-        let val = try!(d.get_value()).into();
-        let datum = Datum { key: key, content: val };
-        debug!("Got Delivery: {:?}", datum);
-        Ok(Response::Delivery(datum))
+        let delivery = Delivery {
+          space: try!(d.get_space()).into(),
+          offset: d.get_offset(),
+          key: try!(d.get_key()).into(),
+          content: try!(d.get_value()).into(),
+        };
+        debug!("Got Delivery: {:?} {:?}", msg.get_sequence(), delivery);
+        Ok(Response::Delivery(msg.get_sequence(), delivery))
+      },
+      client_response::End(()) => Ok(Response::EndOfStream(msg.get_sequence())),
+      client_response::Heartbeat(()) => Ok(Response::Heartbeat(msg.get_sequence())),
+      client_response::Written(offset) => Ok(Response::Written(msg.get_sequence(), offset)),
+      client_response::TimedOut(()) => Ok(Response::TimedOut(msg.get_sequence())),
+      client_response::Refused(reason) => Ok(Response::Refused(msg.get_sequence(), try!(reason).into())),
+      client_response::Layout(nodes) => {
+        let nodes = try!(nodes);
+        let mut layout = Vec::with_capacity(nodes.len() as usize);
+        for i in 0..nodes.len() {
+          layout.push(try!(nodes.get(i)).into());
+        }
+        Ok(Response::Layout(msg.get_sequence(), layout))
+      },
+      client_response::Status(s) => Ok(Response::Status(msg.get_sequence(), try!(decode_status(try!(s))))),
+      client_response::Conflict(c) => {
+        let c = try!(c);
+        let conflict = Conflict {
+          expected: try!(decode_expected(try!(c.get_expected()))),
+          actual: try!(decode_maybe_offset(try!(c.get_actual()))),
+        };
+        Ok(Response::Conflict(msg.get_sequence(), conflict))
+      },
+    }
+  }
+}
+fn encode_status(mut builder: node_status::Builder, status: &NodeStatus) {
+  builder.set_address(&status.address);
+  match status.role {
+    Role::Head => builder.borrow().init_role().set_head(()),
+    Role::Middle => builder.borrow().init_role().set_middle(()),
+    Role::Tail => builder.borrow().init_role().set_tail(()),
+  }
+  match status.downstream {
+    Some(ref downstream) => {
+      let mut d = builder.borrow().init_downstream().init_node();
+      d.set_address(&downstream.address);
+      match downstream.healthy {
+        Some(healthy) => d.borrow().init_health().set_healthy(healthy),
+        None => d.borrow().init_health().set_unknown(()),
       }
+    },
+    None => builder.borrow().init_downstream().set_empty(()),
+  }
+  {
+    let mut list = builder.borrow().init_spaces(status.spaces.len() as u32);
+    for i in 0..status.spaces.len() {
+      let mut s = list.borrow().get(i as u32);
+      s.set_name(&status.spaces[i].name);
+      s.set_first_offset(status.spaces[i].first);
+      s.set_last_offset(status.spaces[i].last);
+      s.set_bytes(status.spaces[i].bytes);
+    }
+  }
+  let mut list = builder.init_sessions(status.sessions.len() as u32);
+  for i in 0..status.sessions.len() {
+    let session = &status.sessions[i];
+    let mut s = list.borrow().get(i as u32);
+    s.set_peer(&session.peer);
+    let mut subscriptions = s.init_subscriptions(session.subscriptions.len() as u32);
+    for j in 0..session.subscriptions.len() {
+      let mut sub = subscriptions.borrow().get(j as u32);
+      sub.set_id(session.subscriptions[j].id);
+      sub.set_space(&session.subscriptions[j].space);
     }
   }
 }
+
+fn decode_status(reader: node_status::Reader) -> Result<NodeStatus, YakError> {
+  let role = match try!(reader.get_role().which()) {
+    node_status::role::Head(()) => Role::Head,
+    node_status::role::Middle(()) => Role::Middle,
+    node_status::role::Tail(()) => Role::Tail,
+  };
+  let downstream = match try!(reader.get_downstream().which()) {
+    node_status::downstream::Empty(()) => None,
+    node_status::downstream::Node(d) => {
+      let d = try!(d);
+      let healthy = match try!(d.get_health().which()) {
+        downstream_status::health::Unknown(()) => None,
+        downstream_status::health::Healthy(healthy) => Some(healthy),
+      };
+      Some(DownstreamStatus { address: try!(d.get_address()).into(), healthy: healthy })
+    },
+  };
+  let mut spaces = Vec::new();
+  for s in try!(reader.get_spaces()).iter() {
+    spaces.push(SpaceStatus {
+      name: try!(s.get_name()).into(),
+      first: s.get_first_offset(),
+      last: s.get_last_offset(),
+      bytes: s.get_bytes(),
+    });
+  }
+  let mut sessions = Vec::new();
+  for s in try!(reader.get_sessions()).iter() {
+    let mut subscriptions = Vec::new();
+    for sub in try!(s.get_subscriptions()).iter() {
+      subscriptions.push(SubscriptionStatus { id: sub.get_id(), space: try!(sub.get_space()).into() });
+    }
+    sessions.push(SessionStatus { peer: try!(s.get_peer()).into(), subscriptions: subscriptions });
+  }
+  Ok(NodeStatus {
+    address: try!(reader.get_address()).into(),
+    role: role,
+    downstream: downstream,
+    spaces: spaces,
+    sessions: sessions,
+  })
+}
+
+fn encode_maybe_offset(mut builder: maybe_offset::Builder, offset: Option<Offset>) {
+  match offset {
+    Some(offset) => builder.set_at(offset),
+    None => builder.set_empty(()),
+  }
+}
+
+fn decode_maybe_offset(reader: maybe_offset::Reader) -> Result<Option<Offset>, YakError> {
+  match try!(reader.which()) {
+    maybe_offset::Empty(()) => Ok(None),
+    maybe_offset::At(offset) => Ok(Some(offset)),
+  }
+}
+
+fn encode_expected(mut builder: expected_offset::Builder, expected: &Expected) {
+  let offset = match expected {
+    &Expected::Space(offset) => { builder.borrow().init_scope().set_space(()); offset },
+    &Expected::Key(offset) => { builder.borrow().init_scope().set_key(()); offset },
+  };
+  encode_maybe_offset(builder.init_offset(), offset);
+}
+
+fn decode_expected(reader: expected_offset::Reader) -> Result<Expected, YakError> {
+  let offset = try!(decode_maybe_offset(try!(reader.get_offset())));
+  match try!(reader.get_scope().which()) {
+    expected_offset::scope::Space(()) => Ok(Expected::Space(offset)),
+    expected_offset::scope::Key(()) => Ok(Expected::Key(offset)),
+  }
+}
+
+impl DeadLetter {
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut message = Builder::new_default();
+    self.encode(&mut message);
+    let mut bytes = Vec::new();
+    serialize_packed::write_message(&mut bytes, &mut message).unwrap();
+    bytes
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Result<DeadLetter, YakError> {
+    let mut bytes = bytes;
+    let message_reader = try!(serialize_packed::read_message(&mut bytes, ReaderOptions::new()));
+    DeadLetter::decode(&message_reader)
+  }
+}
+
+impl WireMessage for DeadLetter {
+  fn encode<A: Allocator>(&self, message: &mut Builder<A>) {
+    let mut rec = message.init_root::<dead_letter::Builder>();
+    rec.set_space(&self.space);
+    rec.set_offset(self.offset);
+    rec.set_deliveries(self.deliveries);
+    rec.set_reason(&self.reason);
+    rec.set_value(&self.content)
+  }
+
+  fn decode<S: ReaderSegments>(message: &Reader<S>) -> Result<Self, YakError> {
+    let msg = try!(message.get_root::<dead_letter::Reader>());
+    Ok(DeadLetter {
+      space: try!(msg.get_space()).into(),
+      offset: msg.get_offset(),
+      deliveries: msg.get_deliveries(),
+      reason: try!(msg.get_reason()).into(),
+      content: try!(msg.get_value()).into(),
+    })
+  }
+}
+
 #[derive(Debug)]
 pub struct WireProtocol<S: io::Read+io::Write> {
   connection: BufStream<S>,
@@ -265,6 +1067,10 @@ impl<S: io::Read+io::Write> WireProtocol<S> {
     WireProtocol { connection: stream }
   }
 
+  pub fn get_ref(&self) -> &S {
+    self.connection.get_ref()
+  }
+
   pub fn send<M : WireMessage + fmt::Debug>(&mut self, req: &M) -> Result<(), YakError> {
     trace!("Send: {:?}", req);
     let mut message = Builder::new_default();
@@ -291,96 +1097,184 @@ impl<S: io::Read+io::Write> WireProtocol<S> {
   }
 }
 
-struct SeqCtr(AtomicUsize);
+// Packed messages don't say how long they are, so the only way to tell
+// whether a buffer holds a whole one is to try decoding it. This notes
+// whether the decoder went looking past the end of what we have.
+struct PartialInput<'a> {
+  buf: &'a [u8],
+  pos: usize,
+  exhausted: bool,
+}
 
-impl SeqCtr {
-  fn new() -> SeqCtr {
-    SeqCtr(AtomicUsize::new(0))
-  }
-  fn next(&mut self) -> u64 {
-    self.0.fetch_add(1, Ordering::Relaxed) as u64
+impl<'a> io::Read for PartialInput<'a> {
+  fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+    let n = {
+      let rest = try!(self.fill_buf());
+      let n = cmp::min(rest.len(), out.len());
+      out[..n].copy_from_slice(&rest[..n]);
+      n
+    };
+    self.consume(n);
+    Ok(n)
   }
 }
 
-impl fmt::Debug for SeqCtr {
+impl<'a> BufRead for PartialInput<'a> {
+  fn fill_buf(&mut self) -> io::Result<&[u8]> {
+    if self.pos == self.buf.len() {
+      self.exhausted = true;
+    }
+    Ok(&self.buf[self.pos..])
+  }
 
-  fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-    write!(fmt, "SeqCtr{{ approx: {:?} }}", self.0.load(Ordering::Relaxed))
+  fn consume(&mut self, amount: usize) {
+    self.pos += amount;
   }
 }
 
-#[derive(Debug)]
-pub struct Client {
-  protocol: WireProtocol<TcpStream>,
-  space: String,
-  sequence: SeqCtr,
+/// Decodes a message from the start of `buf`, returning it along with the
+/// number of bytes it took up, or `None` if `buf` doesn't yet hold all of it.
+pub fn decode_partial<M: WireMessage>(buf: &[u8]) -> Result<Option<(M, usize)>, YakError> {
+  let mut input = PartialInput { buf: buf, pos: 0, exhausted: false };
+  match serialize_packed::read_message(&mut input, ReaderOptions::new()) {
+    Ok(message_reader) => Ok(Some((try!(M::decode(&message_reader)), input.pos))),
+    Err(_) if input.exhausted => Ok(None),
+    Err(e) => Err(From::from(e)),
+  }
 }
 
-pub struct Subscription {
-  protocol: WireProtocol<TcpStream>,
+/// Picks messages, in the form that `WireProtocol` sends them, out of bytes
+/// as they arrive. Unlike `decode_partial`, each byte is only looked at
+/// once, however many pieces a message turns up in.
+pub struct Decoder {
+  packed: Vec<u8>,
+  unpacked: Vec<u8>,
+  // How much of `unpacked` has already been handed out.
+  taken: usize,
 }
 
-impl Client {
-  pub fn connect(loc: &str) -> Result<Client, YakError> {
-    let mut p = UrlParser::new();
-    p.scheme_type_mapper(yak_url_scheme);
-    let url = try!(p.parse(loc));
-    debug!("yak:url: {:?}", url);
-    let (addr, space) = match (url.domain(), url.port(), url.serialize_path()) {
-      (Some(host), Some(port), Some(path)) => ((host, port), path),
-      _ => return Err(YakError::InvalidUrl(url.clone()))
-    };
-
-    let proto = try!(WireProtocol::connect(addr));
-    let seq = SeqCtr::new();
-    Ok(Client { protocol: proto, space: space, sequence: seq })
+impl Decoder {
+  pub fn new() -> Decoder {
+    Decoder { packed: Vec::new(), unpacked: Vec::new(), taken: 0 }
   }
 
-  pub fn write(&mut self, key: &[u8], val: &[u8]) -> Result<(), YakError> {
-    let req = Request::write(self.sequence.next(), &self.space, key, val);
-    try!(self.protocol.send(&req));
-    trace!("Waiting for response: {:?}", req);
-
-    try!(self.protocol.read::<Response>())
-      .map(|r| r.expect_ok())
-      .unwrap_or(Err(YakError::ProtocolError))
-      .map(|_| ())
+  pub fn feed(&mut self, data: &[u8]) {
+    self.packed.extend_from_slice(data);
+    self.unpack();
   }
 
-  pub fn read(&mut self, key: &[u8]) -> Result<Vec<Datum>, YakError> {
-    let req = Request::read(self.sequence.next(), &self.space, key);
-    try!(self.protocol.send(&req));
-    trace!("Waiting for response: {:?}", req);
+  // Only unpacks as far as the largest message we'd accept, so that a peer
+  // that never finishes one can't have us hold more than that.
+  fn unpack(&mut self) {
+    let limit = self.taken + max_message_bytes();
+    let used = unpack(&self.packed, &mut self.unpacked, limit);
+    self.packed.drain(..used);
+  }
 
-    try!(self.protocol.read::<Response>())
-      .map(|r| r.expect_datum_list())
-      .unwrap_or(Err(YakError::ProtocolError))
-      .map(|(_seq, data)| data)
+  /// Returns the next message, once all of it has been fed in. A message
+  /// bigger than the largest we'd accept is an error.
+  pub fn next<M: WireMessage>(&mut self) -> Result<Option<M>, YakError> {
+    self.unpack();
+    let len = match try!(message_len(&self.unpacked[self.taken..])) {
+      Some(len) => len,
+      None => return Ok(None),
+    };
+    let msg = {
+      let mut words = &self.unpacked[self.taken..self.taken + len];
+      let message_reader = try!(serialize::read_message(&mut words, ReaderOptions::new()));
+      try!(M::decode(&message_reader))
+    };
+    self.taken += len;
+    // Rather than shuffle what's left down after every message.
+    if self.taken * 2 > self.unpacked.len() {
+      self.unpacked.drain(..self.taken);
+      self.taken = 0;
+    }
+    Ok(Some(msg))
   }
+}
 
-  pub fn subscribe(mut self) -> Result<Subscription, YakError> {
-    let req = Request::subscribe(self.sequence.next(), &self.space);
-    try!(self.protocol.send(&req));
-    trace!("Waiting for response: {:?}", req);
+// The most a message may take up once unpacked; the reader would refuse to
+// traverse any more than this anyway.
+fn max_message_bytes() -> usize {
+  ReaderOptions::new().traversal_limit_in_words as usize * 8
+}
+
+// As `serialize::read_message` allows.
+const MAX_SEGMENTS : usize = 512;
 
-    let resp = try!(self.protocol.read::<Response>());
-    let resp_seq = try!(resp.map(|r| r.expect_ok()).unwrap_or(Err(YakError::ProtocolError)));
-    trace!("Got response: {:?}", resp_seq);
-    Ok(Subscription { protocol: self.protocol })
+// Unpacks as many whole words as `packed` holds onto the end of `out`, until
+// `out` is `limit` bytes long, and returns how many bytes of `packed` they
+// took up. Each word is led by a tag byte with a bit set for each of its
+// bytes that isn't zero, and only those bytes follow. A tag of zero is
+// followed by a count of further zero words, and one with every bit set by a
+// count of words that are sent as they are.
+fn unpack(packed: &[u8], out: &mut Vec<u8>, limit: usize) -> usize {
+  let mut pos = 0;
+  while pos < packed.len() && out.len() < limit {
+    match packed[pos] {
+      0x00 => {
+        if pos + 2 > packed.len() {
+          break
+        }
+        let len = out.len() + (1 + packed[pos + 1] as usize) * 8;
+        out.resize(len, 0);
+        pos += 2;
+      },
+      0xff => {
+        if pos + 10 > packed.len() || pos + 10 + packed[pos + 9] as usize * 8 > packed.len() {
+          break
+        }
+        let end = pos + 10 + packed[pos + 9] as usize * 8;
+        out.extend_from_slice(&packed[pos + 1..pos + 9]);
+        out.extend_from_slice(&packed[pos + 10..end]);
+        pos = end;
+      },
+      tag => {
+        let present = tag.count_ones() as usize;
+        if pos + 1 + present > packed.len() {
+          break
+        }
+        let mut bytes = packed[pos + 1..pos + 1 + present].iter();
+        for bit in 0..8 {
+          out.push(if tag & (1 << bit) != 0 { *bytes.next().unwrap() } else { 0 });
+        }
+        pos += 1 + present;
+      },
+    }
   }
+  pos
 }
 
-impl Subscription {
-  pub fn fetch_next(&mut self) -> Result<Option<Datum>, YakError> {
-    debug!("Waiting for next delivery");
-    let next = try!(self.protocol.read::<Response>());
-    let next = try!(next.map(Ok).unwrap_or(Err(YakError::ProtocolError)));
-    match next {
-      Response::Okay(_) => Ok(None),
-      Response::Delivery(d) => Ok(Some(d)),
-      _ => Err(YakError::ProtocolError),
-    }
+// The length of the unpacked message at the start of `words`, going by the
+// segment table at its head, once all of it is there.
+fn message_len(words: &[u8]) -> Result<Option<usize>, YakError> {
+  let u32_at = |i: usize| (0..4).fold(0, |n, b| n | (words[i + b] as usize) << (8 * b));
+  if words.len() < 4 {
+    return Ok(None)
+  }
+  let segments = u32_at(0) + 1;
+  if segments > MAX_SEGMENTS {
+    return Err(From::from(capnp::Error::new_decode_error("Too many segments.", Some(format!("{}", segments)))))
+  }
+  let header = (segments / 2 + 1) * 8;
+  if words.len() < header {
+    return Ok(None)
   }
+  let len = header + (0..segments).map(|i| u32_at(4 + 4 * i) * 8).sum::<usize>();
+  if len > max_message_bytes() {
+    return Err(From::from(capnp::Error::new_decode_error("Message is too big.", Some(format!("{} bytes", len)))))
+  }
+  Ok(if words.len() < len { None } else { Some(len) })
+}
+
+/// Encodes a message in the same form that `WireProtocol` sends.
+pub fn encode_packed<M: WireMessage>(msg: &M) -> Result<Vec<u8>, YakError> {
+  let mut message = Builder::new_default();
+  msg.encode(&mut message);
+  let mut bytes = Vec::new();
+  try!(serialize_packed::write_message(&mut bytes, &mut message));
+  Ok(bytes)
 }
 
 impl From<url::ParseError> for YakError {
@@ -406,3 +1300,83 @@ impl From<capnp::NotInSchema> for YakError {
     YakError::CapnpNotInSchema(err)
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::{Decoder, Request, Operation, Expected, Producer, encode_packed, max_message_bytes};
+
+  fn write(seq: u64, value: Vec<u8>) -> Request {
+    Request { sequence: seq, space: "space".to_string(), deadline: Some(seq * 1000),
+      operation: Operation::Write { key: vec![0; 100], value: value, expected: None, producer: None } }
+  }
+
+  #[test]
+  fn decoder_takes_messages_a_byte_at_a_time() {
+    // Runs of zero and of non-zero words pack differently.
+    let sent = vec![write(1, b"short".to_vec()), write(2, vec![0xab; 5000]), write(3, (0..255).collect())];
+    let bytes : Vec<u8> = sent.iter().flat_map(|req| encode_packed(req).unwrap()).collect();
+
+    let mut decoder = Decoder::new();
+    let mut received = Vec::new();
+    for b in bytes.chunks(1) {
+      decoder.feed(b);
+      while let Some(req) = decoder.next::<Request>().unwrap() {
+        received.push(req);
+      }
+    }
+    assert_eq!(format!("{:?}", received), format!("{:?}", sent));
+  }
+
+  #[test]
+  fn decoder_takes_many_messages_at_once() {
+    let sent : Vec<Request> = (0..100).map(|seq| write(seq, vec![seq as u8; seq as usize])).collect();
+    let bytes : Vec<u8> = sent.iter().flat_map(|req| encode_packed(req).unwrap()).collect();
+
+    let mut decoder = Decoder::new();
+    decoder.feed(&bytes);
+    let mut received = Vec::new();
+    while let Some(req) = decoder.next::<Request>().unwrap() {
+      received.push(req);
+    }
+    assert_eq!(format!("{:?}", received), format!("{:?}", sent));
+  }
+
+  #[test]
+  fn conditional_writes_from_producers_are_refused() {
+    let req = Request { sequence: 1, space: "space".to_string(), deadline: None,
+      operation: Operation::Write { key: b"key".to_vec(), value: b"value".to_vec(), expected: Some(Expected::Space(None)),
+        producer: Some(Producer { id: 7, sequence: 0 }) } };
+    let mut decoder = Decoder::new();
+    decoder.feed(&encode_packed(&req).unwrap());
+    assert!(decoder.next::<Request>().is_err());
+  }
+
+  #[test]
+  fn decoder_refuses_messages_bigger_than_it_would_read() {
+    let mut decoder = Decoder::new();
+    // One segment of 2^31 - 1 words, then runs of zero words towards it.
+    decoder.feed(&[0xf0, 0xff, 0xff, 0xff, 0x7f]);
+    for _ in 0..1000 {
+      decoder.feed(&[0x00, 0xff]);
+    }
+    assert!(decoder.next::<Request>().is_err());
+
+    let mut decoder = Decoder::new();
+    // 65536 segments.
+    decoder.feed(&[0x03, 0xff, 0xff]);
+    assert!(decoder.next::<Request>().is_err());
+  }
+
+  #[test]
+  fn decoder_holds_no_more_than_the_largest_message() {
+    let mut decoder = Decoder::new();
+    // One segment, as big as we'd take, and then more zero words than it
+    // could hold.
+    decoder.feed(&[0x70, 0xff, 0xff, 0x7f]);
+    for _ in 0..(max_message_bytes() / 2048 + 100) {
+      decoder.feed(&[0x00, 0xff]);
+    }
+    // Give or take the last run.
+    assert!(decoder.unpacked.len() <= max_message_bytes() + 2048);
+  }
+}